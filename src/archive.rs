@@ -0,0 +1,316 @@
+//! Archive entry sources for folder-differ
+//!
+//! `.tar`, `.tar.gz`, `.tar.zst` and `.zip` files can stand in for a
+//! directory. Tarballs are decompressed and read front to back in a single
+//! pass; zip files are read through their central directory, one member at a
+//! time. Members are never extracted to disk.
+
+use crate::source::{EntrySource, SourceEntry, relative_member_path};
+use crate::{FolderDifferError, Result};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Container and compression of an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveKind {
+    /// Detect the kind of an archive from its file name.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(Self::TarZst)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// An archive read as an [`EntrySource`].
+#[derive(Debug, Clone)]
+pub struct ArchiveSource {
+    path: PathBuf,
+    kind: ArchiveKind,
+}
+
+impl ArchiveSource {
+    /// An archive whose kind is taken from its file name.
+    pub fn open(path: &Path) -> Result<Self> {
+        let kind = ArchiveKind::from_path(path).ok_or_else(|| {
+            FolderDifferError::Other(format!("Unsupported archive type: {}", path.display()))
+        })?;
+        Ok(Self::with_kind(path, kind))
+    }
+
+    pub fn with_kind(path: &Path, kind: ArchiveKind) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            kind,
+        }
+    }
+
+    fn visit_tar(
+        &self,
+        reader: impl Read,
+        visit: &mut dyn FnMut(&SourceEntry, &mut dyn Read) -> Result<()>,
+    ) -> Result<()> {
+        let mut archive = tar::Archive::new(reader);
+        for member in archive.entries()? {
+            let mut member = member?;
+            let header = member.header();
+            if !header.entry_type().is_file() {
+                continue;
+            }
+            let Some(path) = relative_member_path(&member.path()?) else {
+                log::debug!("Skipping archive member outside the root");
+                continue;
+            };
+            let entry = SourceEntry {
+                path,
+                size: header.size()?,
+                mtime: header
+                    .mtime()
+                    .ok()
+                    .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                mode: header.mode().ok().map(|mode| mode & 0o7777),
+            };
+            visit(&entry, &mut member)?;
+        }
+        Ok(())
+    }
+
+    fn visit_zip(
+        &self,
+        visit: &mut dyn FnMut(&SourceEntry, &mut dyn Read) -> Result<()>,
+    ) -> Result<()> {
+        let zip_error = |e: zip::result::ZipError| {
+            FolderDifferError::Other(format!("Cannot read {}: {}", self.path.display(), e))
+        };
+        let mut archive =
+            zip::ZipArchive::new(BufReader::new(File::open(&self.path)?)).map_err(zip_error)?;
+        for index in 0..archive.len() {
+            let mut member = archive.by_index(index).map_err(zip_error)?;
+            if !member.is_file() {
+                continue;
+            }
+            let Some(path) = relative_member_path(Path::new(member.name())) else {
+                log::debug!("Skipping archive member outside the root");
+                continue;
+            };
+            let entry = SourceEntry {
+                path,
+                size: member.size(),
+                mtime: member.last_modified().and_then(zip_time),
+                mode: member.unix_mode().map(|mode| mode & 0o7777),
+            };
+            visit(&entry, &mut member)?;
+        }
+        Ok(())
+    }
+}
+
+impl EntrySource for ArchiveSource {
+    fn visit(
+        &mut self,
+        visit: &mut dyn FnMut(&SourceEntry, &mut dyn Read) -> Result<()>,
+    ) -> Result<()> {
+        let file = BufReader::new(File::open(&self.path)?);
+        match self.kind {
+            ArchiveKind::Tar => self.visit_tar(file, visit),
+            ArchiveKind::TarGz => self.visit_tar(flate2::read::GzDecoder::new(file), visit),
+            ArchiveKind::TarZst => self.visit_tar(zstd::stream::read::Decoder::new(file)?, visit),
+            ArchiveKind::Zip => self.visit_zip(visit),
+        }
+    }
+}
+
+/// Convert a zip (MS-DOS) timestamp, which has no time zone, as UTC.
+fn zip_time(time: zip::DateTime) -> Option<SystemTime> {
+    // Days since the epoch of a proleptic Gregorian date (Hinnant's algorithm).
+    let (year, month, day) = (
+        i64::from(time.year()),
+        i64::from(time.month()),
+        i64::from(time.day()),
+    );
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    let secs = days * 86_400
+        + i64::from(time.hour()) * 3600
+        + i64::from(time.minute()) * 60
+        + i64::from(time.second());
+    u64::try_from(secs)
+        .ok()
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::DiffType;
+    use crate::hash::HashAlgorithm;
+    use crate::source::compare_dir_to_source;
+    use crate::sync::Side;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn tar_bytes(members: &[(&str, &[u8], u32)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data, mode) in members {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(*mode);
+            header.set_mtime(1_700_000_000);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn diff_summary(diffs: &[crate::diff::Diff]) -> Vec<(String, &'static str)> {
+        diffs
+            .iter()
+            .map(|d| {
+                let kind = match d.diff_type {
+                    DiffType::OnlyInLeft => "left",
+                    DiffType::OnlyInRight => "right",
+                    DiffType::Different { .. } => "different",
+                    DiffType::ModeDiffers { .. } => "mode",
+                    _ => "other",
+                };
+                (d.path.clone(), kind)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_compare_dir_to_tarballs() {
+        let dir = tempdir().unwrap();
+        let deploy = dir.path().join("deploy");
+        std::fs::create_dir_all(deploy.join("bin")).unwrap();
+        std::fs::write(deploy.join("bin/run"), b"#!/bin/sh\n").unwrap();
+        std::fs::write(deploy.join("conf"), b"new").unwrap();
+        std::fs::write(deploy.join("local"), b"x").unwrap();
+        let tar = tar_bytes(&[
+            ("./bin/run", b"#!/bin/sh\n", 0o755),
+            ("conf", b"old", 0o644),
+            ("missing", b"m", 0o644),
+        ]);
+
+        let plain = dir.path().join("release.tar");
+        std::fs::write(&plain, &tar).unwrap();
+        let gz = dir.path().join("release.tar.gz");
+        let mut encoder =
+            flate2::write::GzEncoder::new(File::create(&gz).unwrap(), Default::default());
+        encoder.write_all(&tar).unwrap();
+        encoder.finish().unwrap();
+        let zst = dir.path().join("release.tar.zst");
+        std::fs::write(&zst, zstd::encode_all(&tar[..], 0).unwrap()).unwrap();
+
+        for archive in [plain, gz, zst] {
+            let mut source = ArchiveSource::open(&archive).unwrap();
+            let diffs =
+                compare_dir_to_source(&deploy, &mut source, Side::Right, HashAlgorithm::Blake3)
+                    .unwrap();
+            assert_eq!(
+                diff_summary(&diffs),
+                [
+                    ("conf".to_string(), "different"),
+                    ("local".to_string(), "left"),
+                    ("missing".to_string(), "right"),
+                ],
+                "{}",
+                archive.display()
+            );
+        }
+    }
+
+    #[test]
+    fn test_compare_two_archives() {
+        let dir = tempdir().unwrap();
+        let old = dir.path().join("release-1.4.tar.gz");
+        let mut encoder =
+            flate2::write::GzEncoder::new(File::create(&old).unwrap(), Default::default());
+        encoder
+            .write_all(&tar_bytes(&[
+                ("app/run", b"run", 0o644),
+                ("app/lib", b"v1", 0o644),
+                ("app/removed", b"r", 0o644),
+            ]))
+            .unwrap();
+        encoder.finish().unwrap();
+        let new = dir.path().join("release-1.5.tar.zst");
+        let tar = tar_bytes(&[
+            ("app/run", b"run", 0o755),
+            ("app/lib", b"v2", 0o644),
+            ("app/added", b"a", 0o644),
+        ]);
+        std::fs::write(&new, zstd::encode_all(&tar[..], 0).unwrap()).unwrap();
+
+        let diffs = crate::source::compare_sources(
+            &mut ArchiveSource::open(&old).unwrap(),
+            &mut ArchiveSource::open(&new).unwrap(),
+            HashAlgorithm::Blake3,
+        )
+        .unwrap();
+        assert_eq!(
+            diff_summary(&diffs),
+            [
+                ("app/added".to_string(), "right"),
+                ("app/lib".to_string(), "different"),
+                ("app/removed".to_string(), "left"),
+                ("app/run".to_string(), "mode"),
+            ]
+        );
+        let DiffType::Different { left_time, .. } = &diffs[1].diff_type else {
+            unreachable!()
+        };
+        assert_eq!(
+            *left_time,
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+    }
+
+    #[test]
+    fn test_zip_source() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("release.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default().unix_permissions(0o755);
+        writer.add_directory("app/", options).unwrap();
+        writer.start_file("app/run", options).unwrap();
+        writer.write_all(b"run").unwrap();
+        writer.finish().unwrap();
+
+        let mut entries = Vec::new();
+        ArchiveSource::open(&path)
+            .unwrap()
+            .visit(&mut |entry, content| {
+                let mut data = String::new();
+                content.read_to_string(&mut data)?;
+                entries.push((entry.path.clone(), entry.mode, data));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            entries,
+            [("app/run".to_string(), Some(0o755), "run".to_string())]
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! Binary difference localization for folder-differ
+//!
+//! Two files are read side by side in large blocks. Equal blocks are skipped
+//! with a single comparison; only blocks that differ are scanned byte by byte,
+//! so locating a change in a large image costs little more than reading it.
+
+use crate::Result;
+use crate::hash::read_block;
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+/// Bytes read from each file at a time.
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Where two files differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BinaryDiff {
+    /// Offset of the first differing byte, `None` if the files are equal.
+    pub first_offset: Option<u64>,
+    /// Number of runs of consecutive differing bytes.
+    pub regions: u64,
+    /// Differing bytes, counting the extra tail of the longer file.
+    pub bytes_changed: u64,
+}
+
+impl fmt::Display for BinaryDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.first_offset {
+            Some(offset) => write!(
+                f,
+                "first difference at offset {}, {} region(s), {} bytes changed",
+                offset, self.regions, self.bytes_changed
+            ),
+            None => write!(f, "no difference"),
+        }
+    }
+}
+
+/// Compare `left` and `right` byte by byte and summarize where they differ.
+///
+/// Bytes past the end of the shorter file count as changed, as one region
+/// (merged with a region that runs up to the end of the shorter file).
+pub fn locate_differences(left: &Path, right: &Path) -> Result<BinaryDiff> {
+    let mut left = File::open(left)?;
+    let mut right = File::open(right)?;
+    let (left_len, right_len) = (left.metadata()?.len(), right.metadata()?.len());
+    let mut left_buf = vec![0u8; BLOCK_SIZE];
+    let mut right_buf = vec![0u8; BLOCK_SIZE];
+    let mut result = BinaryDiff::default();
+    let mut offset = 0u64;
+    let mut in_region = false;
+    loop {
+        let left_n = read_block(&mut left, &mut left_buf)?;
+        let right_n = read_block(&mut right, &mut right_buf)?;
+        let common = left_n.min(right_n);
+        if common == 0 {
+            break;
+        }
+        if left_buf[..common] == right_buf[..common] {
+            in_region = false;
+        } else {
+            for (i, (a, b)) in left_buf[..common]
+                .iter()
+                .zip(&right_buf[..common])
+                .enumerate()
+            {
+                if a == b {
+                    in_region = false;
+                    continue;
+                }
+                result.first_offset.get_or_insert(offset + i as u64);
+                result.bytes_changed += 1;
+                if !in_region {
+                    result.regions += 1;
+                    in_region = true;
+                }
+            }
+        }
+        offset += common as u64;
+        if common < BLOCK_SIZE {
+            break;
+        }
+    }
+    let tail = left_len.max(right_len).saturating_sub(offset);
+    if tail > 0 {
+        result.first_offset.get_or_insert(offset);
+        result.bytes_changed += tail;
+        if !in_region {
+            result.regions += 1;
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_locate_differences() {
+        let dir = tempdir().unwrap();
+        let left = dir.path().join("left.img");
+        let right = dir.path().join("right.img");
+        let mut data = vec![0u8; BLOCK_SIZE + 100];
+        std::fs::write(&left, &data).unwrap();
+        std::fs::write(&right, &data).unwrap();
+        assert_eq!(
+            locate_differences(&left, &right).unwrap(),
+            BinaryDiff::default()
+        );
+
+        // A two-byte region straddling the block boundary, a single byte
+        // later on, and a longer right file whose tail starts a new region.
+        data[BLOCK_SIZE - 1] = 1;
+        data[BLOCK_SIZE] = 1;
+        data[BLOCK_SIZE + 10] = 1;
+        data.extend_from_slice(&[9; 5]);
+        std::fs::write(&right, &data).unwrap();
+        assert_eq!(
+            locate_differences(&left, &right).unwrap(),
+            BinaryDiff {
+                first_offset: Some(BLOCK_SIZE as u64 - 1),
+                regions: 3,
+                bytes_changed: 8,
+            }
+        );
+    }
+}
@@ -0,0 +1,279 @@
+//! Persistent hash cache for folder-differ
+//!
+//! Digests are remembered per tree in `<root>/.folder-differ/hashcache`,
+//! keyed by `(device, inode, size, mtime_ns)`. An entry is only used while
+//! all four still match the file (and it was made with the same hash
+//! algorithm), so a changed file is rehashed and its entry replaced. The file
+//! is tab-separated text with one entry per line:
+//! `dev ino size mtime_ns algorithm digest path`. A cache written in another
+//! format is discarded, and malformed lines (say, from a write cut short)
+//! are skipped and dropped on the next save.
+
+use crate::hash::{HashAlgorithm, from_hex, hash_file_with, to_hex};
+use crate::journal::{escape_field, unescape_field};
+use crate::links::inode_key;
+use crate::{Result, STATE_DIR_NAME};
+use rustc_hash::FxHashMap;
+use std::fs::{File, Metadata};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// File name of the cache inside a tree's state directory.
+pub const HASH_CACHE_FILE_NAME: &str = "hashcache";
+
+const HEADER: &str = "# folder-differ hash cache v2";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CacheEntry {
+    size: u64,
+    mtime_ns: i64,
+    algorithm: HashAlgorithm,
+    digest: Vec<u8>,
+    path: String,
+}
+
+/// The hash cache of one tree.
+#[derive(Debug)]
+pub struct HashCache {
+    root: PathBuf,
+    algorithm: HashAlgorithm,
+    entries: Mutex<FxHashMap<(u64, u64), CacheEntry>>,
+    dirty: AtomicBool,
+}
+
+/// Modification time in nanoseconds since the epoch.
+#[cfg(unix)]
+pub(crate) fn mtime_ns(meta: &Metadata) -> i64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.mtime() * 1_000_000_000 + meta.mtime_nsec()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn mtime_ns(meta: &Metadata) -> i64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos() as i64)
+}
+
+impl HashCache {
+    /// Location of the cache file for the tree at `root`.
+    pub fn path_for(root: &Path) -> PathBuf {
+        root.join(STATE_DIR_NAME).join(HASH_CACHE_FILE_NAME)
+    }
+
+    /// Load the cache of the tree at `root` for digests made with
+    /// `algorithm`; a missing file gives an empty cache.
+    pub fn open(root: &Path, algorithm: HashAlgorithm) -> Result<Self> {
+        let cache = Self {
+            root: root.to_path_buf(),
+            algorithm,
+            entries: Mutex::new(FxHashMap::default()),
+            dirty: AtomicBool::new(false),
+        };
+        let file = match File::open(Self::path_for(root)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(cache),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = BufReader::new(file).lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            log::debug!(
+                "Discarding hash cache of {} in an old format",
+                root.display()
+            );
+            return Ok(cache);
+        }
+        let mut entries = cache.entries.lock().unwrap();
+        for (index, line) in lines.enumerate() {
+            let parsed = match line {
+                Ok(line) if line.is_empty() || line.starts_with('#') => continue,
+                Ok(line) => parse_line(&line),
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => None,
+                Err(e) => return Err(e.into()),
+            };
+            let Some((key, entry)) = parsed else {
+                // Most likely a write cut short; the next save drops it.
+                log::debug!(
+                    "Skipping malformed hash cache line {} in {}",
+                    index + 2,
+                    Self::path_for(root).display()
+                );
+                cache.dirty.store(true, Ordering::SeqCst);
+                continue;
+            };
+            entries.insert(key, entry);
+        }
+        drop(entries);
+        Ok(cache)
+    }
+
+    /// Number of cached digests.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The cached digest of a file, if its entry is still valid.
+    pub fn get(&self, meta: &Metadata) -> Option<Vec<u8>> {
+        let key = inode_key(meta)?;
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key)?;
+        (entry.size == meta.len()
+            && entry.mtime_ns == mtime_ns(meta)
+            && entry.algorithm == self.algorithm)
+            .then(|| entry.digest.clone())
+    }
+
+    /// Remember the digest of the file at `rel_path`, replacing any stale entry.
+    pub fn insert(&self, rel_path: &str, meta: &Metadata, digest: Vec<u8>) {
+        let Some(key) = inode_key(meta) else {
+            return;
+        };
+        let entry = CacheEntry {
+            size: meta.len(),
+            mtime_ns: mtime_ns(meta),
+            algorithm: self.algorithm,
+            digest,
+            path: rel_path.to_string(),
+        };
+        let previous = self.entries.lock().unwrap().insert(key, entry.clone());
+        if previous.as_ref() != Some(&entry) {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Hash the file at `rel_path` (metadata `meta`), using the cache.
+    pub fn hash_file(&self, rel_path: &str, meta: &Metadata) -> Result<Vec<u8>> {
+        if let Some(digest) = self.get(meta) {
+            return Ok(digest);
+        }
+        let digest = hash_file_with(&self.root.join(rel_path), self.algorithm)?;
+        self.insert(rel_path, meta, digest.clone());
+        Ok(digest)
+    }
+
+    /// Drop entries whose file is gone or has changed since it was hashed.
+    ///
+    /// Returns the number of entries removed.
+    pub fn prune(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|key, entry| {
+            std::fs::symlink_metadata(self.root.join(&entry.path)).is_ok_and(|meta| {
+                inode_key(&meta) == Some(*key)
+                    && meta.len() == entry.size
+                    && mtime_ns(&meta) == entry.mtime_ns
+            })
+        });
+        let removed = before - entries.len();
+        if removed > 0 {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        removed
+    }
+
+    /// Write the cache back if it changed, replacing the file atomically.
+    pub fn save(&self) -> Result<()> {
+        if !self.dirty.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let path = Self::path_for(&self.root);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writeln!(writer, "{}", HEADER)?;
+        for ((dev, ino), entry) in self.entries.lock().unwrap().iter() {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                dev,
+                ino,
+                entry.size,
+                entry.mtime_ns,
+                entry.algorithm.as_str(),
+                to_hex(&entry.digest),
+                escape_field(&entry.path)
+            )?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_data()?;
+        std::fs::rename(&tmp_path, &path)?;
+        self.dirty.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+fn parse_line(line: &str) -> Option<((u64, u64), CacheEntry)> {
+    let mut fields = line.splitn(7, '\t');
+    let dev = fields.next()?.parse().ok()?;
+    let ino = fields.next()?.parse().ok()?;
+    let entry = CacheEntry {
+        size: fields.next()?.parse().ok()?,
+        mtime_ns: fields.next()?.parse().ok()?,
+        algorithm: HashAlgorithm::parse(fields.next()?).ok()?,
+        digest: from_hex(fields.next()?)?,
+        path: unescape_field(fields.next()?),
+    };
+    Some(((dev, ino), entry))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_cache_round_trip_and_invalidation() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), b"hello").unwrap();
+        let meta = std::fs::metadata(dir.path().join("a.txt")).unwrap();
+
+        let cache = HashCache::open(dir.path(), HashAlgorithm::Blake3).unwrap();
+        let digest = cache.hash_file("a.txt", &meta).unwrap();
+        cache.save().unwrap();
+
+        let cache = HashCache::open(dir.path(), HashAlgorithm::Blake3).unwrap();
+        assert_eq!(cache.get(&meta), Some(digest));
+        let xxh3 = HashCache::open(dir.path(), HashAlgorithm::Xxh3).unwrap();
+        assert_eq!(xxh3.get(&meta), None);
+
+        // A changed file no longer matches its entry, and prune drops it.
+        std::fs::write(dir.path().join("a.txt"), b"changed!").unwrap();
+        let meta = std::fs::metadata(dir.path().join("a.txt")).unwrap();
+        assert_eq!(cache.get(&meta), None);
+        assert_eq!(cache.prune(), 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_malformed_lines_are_skipped_and_dropped() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), b"hello").unwrap();
+        let meta = std::fs::metadata(dir.path().join("a.txt")).unwrap();
+        let cache = HashCache::open(dir.path(), HashAlgorithm::Blake3).unwrap();
+        let digest = cache.hash_file("a.txt", &meta).unwrap();
+        cache.save().unwrap();
+
+        let path = HashCache::path_for(dir.path());
+        let mut content = std::fs::read(&path).unwrap();
+        content.extend_from_slice(b"not\ta cache line\n12\t34\t5\t\xff");
+        std::fs::write(&path, &content).unwrap();
+
+        let cache = HashCache::open(dir.path(), HashAlgorithm::Blake3).unwrap();
+        assert_eq!(cache.get(&meta), Some(digest));
+        assert_eq!(cache.len(), 1);
+        cache.save().unwrap();
+        let rewritten = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(rewritten.lines().count(), 2);
+    }
+}
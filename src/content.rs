@@ -0,0 +1,323 @@
+//! Content-aware comparison for folder-differ
+//!
+//! Some formats have contents that differ byte for byte while meaning the
+//! same thing: JSON with keys in another order, text with other line endings,
+//! a zip whose members were only re-stamped. A [`ContentComparer`] decides
+//! whether two such files are equivalent, and a [`ComparerRegistry`] picks the
+//! comparer for each path by glob. Paths no rule matches are compared byte for
+//! byte as usual.
+
+use crate::{FolderDifferError, Result};
+use globset::{Glob, GlobMatcher};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Decides whether two files of one format have equivalent content.
+///
+/// An `Err` means the files could not be interpreted (e.g. invalid JSON);
+/// they are then compared byte for byte instead.
+pub trait ContentComparer: Send + Sync {
+    /// Short name, as used with `--compare GLOB=NAME`.
+    fn name(&self) -> &str;
+
+    fn equivalent(&self, left: &Path, right: &Path) -> Result<bool>;
+}
+
+/// JSON documents that parse to the same value, whatever their formatting
+/// and key order.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonComparer;
+
+impl ContentComparer for JsonComparer {
+    fn name(&self) -> &str {
+        "json"
+    }
+
+    fn equivalent(&self, left: &Path, right: &Path) -> Result<bool> {
+        let parse = |path: &Path| {
+            serde_json::from_slice::<serde_json::Value>(&std::fs::read(path)?).map_err(|e| {
+                FolderDifferError::Other(format!("Invalid JSON in {}: {}", path.display(), e))
+            })
+        };
+        Ok(parse(left)? == parse(right)?)
+    }
+}
+
+/// YAML documents that parse to the same value, whatever their formatting
+/// and key order.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct YamlComparer;
+
+impl ContentComparer for YamlComparer {
+    fn name(&self) -> &str {
+        "yaml"
+    }
+
+    fn equivalent(&self, left: &Path, right: &Path) -> Result<bool> {
+        let parse = |path: &Path| {
+            serde_yaml::from_slice::<serde_yaml::Value>(&std::fs::read(path)?).map_err(|e| {
+                FolderDifferError::Other(format!("Invalid YAML in {}: {}", path.display(), e))
+            })
+        };
+        Ok(parse(left)? == parse(right)?)
+    }
+}
+
+/// Text that is the same once line endings, trailing whitespace and trailing
+/// blank lines are disregarded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TextComparer;
+
+impl TextComparer {
+    fn lines(content: &[u8]) -> Vec<&[u8]> {
+        let mut lines: Vec<&[u8]> = content
+            .split(|&b| b == b'\n')
+            .map(<[u8]>::trim_ascii_end)
+            .collect();
+        while lines.last().is_some_and(|line| line.is_empty()) {
+            lines.pop();
+        }
+        lines
+    }
+}
+
+impl ContentComparer for TextComparer {
+    fn name(&self) -> &str {
+        "text"
+    }
+
+    fn equivalent(&self, left: &Path, right: &Path) -> Result<bool> {
+        let (left, right) = (std::fs::read(left)?, std::fs::read(right)?);
+        Ok(Self::lines(&left) == Self::lines(&right))
+    }
+}
+
+/// Zip archives (including jar files) with the same member names and
+/// contents, whatever their timestamps, order or compression.
+#[cfg(feature = "archive")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZipComparer;
+
+#[cfg(feature = "archive")]
+impl ZipComparer {
+    /// Name, CRC-32 and size of every file member, sorted by name.
+    fn members(path: &Path) -> Result<Vec<(String, u32, u64)>> {
+        let zip_error = |e: zip::result::ZipError| {
+            FolderDifferError::Other(format!("Cannot read {}: {}", path.display(), e))
+        };
+        let mut archive = zip::ZipArchive::new(std::io::BufReader::new(std::fs::File::open(path)?))
+            .map_err(zip_error)?;
+        let mut members = Vec::with_capacity(archive.len());
+        for index in 0..archive.len() {
+            let member = archive.by_index_raw(index).map_err(zip_error)?;
+            if member.is_file() {
+                members.push((member.name().to_string(), member.crc32(), member.size()));
+            }
+        }
+        members.sort();
+        Ok(members)
+    }
+}
+
+#[cfg(feature = "archive")]
+impl ContentComparer for ZipComparer {
+    fn name(&self) -> &str {
+        "zip"
+    }
+
+    fn equivalent(&self, left: &Path, right: &Path) -> Result<bool> {
+        Ok(Self::members(left)? == Self::members(right)?)
+    }
+}
+
+/// The built-in comparer called `name`: `json`, `yaml`, `text` or (with
+/// the `archive` feature) `zip`.
+pub fn builtin_comparer(name: &str) -> Option<Arc<dyn ContentComparer>> {
+    match name {
+        "json" => Some(Arc::new(JsonComparer)),
+        "yaml" => Some(Arc::new(YamlComparer)),
+        "text" => Some(Arc::new(TextComparer)),
+        #[cfg(feature = "archive")]
+        "zip" => Some(Arc::new(ZipComparer)),
+        _ => None,
+    }
+}
+
+/// Comparers by glob pattern; the first matching rule wins.
+///
+/// Patterns are matched against the path relative to the tree root, and `*`
+/// also matches `/`, so `*.json` applies at any depth.
+#[derive(Clone, Default)]
+pub struct ComparerRegistry {
+    rules: Vec<(Glob, GlobMatcher, Arc<dyn ContentComparer>)>,
+}
+
+impl fmt::Debug for ComparerRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.rules
+                    .iter()
+                    .map(|(glob, _, comparer)| format!("{}={}", glob, comparer.name())),
+            )
+            .finish()
+    }
+}
+
+impl ComparerRegistry {
+    /// A registry with no rules: every file is compared byte for byte.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rules for the built-in comparers: `*.json` as JSON, `*.yaml` and
+    /// `*.yml` as YAML and, with the `archive` feature, `*.zip` and `*.jar`
+    /// by entries.
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+        registry
+            .register("*.json", Arc::new(JsonComparer))
+            .expect("valid pattern");
+        for pattern in ["*.yaml", "*.yml"] {
+            registry
+                .register(pattern, Arc::new(YamlComparer))
+                .expect("valid pattern");
+        }
+        #[cfg(feature = "archive")]
+        for pattern in ["*.zip", "*.jar"] {
+            registry
+                .register(pattern, Arc::new(ZipComparer))
+                .expect("valid pattern");
+        }
+        registry
+    }
+
+    /// Compare paths matching `pattern` with `comparer`, after the rules
+    /// registered before it.
+    pub fn register(&mut self, pattern: &str, comparer: Arc<dyn ContentComparer>) -> Result<()> {
+        let glob = Glob::new(pattern)
+            .map_err(|e| FolderDifferError::Other(format!("Invalid pattern {}: {}", pattern, e)))?;
+        let matcher = glob.compile_matcher();
+        self.rules.push((glob, matcher, comparer));
+        Ok(())
+    }
+
+    /// Add a rule given as `GLOB=NAME` for a built-in comparer.
+    pub fn register_spec(&mut self, spec: &str) -> Result<()> {
+        let (pattern, name) = spec.rsplit_once('=').ok_or_else(|| {
+            FolderDifferError::Other(format!("Expected GLOB=COMPARER, got {}", spec))
+        })?;
+        let comparer = builtin_comparer(name)
+            .ok_or_else(|| FolderDifferError::Other(format!("Unknown comparer: {}", name)))?;
+        self.register(pattern, comparer)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The comparer for the relative path `rel_path`, if any rule matches.
+    pub fn comparer_for(&self, rel_path: &str) -> Option<&dyn ContentComparer> {
+        self.rules
+            .iter()
+            .find(|(_, matcher, _)| matcher.is_match(rel_path))
+            .map(|(_, _, comparer)| comparer.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::tests::backdate;
+    use crate::diff::{CompareContext, compare_dirs_with};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_comparers_suppress_equivalent_changes() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        std::fs::create_dir(left.path().join("conf")).unwrap();
+        std::fs::create_dir(right.path().join("conf")).unwrap();
+        std::fs::write(left.path().join("conf/a.json"), r#"{"a": 1, "b": [1, 2]}"#).unwrap();
+        std::fs::write(
+            right.path().join("conf/a.json"),
+            "{\n  \"b\": [1, 2],\n  \"a\": 1\n}\n",
+        )
+        .unwrap();
+        std::fs::write(left.path().join("b.json"), r#"{"a": 1}"#).unwrap();
+        std::fs::write(right.path().join("b.json"), r#"{"a": 2}"#).unwrap();
+        std::fs::write(left.path().join("app.yaml"), "name: x\nports: [1, 2]\n").unwrap();
+        std::fs::write(
+            right.path().join("app.yaml"),
+            "ports:\n  - 1\n  - 2\nname: x\n",
+        )
+        .unwrap();
+        std::fs::write(left.path().join("notes.md"), "one  \r\ntwo\r\n").unwrap();
+        std::fs::write(right.path().join("notes.md"), "one\ntwo\n\n").unwrap();
+        // Not JSON after all: compared byte for byte.
+        std::fs::write(left.path().join("broken.json"), "{").unwrap();
+        std::fs::write(right.path().join("broken.json"), "[").unwrap();
+        for name in ["b.json", "broken.json"] {
+            backdate(&right.path().join(name), 0);
+        }
+
+        let mut comparers = ComparerRegistry::with_builtins();
+        comparers.register_spec("*.md=text").unwrap();
+        let mut context = CompareContext {
+            comparers,
+            ..CompareContext::default()
+        };
+        let mut paths: Vec<String> = compare_dirs_with(left.path(), right.path(), &context)
+            .unwrap()
+            .into_iter()
+            .map(|d| d.path)
+            .collect();
+        paths.sort();
+        assert_eq!(paths, ["b.json", "broken.json"]);
+
+        // Library users can plug in their own rules.
+        struct AlwaysEqual;
+        impl ContentComparer for AlwaysEqual {
+            fn name(&self) -> &str {
+                "always"
+            }
+            fn equivalent(&self, _left: &Path, _right: &Path) -> Result<bool> {
+                Ok(true)
+            }
+        }
+        context.comparers = ComparerRegistry::new();
+        context
+            .comparers
+            .register("*", Arc::new(AlwaysEqual))
+            .unwrap();
+        assert!(
+            compare_dirs_with(left.path(), right.path(), &context)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[cfg(feature = "archive")]
+    #[test]
+    fn test_zip_comparer_ignores_timestamps() {
+        use std::io::Write;
+        let dir = tempdir().unwrap();
+        let write_zip = |name: &str, year: u16, content: &[u8]| {
+            let path = dir.path().join(name);
+            let mut writer = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+            let time = zip::DateTime::from_date_and_time(year, 1, 1, 0, 0, 0).unwrap();
+            let options = zip::write::SimpleFileOptions::default().last_modified_time(time);
+            writer.start_file("META-INF/MANIFEST.MF", options).unwrap();
+            writer.write_all(content).unwrap();
+            writer.finish().unwrap();
+            path
+        };
+        let a = write_zip("a.jar", 2001, b"Version: 1\n");
+        let b = write_zip("b.jar", 2024, b"Version: 1\n");
+        let c = write_zip("c.jar", 2024, b"Version: 2\n");
+        assert_ne!(std::fs::read(&a).unwrap(), std::fs::read(&b).unwrap());
+        assert!(ZipComparer.equivalent(&a, &b).unwrap());
+        assert!(!ZipComparer.equivalent(&b, &c).unwrap());
+    }
+}
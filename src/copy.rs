@@ -0,0 +1,607 @@
+//! File copy primitives used by sync for folder-differ
+
+use crate::hash::{hash_blocks, read_block};
+use crate::sparse::data_segments;
+use crate::{FolderDifferError, Result};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Buffer size for streaming copies.
+const COPY_BUF_SIZE: usize = 256 * 1024;
+
+/// Block size used to match unchanged data in delta transfers.
+pub const DELTA_BLOCK_SIZE: usize = 128 * 1024;
+
+/// Files smaller than this are always copied whole.
+pub const DELTA_MIN_SIZE: u64 = 1024 * 1024;
+
+/// How sync copies file contents, as chosen with `--copy-method`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CopyMethod {
+    /// Clone extents when the filesystem supports it, otherwise copy.
+    #[default]
+    Auto,
+    /// Only clone extents; fail where reflinks are unsupported.
+    Reflink,
+    /// Always copy the data.
+    Copy,
+}
+
+impl CopyMethod {
+    /// Parse `auto`, `reflink` or `copy`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        match spec {
+            "auto" => Ok(CopyMethod::Auto),
+            "reflink" => Ok(CopyMethod::Reflink),
+            "copy" => Ok(CopyMethod::Copy),
+            _ => Err(FolderDifferError::Other(format!(
+                "Unknown copy method: {} (expected auto, reflink or copy)",
+                spec
+            ))),
+        }
+    }
+}
+
+/// The mechanism that actually performed a copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyBackend {
+    /// Extents shared with the source via `FICLONE`; no data was copied.
+    Reflink,
+    /// Data copied in the kernel with `copy_file_range`.
+    CopyFileRange,
+    /// Data read and written through a user-space buffer.
+    Stream,
+    /// Only changed blocks taken from the source, see [`delta_update`].
+    Delta,
+    /// Only data segments copied, holes recreated, see [`sparse_copy`].
+    Sparse,
+    /// Hardlinked to another path of the destination; no data was copied.
+    Hardlink,
+}
+
+impl CopyBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CopyBackend::Reflink => "reflink",
+            CopyBackend::CopyFileRange => "copy_file_range",
+            CopyBackend::Stream => "stream",
+            CopyBackend::Delta => "delta",
+            CopyBackend::Sparse => "sparse",
+            CopyBackend::Hardlink => "hardlink",
+        }
+    }
+}
+
+/// What a copy wrote: the file size, the bytes taken from the source and the
+/// backend used.
+///
+/// For a whole-file copy the sizes are equal; a delta transfer only counts
+/// the blocks that changed and a reflink counts nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CopyStats {
+    pub size: u64,
+    pub transferred: u64,
+    pub backend: CopyBackend,
+}
+
+impl fmt::Display for CopyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} of {} bytes transferred",
+            self.backend.as_str(),
+            self.transferred,
+            self.size
+        )
+    }
+}
+
+/// A shared bytes-per-second limit across all copy threads.
+///
+/// Callers report bytes as they move them and are put to sleep whenever the
+/// total gets ahead of the configured rate.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_sec: u64,
+    state: Mutex<(Instant, u64)>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            state: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// The configured limit.
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Account for `bytes` transferred, sleeping if over the limit.
+    pub fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            state.1 += bytes;
+            let due = Duration::from_secs_f64(state.1 as f64 / self.bytes_per_sec as f64);
+            due.saturating_sub(state.0.elapsed())
+        };
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// Clone `src` into `dst` with the `FICLONE` ioctl.
+///
+/// Fails on filesystems without reflink support (and off Linux); `dst` may
+/// then be left behind empty.
+pub fn reflink_file(src: &Path, dst: &Path) -> Result<CopyStats> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        let reader = File::open(src)?;
+        let meta = reader.metadata()?;
+        let writer = File::create(dst)?;
+        // SAFETY: both descriptors are open for the duration of the call.
+        let ret = unsafe { libc::ioctl(writer.as_raw_fd(), libc::FICLONE, reader.as_raw_fd()) };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        std::fs::set_permissions(dst, meta.permissions())?;
+        Ok(CopyStats {
+            size: meta.len(),
+            transferred: 0,
+            backend: CopyBackend::Reflink,
+        })
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (src, dst);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "reflinks are not supported on this platform",
+        )
+        .into())
+    }
+}
+
+/// Make `dst` a hardlink to `existing`.
+pub fn link_file(existing: &Path, dst: &Path) -> Result<CopyStats> {
+    std::fs::hard_link(existing, dst)?;
+    Ok(CopyStats {
+        size: std::fs::metadata(dst)?.len(),
+        transferred: 0,
+        backend: CopyBackend::Hardlink,
+    })
+}
+
+/// Copy the data of `src` to `dst`, optionally rate-limited.
+///
+/// Uses `copy_file_range` where available and falls back to a streaming copy.
+/// Permissions are copied as with `std::fs::copy`.
+pub fn copy_file(src: &Path, dst: &Path, throttle: Option<&Throttle>) -> Result<CopyStats> {
+    let mut reader = File::open(src)?;
+    let permissions = reader.metadata()?.permissions();
+    let mut writer = File::create(dst)?;
+    #[cfg(target_os = "linux")]
+    let in_kernel = copy_range(&reader, &writer, throttle)?;
+    #[cfg(not(target_os = "linux"))]
+    let in_kernel = None;
+    let (size, backend) = match in_kernel {
+        Some(size) => (size, CopyBackend::CopyFileRange),
+        None => (
+            stream_copy(&mut reader, &mut writer, throttle)?,
+            CopyBackend::Stream,
+        ),
+    };
+    std::fs::set_permissions(dst, permissions)?;
+    Ok(CopyStats {
+        size,
+        transferred: size,
+        backend,
+    })
+}
+
+/// Copy with `copy_file_range`, or return `None` if the kernel or filesystem
+/// cannot do it (before anything was copied).
+#[cfg(target_os = "linux")]
+fn copy_range(reader: &File, writer: &File, throttle: Option<&Throttle>) -> Result<Option<u64>> {
+    use std::os::fd::AsRawFd;
+    let chunk = if throttle.is_some() {
+        COPY_BUF_SIZE
+    } else {
+        1 << 30
+    };
+    let mut total = 0u64;
+    loop {
+        // SAFETY: both descriptors are open; null offsets use the file positions.
+        let n = unsafe {
+            libc::copy_file_range(
+                reader.as_raw_fd(),
+                std::ptr::null_mut(),
+                writer.as_raw_fd(),
+                std::ptr::null_mut(),
+                chunk,
+                0,
+            )
+        };
+        if n < 0 {
+            let err = std::io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(
+                    libc::ENOSYS | libc::EXDEV | libc::EINVAL | libc::EOPNOTSUPP | libc::EPERM,
+                ) if total == 0 => {
+                    return Ok(None);
+                }
+                _ => return Err(err.into()),
+            }
+        }
+        if n == 0 {
+            return Ok(Some(total));
+        }
+        if let Some(throttle) = throttle {
+            throttle.consume(n as u64);
+        }
+        total += n as u64;
+    }
+}
+
+fn stream_copy(reader: &mut File, writer: &mut File, throttle: Option<&Throttle>) -> Result<u64> {
+    let mut buf = vec![0u8; COPY_BUF_SIZE];
+    let mut total = 0u64;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buf[..n])?;
+        if let Some(throttle) = throttle {
+            throttle.consume(n as u64);
+        }
+        total += n as u64;
+    }
+    writer.flush()?;
+    Ok(total)
+}
+
+/// Copy a sparse `src` to `dst`, keeping its holes.
+///
+/// Only the data segments are read and written; `dst` is extended to the
+/// full length so the gaps between them stay unallocated.
+pub fn sparse_copy(src: &Path, dst: &Path, throttle: Option<&Throttle>) -> Result<CopyStats> {
+    let mut reader = File::open(src)?;
+    let meta = reader.metadata()?;
+    let mut writer = File::create(dst)?;
+    let mut buf = vec![0u8; COPY_BUF_SIZE];
+    let mut transferred = 0u64;
+    for (start, end) in data_segments(&reader)? {
+        reader.seek(SeekFrom::Start(start))?;
+        writer.seek(SeekFrom::Start(start))?;
+        let mut remaining = end - start;
+        while remaining > 0 {
+            let want = remaining.min(buf.len() as u64) as usize;
+            let n = reader.read(&mut buf[..want])?;
+            if n == 0 {
+                break;
+            }
+            writer.write_all(&buf[..n])?;
+            if let Some(throttle) = throttle {
+                throttle.consume(n as u64);
+            }
+            remaining -= n as u64;
+            transferred += n as u64;
+        }
+    }
+    writer.set_len(meta.len())?;
+    std::fs::set_permissions(dst, meta.permissions())?;
+    Ok(CopyStats {
+        size: meta.len(),
+        transferred,
+        backend: CopyBackend::Sparse,
+    })
+}
+
+/// Magic bytes at the start of a block backup.
+const BLOCK_BACKUP_MAGIC: &[u8; 8] = b"FDBLOCK1";
+
+/// The blocks of a file overwritten by [`delta_update`]: enough to rebuild
+/// the old version with [`restore_blocks`].
+///
+/// The file holds the magic bytes, the old length, mode and times, then one
+/// record (offset, length, data) per overwritten block. Each record reaches
+/// the disk before the block it saves is overwritten, so an update
+/// interrupted at any point can be undone.
+struct BlockBackup {
+    file: File,
+}
+
+impl BlockBackup {
+    fn create(path: &Path, original: &std::fs::Metadata) -> Result<Self> {
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::PermissionsExt::mode(&original.permissions());
+        #[cfg(not(unix))]
+        let mode = u32::from(original.permissions().readonly());
+        let atime = filetime::FileTime::from_last_access_time(original);
+        let mtime = filetime::FileTime::from_last_modification_time(original);
+        let mut header = Vec::with_capacity(48);
+        header.extend_from_slice(BLOCK_BACKUP_MAGIC);
+        header.extend_from_slice(&original.len().to_le_bytes());
+        header.extend_from_slice(&mode.to_le_bytes());
+        for time in [atime, mtime] {
+            header.extend_from_slice(&time.unix_seconds().to_le_bytes());
+            header.extend_from_slice(&time.nanoseconds().to_le_bytes());
+        }
+        let mut file = File::create(path)?;
+        file.write_all(&header)?;
+        file.sync_data()?;
+        Ok(Self { file })
+    }
+
+    /// Save the old `data` at `offset`, durably.
+    fn save(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let mut record = Vec::with_capacity(12 + data.len());
+        record.extend_from_slice(&offset.to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(data);
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Bring `dst` up to date with `src` in place, writing only the blocks that
+/// differ.
+///
+/// The destination is hashed block by block first ([`hash_blocks`]); `src` is
+/// then streamed once and each of its blocks is written only if its BLAKE3
+/// digest differs from the destination block at the same offset. Kept blocks
+/// keep their extents (and holes), and `dst` keeps its inode. Every block
+/// about to be overwritten, and any tail cut off, is first saved to a block
+/// backup at `backup`. Only the rewritten blocks count as transferred and go
+/// through the throttle.
+pub fn delta_update(
+    src: &Path,
+    dst: &Path,
+    backup: &Path,
+    block_size: usize,
+    throttle: Option<&Throttle>,
+) -> Result<CopyStats> {
+    let old_blocks = hash_blocks(dst, block_size)?;
+    let mut reader = File::open(src)?;
+    let permissions = reader.metadata()?.permissions();
+    let mut writer = OpenOptions::new().read(true).write(true).open(dst)?;
+    let old_len = writer.metadata()?.len();
+    let mut undo = BlockBackup::create(backup, &writer.metadata()?)?;
+    let mut buf = vec![0u8; block_size];
+    let mut old_buf = vec![0u8; block_size];
+    let mut stats = CopyStats {
+        size: 0,
+        transferred: 0,
+        backend: CopyBackend::Delta,
+    };
+    for index in 0.. {
+        let n = read_block(&mut reader, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        let digest = blake3::hash(&buf[..n]);
+        if old_blocks.get(index) != Some(digest.as_bytes()) {
+            writer.seek(SeekFrom::Start(stats.size))?;
+            let old_n = read_block(&mut writer, &mut old_buf[..n])?;
+            undo.save(stats.size, &old_buf[..old_n])?;
+            writer.seek(SeekFrom::Start(stats.size))?;
+            writer.write_all(&buf[..n])?;
+            if let Some(throttle) = throttle {
+                throttle.consume(n as u64);
+            }
+            stats.transferred += n as u64;
+        }
+        stats.size += n as u64;
+    }
+    if old_len > stats.size {
+        writer.seek(SeekFrom::Start(stats.size))?;
+        let mut offset = stats.size;
+        loop {
+            let n = read_block(&mut writer, &mut old_buf)?;
+            if n == 0 {
+                break;
+            }
+            undo.save(offset, &old_buf[..n])?;
+            offset += n as u64;
+        }
+        writer.set_len(stats.size)?;
+    }
+    writer.flush()?;
+    std::fs::set_permissions(dst, permissions)?;
+    Ok(stats)
+}
+
+/// Rebuild the old version of `target` from a block backup written by
+/// [`delta_update`], including its length, mode and times.
+///
+/// Safe to repeat, and to use on an update that was cut short.
+pub fn restore_blocks(backup: &Path, target: &Path) -> Result<()> {
+    let mut reader = BufReader::new(File::open(backup)?);
+    let bad = || FolderDifferError::Other(format!("Corrupt block backup {}", backup.display()));
+    let mut header = [0u8; 44];
+    reader.read_exact(&mut header).map_err(|_| bad())?;
+    if &header[..8] != BLOCK_BACKUP_MAGIC {
+        return Err(bad());
+    }
+    let u64_at = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+    let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+    let old_len = u64_at(8);
+    let mode = u32_at(16);
+    let atime = filetime::FileTime::from_unix_time(u64_at(20) as i64, u32_at(28));
+    let mtime = filetime::FileTime::from_unix_time(u64_at(32) as i64, u32_at(40));
+    let mut writer = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(target)?;
+    let mut record = [0u8; 12];
+    let mut data = Vec::new();
+    loop {
+        match reader.read_exact(&mut record) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let offset = u64::from_le_bytes(record[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(record[8..].try_into().unwrap()) as usize;
+        data.resize(len, 0);
+        // A record torn by a crash was never followed by its overwrite.
+        if reader.read_exact(&mut data).is_err() {
+            break;
+        }
+        writer.seek(SeekFrom::Start(offset))?;
+        writer.write_all(&data)?;
+    }
+    writer.set_len(old_len)?;
+    drop(writer);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(target, std::fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    {
+        let mut permissions = std::fs::metadata(target)?.permissions();
+        permissions.set_readonly(mode != 0);
+        std::fs::set_permissions(target, permissions)?;
+    }
+    filetime::set_file_times(target, atime, mtime)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_throttled_copy() {
+        let dir = tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        let dst = dir.path().join("dst.bin");
+        std::fs::write(&src, vec![1u8; 64 * 1024]).unwrap();
+        let throttle = Throttle::new(512 * 1024);
+
+        let start = Instant::now();
+        let stats = copy_file(&src, &dst, Some(&throttle)).unwrap();
+        assert_eq!(stats.transferred, 64 * 1024);
+        assert_eq!(std::fs::read(&dst).unwrap(), std::fs::read(&src).unwrap());
+        // 64KB at 512KB/s takes at least ~125ms.
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_delta_update_rewrites_changed_blocks_only() {
+        let dir = tempdir().unwrap();
+        let src = dir.path().join("new.bin");
+        let dst = dir.path().join("dst.bin");
+        let backup = dir.path().join("dst.blocks");
+        // The destination has a hole in its middle block, which the source
+        // matches with zeros: rewriting that block would allocate it.
+        let block = 64 * 1024;
+        let mut old = File::create(&dst).unwrap();
+        old.write_all(&vec![1u8; block]).unwrap();
+        old.seek(SeekFrom::Start(2 * block as u64)).unwrap();
+        old.write_all(&vec![3u8; block]).unwrap();
+        drop(old);
+        let old_meta = std::fs::metadata(&dst).unwrap();
+        let old_content = std::fs::read(&dst).unwrap();
+        let mut content = old_content.clone();
+        content[2 * block + 10] = 9;
+        content.extend_from_slice(b"appended");
+        std::fs::write(&src, &content).unwrap();
+
+        let stats = delta_update(&src, &dst, &backup, block, None).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), content);
+        assert_eq!(stats.size, content.len() as u64);
+        // The third block changed and the short last block is new.
+        assert_eq!(stats.transferred, block as u64 + 8);
+        let new_meta = std::fs::metadata(&dst).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(new_meta.ino(), old_meta.ino());
+        }
+        if crate::sparse::is_sparse(&old_meta) {
+            // Writing the hole would have allocated a whole block.
+            assert!(
+                crate::sparse::allocated_bytes(&new_meta)
+                    < crate::sparse::allocated_bytes(&old_meta) + block as u64
+            );
+        }
+        // Only the overwritten block was backed up, plus the header and one
+        // empty record for the appended data.
+        assert!(std::fs::metadata(&backup).unwrap().len() < 2 * block as u64);
+
+        restore_blocks(&backup, &dst).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), old_content);
+        assert_eq!(
+            std::fs::metadata(&dst).unwrap().modified().unwrap(),
+            old_meta.modified().unwrap()
+        );
+
+        // A shrinking file has its cut-off tail backed up as well.
+        std::fs::write(&src, &old_content[..block]).unwrap();
+        let stats = delta_update(&src, &dst, &backup, block, None).unwrap();
+        assert_eq!(stats.transferred, 0);
+        assert_eq!(std::fs::read(&dst).unwrap(), &old_content[..block]);
+        restore_blocks(&backup, &dst).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), old_content);
+    }
+
+    #[test]
+    fn test_copy_methods() {
+        assert_eq!(CopyMethod::parse("auto").unwrap(), CopyMethod::Auto);
+        assert_eq!(CopyMethod::parse("reflink").unwrap(), CopyMethod::Reflink);
+        assert!(CopyMethod::parse("clone").is_err());
+
+        let dir = tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        std::fs::write(&src, b"some data").unwrap();
+        let dst = dir.path().join("copy.bin");
+        let stats = copy_file(&src, &dst, None).unwrap();
+        assert_ne!(stats.backend, CopyBackend::Reflink);
+        assert_eq!(stats.transferred, 9);
+        assert_eq!(std::fs::read(&dst).unwrap(), b"some data");
+
+        // Reflinks depend on the filesystem; when they work, no data moves.
+        let dst = dir.path().join("clone.bin");
+        if let Ok(stats) = reflink_file(&src, &dst) {
+            assert_eq!(stats.transferred, 0);
+            assert_eq!(std::fs::read(&dst).unwrap(), b"some data");
+        }
+    }
+
+    #[test]
+    fn test_sparse_copy_keeps_content() {
+        let dir = tempdir().unwrap();
+        let src = dir.path().join("disk.img");
+        let dst = dir.path().join("copy.img");
+        let mut file = File::create(&src).unwrap();
+        file.seek(SeekFrom::Start(1 << 20)).unwrap();
+        file.write_all(b"data").unwrap();
+        file.set_len(4 << 20).unwrap();
+        drop(file);
+
+        let stats = sparse_copy(&src, &dst, None).unwrap();
+        assert_eq!(stats.size, 4 << 20);
+        assert_eq!(std::fs::read(&dst).unwrap(), std::fs::read(&src).unwrap());
+        let src_meta = std::fs::metadata(&src).unwrap();
+        if crate::sparse::is_sparse(&src_meta) {
+            assert!(stats.transferred < stats.size);
+            assert!(crate::sparse::is_sparse(&std::fs::metadata(&dst).unwrap()));
+        }
+    }
+}
@@ -1,16 +1,25 @@
 //! Diffing logic and types for folder-differ
 
+use crate::Result;
+use crate::cache::HashCache;
+use crate::content::ComparerRegistry;
 use crate::get_dir_files_with_ignore;
-use crate::hash::{compare_small_files, hash_file};
-use crate::{FolderDifferError, Result};
+use crate::hash::{
+    HashAlgorithm, InodeHashes, SAMPLE_MIN_SIZE, compare_small_files, hash_file_full,
+    hash_file_with,
+};
+use crate::links::{LinkGroups, inode_key};
+use crate::normalize::TextNormalization;
+use crate::sparse::{allocated_bytes, is_sparse};
+use crate::sync::Side;
 use rayon::prelude::*;
 use rustc_hash::{FxHashMap, FxHashSet};
 use std::fs::Metadata;
 use std::path::Path;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 /// The type of difference between two files or directories.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DiffType {
     OnlyInLeft,
     OnlyInRight,
@@ -19,16 +28,149 @@ pub enum DiffType {
         right_size: u64,
         left_time: Option<SystemTime>,
         right_time: Option<SystemTime>,
+        /// What was disregarded when the contents were compared, if the
+        /// text was normalized first.
+        normalization: Option<String>,
+    },
+    /// Same content, but one side is sparse and the other is not.
+    AllocationDiffers {
+        size: u64,
+        left_allocated: u64,
+        right_allocated: u64,
+    },
+    /// Same content, but modification times further apart than the
+    /// tolerance. Only reported when [`CompareContext::report_time_diffs`]
+    /// is set.
+    TimeDiffers {
+        size: u64,
+        left_time: Option<SystemTime>,
+        right_time: Option<SystemTime>,
+    },
+    /// Same content, but different permission bits.
+    ModeDiffers {
+        left_mode: u32,
+        right_mode: u32,
+    },
+    /// The paths hardlinked together differ between the trees.
+    ///
+    /// Groups only list paths present in both trees.
+    HardlinksDiffer {
+        left_group: Vec<String>,
+        right_group: Vec<String>,
     },
 }
 
 /// Represents a difference found between two directories.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Diff {
     pub path: String,
     pub diff_type: DiffType,
 }
 
+/// Largest whole-hour offset that [`TimeTolerance::ignore_hour_offsets`]
+/// disregards.
+const MAX_HOUR_OFFSET: Duration = Duration::from_secs(24 * 3600);
+
+/// When two modification times count as equal.
+///
+/// The default is exact equality. Files on FAT/exFAT keep mtimes at 2-second
+/// granularity, and SMB shares may report them shifted by whole hours around
+/// DST changes; both make unchanged files look modified.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeTolerance {
+    /// Largest difference still taken as equal.
+    pub window: Duration,
+    /// Also take mtimes a whole number of hours apart (up to a day, give or
+    /// take `window`) as equal.
+    pub ignore_hour_offsets: bool,
+}
+
+impl TimeTolerance {
+    /// Whether `left` and `right` count as the same mtime. Unknown times only
+    /// match each other.
+    pub fn matches(&self, left: Option<SystemTime>, right: Option<SystemTime>) -> bool {
+        let (Some(left), Some(right)) = (left, right) else {
+            return left == right;
+        };
+        let delta = left.duration_since(right).unwrap_or_else(|e| e.duration());
+        if delta <= self.window {
+            return true;
+        }
+        if !self.ignore_hour_offsets || delta > MAX_HOUR_OFFSET + self.window {
+            return false;
+        }
+        let hour = Duration::from_secs(3600).as_nanos();
+        let off_hour = delta.as_nanos() % hour;
+        off_hour.min(hour - off_hour) <= self.window.as_nanos()
+    }
+}
+
+/// State shared by all comparisons of one run.
+#[derive(Debug, Default)]
+pub struct CompareContext {
+    /// Digests of hardlinked files, so each inode is hashed once.
+    pub inode_hashes: InodeHashes,
+    /// Algorithm used to hash file contents.
+    pub algorithm: HashAlgorithm,
+    /// Persistent hash caches of the left and right trees, if enabled.
+    pub left_cache: Option<HashCache>,
+    pub right_cache: Option<HashCache>,
+    /// Format-aware comparers by path; empty means byte-for-byte only.
+    pub comparers: ComparerRegistry,
+    /// Noise to disregard in text files; empty means none.
+    pub normalization: TextNormalization,
+    /// When mtimes count as equal, so that files are not hashed.
+    pub time_tolerance: TimeTolerance,
+    /// Report files with equal content but differing mtimes as
+    /// [`DiffType::TimeDiffers`] instead of passing them over.
+    pub report_time_diffs: bool,
+}
+
+impl CompareContext {
+    /// A context hashing with `algorithm` and no cache.
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        Self {
+            algorithm,
+            ..Self::default()
+        }
+    }
+
+    /// A context that uses (and fills) the on-disk hash cache of each tree.
+    pub fn with_caches(left: &Path, right: &Path, algorithm: HashAlgorithm) -> Result<Self> {
+        Ok(Self {
+            left_cache: Some(HashCache::open(left, algorithm)?),
+            right_cache: Some(HashCache::open(right, algorithm)?),
+            ..Self::new(algorithm)
+        })
+    }
+
+    /// Hash the file `rel_path` of the tree at `root` on `side`.
+    pub fn hash(
+        &self,
+        side: Side,
+        root: &Path,
+        rel_path: &str,
+        meta: &Metadata,
+    ) -> Result<Vec<u8>> {
+        let cache = match side {
+            Side::Left => self.left_cache.as_ref(),
+            Side::Right => self.right_cache.as_ref(),
+        };
+        self.inode_hashes.get_or_hash(meta, || match cache {
+            Some(cache) => cache.hash_file(rel_path, meta),
+            None => hash_file_with(&root.join(rel_path), self.algorithm),
+        })
+    }
+
+    /// Write back any hash cache that changed.
+    pub fn save_caches(&self) -> Result<()> {
+        for cache in self.left_cache.iter().chain(&self.right_cache) {
+            cache.save()?;
+        }
+        Ok(())
+    }
+}
+
 /// Compares two directories and returns a list of differences.
 ///
 /// # Arguments
@@ -38,94 +180,270 @@ pub struct Diff {
 /// # Returns
 /// A vector of `Diff` representing the differences found.
 pub fn compare_dirs(left: &Path, right: &Path) -> Result<Vec<Diff>> {
+    compare_dirs_with(left, right, &CompareContext::default())
+}
+
+/// Like [`compare_dirs`], but hashing, caching and comparing contents as
+/// `context` says.
+pub fn compare_dirs_with(left: &Path, right: &Path, context: &CompareContext) -> Result<Vec<Diff>> {
     let mut left_files: FxHashMap<String, Metadata> = FxHashMap::default();
     let mut right_files: FxHashMap<String, Metadata> = FxHashMap::default();
 
-    rayon::join(
+    let (left_res, right_res) = rayon::join(
         || get_dir_files_with_ignore(left, &mut left_files, &[]),
         || get_dir_files_with_ignore(right, &mut right_files, &[]),
     );
+    left_res?;
+    right_res?;
 
     let all_paths: FxHashSet<_> = left_files.keys().chain(right_files.keys()).collect();
-    let diffs: Vec<Diff> = all_paths
+    let mut diffs: Vec<Diff> = all_paths
         .par_iter()
         .map(
             |path| match (left_files.get(*path), right_files.get(*path)) {
                 (Some(left_meta), Some(right_meta)) => {
-                    let left_size = left_meta.len();
-                    let right_size = right_meta.len();
-                    let left_time = left_meta.modified().ok();
-                    let right_time = right_meta.modified().ok();
-                    if left_size != right_size {
-                        Ok::<Option<Diff>, FolderDifferError>(Some(Diff {
-                            path: (*path).clone(),
-                            diff_type: DiffType::Different {
-                                left_size,
-                                right_size,
-                                left_time,
-                                right_time,
-                            },
-                        }))
-                    } else if left_time != right_time {
-                        let left_path = left.join(*path);
-                        let right_path = right.join(*path);
-                        if left_size < 1024 {
-                            if compare_small_files(&left_path, &right_path)? == false {
-                                Ok::<Option<Diff>, FolderDifferError>(Some(Diff {
-                                    path: (*path).clone(),
-                                    diff_type: DiffType::Different {
-                                        left_size,
-                                        right_size,
-                                        left_time,
-                                        right_time,
-                                    },
-                                }))
-                            } else {
-                                Ok::<Option<Diff>, FolderDifferError>(None)
-                            }
-                        } else {
-                            let left_hash = hash_file(&left_path)?;
-                            let right_hash = hash_file(&right_path)?;
-                            if left_hash != right_hash {
-                                Ok::<Option<Diff>, FolderDifferError>(Some(Diff {
-                                    path: (*path).clone(),
-                                    diff_type: DiffType::Different {
-                                        left_size,
-                                        right_size,
-                                        left_time,
-                                        right_time,
-                                    },
-                                }))
-                            } else {
-                                Ok::<Option<Diff>, FolderDifferError>(None)
-                            }
-                        }
-                    } else {
-                        Ok::<Option<Diff>, FolderDifferError>(None)
-                    }
+                    compare_entry(path, left, right, left_meta, right_meta, context)
                 }
-                (Some(_), None) => Ok::<Option<Diff>, FolderDifferError>(Some(Diff {
+                (Some(_), None) => Ok(Some(Diff {
                     path: (*path).clone(),
                     diff_type: DiffType::OnlyInLeft,
                 })),
-                (None, Some(_)) => Ok::<Option<Diff>, FolderDifferError>(Some(Diff {
+                (None, Some(_)) => Ok(Some(Diff {
                     path: (*path).clone(),
                     diff_type: DiffType::OnlyInRight,
                 })),
-                (None, None) => Ok::<Option<Diff>, FolderDifferError>(None),
+                (None, None) => Ok(None),
             },
         )
-        .collect::<std::result::Result<Vec<_>, _>>()?
+        .collect::<Result<Vec<_>>>()?
         .into_iter()
         .flatten()
         .collect();
+    let reported: FxHashSet<&str> = diffs.iter().map(|d| d.path.as_str()).collect();
+    let grouping = hardlink_diffs(&left_files, &right_files, &reported);
+    diffs.extend(grouping);
     Ok(diffs)
 }
 
+/// Compares one path of the two trees, whichever sides it exists on.
+///
+/// Used to re-check single paths after a full comparison; anything that is
+/// not a regular file counts as absent.
+pub fn compare_path(
+    path: &str,
+    left: &Path,
+    right: &Path,
+    context: &CompareContext,
+) -> Result<Option<Diff>> {
+    let stat = |root: &Path| match std::fs::metadata(root.join(path)) {
+        Ok(meta) if meta.is_file() => Ok(Some(meta)),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    };
+    let diff_type = match (stat(left)?, stat(right)?) {
+        (Some(left_meta), Some(right_meta)) => {
+            return compare_entry(path, left, right, &left_meta, &right_meta, context);
+        }
+        (Some(_), None) => DiffType::OnlyInLeft,
+        (None, Some(_)) => DiffType::OnlyInRight,
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(Diff {
+        path: path.to_string(),
+        diff_type,
+    }))
+}
+
+/// Compares a file present in both trees.
+///
+/// Files of different size differ. Files whose mtimes differ (beyond
+/// `context.time_tolerance`) have their
+/// content compared (byte-wise when small, by hash otherwise) unless both
+/// paths are links to the same inode. Files with equal content are still
+/// reported if only one of them is sparse.
+///
+/// Paths that have a comparer in `context.comparers` are instead compared by
+/// it whenever size or mtime differ; if it cannot read them, the byte-wise
+/// comparison applies. Otherwise, with `context.normalization` set, text
+/// files whose size or mtime differ are compared after normalization.
+/// Identical files whose mtimes differ are reported as `TimeDiffers` if
+/// `context.report_time_diffs` is set.
+pub fn compare_entry(
+    path: &str,
+    left: &Path,
+    right: &Path,
+    left_meta: &Metadata,
+    right_meta: &Metadata,
+    context: &CompareContext,
+) -> Result<Option<Diff>> {
+    let left_size = left_meta.len();
+    let right_size = right_meta.len();
+    let left_time = left_meta.modified().ok();
+    let right_time = right_meta.modified().ok();
+    let different_after = |normalization: Option<String>| Diff {
+        path: path.to_string(),
+        diff_type: DiffType::Different {
+            left_size,
+            right_size,
+            left_time,
+            right_time,
+            normalization,
+        },
+    };
+    let different = || different_after(None);
+    let same_inode =
+        inode_key(left_meta).is_some() && inode_key(left_meta) == inode_key(right_meta);
+    let times_match = context.time_tolerance.matches(left_time, right_time);
+    if let Some(comparer) = context.comparers.comparer_for(path)
+        && !same_inode
+        && (left_size != right_size || !times_match)
+    {
+        match comparer.equivalent(&left.join(path), &right.join(path)) {
+            Ok(true) => return Ok(None),
+            Ok(false) => return Ok(Some(different())),
+            Err(e) => log::debug!(
+                "{} comparer failed on {}, comparing bytes: {}",
+                comparer.name(),
+                path,
+                e
+            ),
+        }
+    }
+    if !context.normalization.is_empty()
+        && !same_inode
+        && (left_size != right_size || !times_match)
+        && let Some(left_digest) = context
+            .normalization
+            .hash_file(&left.join(path), context.algorithm)?
+        && let Some(right_digest) = context
+            .normalization
+            .hash_file(&right.join(path), context.algorithm)?
+    {
+        if left_digest == right_digest {
+            return Ok(None);
+        }
+        return Ok(Some(different_after(Some(
+            context.normalization.describe(),
+        ))));
+    }
+    if left_size != right_size {
+        return Ok(Some(different()));
+    }
+    if !times_match && !same_inode {
+        let left_path = left.join(path);
+        let right_path = right.join(path);
+        let equal = if left_size < 1024 {
+            compare_small_files(&left_path, &right_path)?
+        } else if left_size >= SAMPLE_MIN_SIZE && is_sparse(left_meta) != is_sparse(right_meta) {
+            // The sparse side is hashed in full, so its twin must be too.
+            hash_file_full(&left_path, context.algorithm)?
+                == hash_file_full(&right_path, context.algorithm)?
+        } else {
+            context.hash(Side::Left, left, path, left_meta)?
+                == context.hash(Side::Right, right, path, right_meta)?
+        };
+        if !equal {
+            return Ok(Some(different()));
+        }
+    }
+    if is_sparse(left_meta) != is_sparse(right_meta) {
+        return Ok(Some(Diff {
+            path: path.to_string(),
+            diff_type: DiffType::AllocationDiffers {
+                size: left_size,
+                left_allocated: allocated_bytes(left_meta),
+                right_allocated: allocated_bytes(right_meta),
+            },
+        }));
+    }
+    if context.report_time_diffs && !times_match {
+        return Ok(Some(Diff {
+            path: path.to_string(),
+            diff_type: DiffType::TimeDiffers {
+                size: left_size,
+                left_time,
+                right_time,
+            },
+        }));
+    }
+    Ok(None)
+}
+
+/// Reports paths whose hardlink grouping differs between the trees.
+///
+/// Groups are restricted to paths present on both sides, so a link that is
+/// simply missing shows up only as `OnlyInLeft`/`OnlyInRight`. One diff is
+/// produced per mismatch, and every group of either tree that takes part in
+/// a mismatch is the `left_group` or `right_group` of some diff, so sync can
+/// rebuild the grouping from the diffs alone.
+///
+/// `reported` holds the paths that already have a diff of their own; a
+/// mismatch is reported at the first of its paths not among them, and not
+/// at all if there is none.
+pub fn hardlink_diffs(
+    left_files: &FxHashMap<String, Metadata>,
+    right_files: &FxHashMap<String, Metadata>,
+    reported: &FxHashSet<&str>,
+) -> Vec<Diff> {
+    let left_groups = LinkGroups::from_files(left_files);
+    let right_groups = LinkGroups::from_files(right_files);
+    let common =
+        |groups: &LinkGroups, path: &String, other: &FxHashMap<String, Metadata>| match groups
+            .group(path)
+        {
+            Some(group) => group
+                .iter()
+                .filter(|p| other.contains_key(*p))
+                .cloned()
+                .collect(),
+            None => vec![path.clone()],
+        };
+    let mut paths: Vec<&String> = left_groups
+        .iter()
+        .chain(right_groups.iter())
+        .flatten()
+        .filter(|p| left_files.contains_key(*p) && right_files.contains_key(*p))
+        .collect();
+    paths.sort();
+    paths.dedup();
+    // Paths whose left (right) group is already part of a diff.
+    let mut covered_left: FxHashSet<String> = FxHashSet::default();
+    let mut covered_right: FxHashSet<String> = FxHashSet::default();
+    let mut diffs = Vec::new();
+    for path in paths {
+        let left_group: Vec<String> = common(&left_groups, path, right_files);
+        let right_group: Vec<String> = common(&right_groups, path, left_files);
+        let covered = |group: &[String], covered: &FxHashSet<String>| {
+            group.len() == 1 || covered.contains(path)
+        };
+        if left_group == right_group
+            || (covered(&left_group, &covered_left) && covered(&right_group, &covered_right))
+        {
+            continue;
+        }
+        covered_left.extend(left_group.iter().cloned());
+        covered_right.extend(right_group.iter().cloned());
+        let mut members: Vec<&String> = left_group.iter().chain(&right_group).collect();
+        members.sort();
+        let Some(at) = members.into_iter().find(|p| !reported.contains(p.as_str())) else {
+            continue;
+        };
+        diffs.push(Diff {
+            path: at.clone(),
+            diff_type: DiffType::HardlinksDiffer {
+                left_group,
+                right_group,
+            },
+        });
+    }
+    diffs
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use std::fs::{self, File};
+    use std::fs::File;
     use std::io::Write;
     use std::path::Path;
     use tempfile::tempdir;
@@ -135,6 +453,16 @@ mod tests {
         file.write_all(content).unwrap();
     }
 
+    /// Set the mtime of `path` to `secs` seconds after the epoch.
+    ///
+    /// Same-size files written within one clock tick would pass the mtime
+    /// check without their content being compared at all.
+    pub(crate) fn backdate(path: &Path, secs: u64) {
+        let file = File::options().write(true).open(path).unwrap();
+        file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+            .unwrap();
+    }
+
     #[test]
     fn test_identical_dirs() {
         let dir1 = tempdir().unwrap();
@@ -163,6 +491,13 @@ mod tests {
         let dir2 = tempdir().unwrap();
         write_file(&dir1.path().join("a.txt"), b"hello");
         write_file(&dir2.path().join("a.txt"), b"world");
+        // Same size, so make sure the mtimes differ and content gets compared.
+        File::options()
+            .write(true)
+            .open(dir2.path().join("a.txt"))
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .unwrap();
 
         let diffs = super::compare_dirs(dir1.path(), dir2.path()).unwrap();
         assert_eq!(diffs.len(), 1);
@@ -171,4 +506,208 @@ mod tests {
             super::DiffType::Different { .. }
         ));
     }
+
+    #[test]
+    fn test_time_tolerance() {
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let exact = TimeTolerance::default();
+        assert!(exact.matches(Some(t), Some(t)));
+        assert!(!exact.matches(Some(t), Some(t + Duration::from_secs(1))));
+        assert!(!exact.matches(Some(t), None));
+
+        let fat = TimeTolerance {
+            window: Duration::from_secs(2),
+            ignore_hour_offsets: false,
+        };
+        assert!(fat.matches(Some(t + Duration::from_secs(2)), Some(t)));
+        assert!(!fat.matches(Some(t), Some(t + Duration::from_secs(3))));
+        assert!(!fat.matches(Some(t), Some(t + Duration::from_secs(3600))));
+
+        let smb = TimeTolerance {
+            ignore_hour_offsets: true,
+            ..fat
+        };
+        assert!(smb.matches(Some(t), Some(t + Duration::from_secs(3601))));
+        assert!(smb.matches(Some(t + Duration::from_secs(7199)), Some(t)));
+        assert!(!smb.matches(Some(t), Some(t + Duration::from_secs(1800))));
+        assert!(!smb.matches(Some(t), Some(t + Duration::from_secs(48 * 3600))));
+    }
+
+    #[test]
+    fn test_time_only_diffs() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        write_file(&left.path().join("copied.txt"), b"same");
+        write_file(&right.path().join("copied.txt"), b"same");
+        write_file(&left.path().join("fat.txt"), b"data");
+        write_file(&right.path().join("fat.txt"), b"DATA");
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let set_time = |root: &Path, name: &str, time: SystemTime| {
+            let file = File::options().write(true).open(root.join(name)).unwrap();
+            file.set_modified(time).unwrap();
+        };
+        set_time(left.path(), "copied.txt", t);
+        set_time(right.path(), "copied.txt", t + Duration::from_secs(60));
+        // Changed content, but within the window: assumed unchanged.
+        set_time(left.path(), "fat.txt", t);
+        set_time(right.path(), "fat.txt", t + Duration::from_secs(1));
+
+        let mut context = CompareContext {
+            report_time_diffs: true,
+            ..CompareContext::default()
+        };
+        let mut diffs = compare_dirs_with(left.path(), right.path(), &context).unwrap();
+        diffs.sort_by(|a, b| a.path.cmp(&b.path));
+        assert!(matches!(
+            diffs[0].diff_type,
+            DiffType::TimeDiffers { size: 4, .. }
+        ));
+        assert!(matches!(diffs[1].diff_type, DiffType::Different { .. }));
+
+        context.time_tolerance.window = Duration::from_secs(2);
+        let diffs = compare_dirs_with(left.path(), right.path(), &context).unwrap();
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].path, "copied.txt");
+        assert!(matches!(diffs[0].diff_type, DiffType::TimeDiffers { .. }));
+    }
+
+    #[test]
+    fn test_allocation_diff() {
+        let dir1 = tempdir().unwrap();
+        let dir2 = tempdir().unwrap();
+        File::create(dir1.path().join("disk.img"))
+            .unwrap()
+            .set_len(1 << 20)
+            .unwrap();
+        write_file(&dir2.path().join("disk.img"), &vec![0u8; 1 << 20]);
+
+        let diffs = super::compare_dirs(dir1.path(), dir2.path()).unwrap();
+        let sparse =
+            crate::sparse::is_sparse(&std::fs::metadata(dir1.path().join("disk.img")).unwrap());
+        if sparse {
+            assert_eq!(diffs.len(), 1);
+            assert!(matches!(
+                diffs[0].diff_type,
+                super::DiffType::AllocationDiffers { size, .. } if size == 1 << 20
+            ));
+        } else {
+            assert!(diffs.is_empty());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_large_sparse_and_dense_twins_compare_equal() {
+        use std::os::unix::fs::FileExt;
+        let dir1 = tempdir().unwrap();
+        let dir2 = tempdir().unwrap();
+        let len = crate::hash::SAMPLE_MIN_SIZE + (1 << 20);
+        let sparse = File::create(dir1.path().join("disk.img")).unwrap();
+        sparse.set_len(len).unwrap();
+        sparse.write_all_at(b"data", len / 2).unwrap();
+        if !is_sparse(&sparse.metadata().unwrap()) {
+            return;
+        }
+        let mut content = vec![0u8; len as usize];
+        content[len as usize / 2..][..4].copy_from_slice(b"data");
+        write_file(&dir2.path().join("disk.img"), &content);
+        File::options()
+            .write(true)
+            .open(dir2.path().join("disk.img"))
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .unwrap();
+
+        let diffs = super::compare_dirs(dir1.path(), dir2.path()).unwrap();
+        assert_eq!(diffs.len(), 1);
+        assert!(matches!(
+            diffs[0].diff_type,
+            DiffType::AllocationDiffers { .. }
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hardlink_grouping_diff() {
+        let dir1 = tempdir().unwrap();
+        let dir2 = tempdir().unwrap();
+        write_file(&dir1.path().join("a"), b"same");
+        std::fs::hard_link(dir1.path().join("a"), dir1.path().join("b")).unwrap();
+        write_file(&dir2.path().join("a"), b"same");
+        write_file(&dir2.path().join("b"), b"same");
+        // Match the mtimes so only the grouping differs.
+        for name in ["a", "b"] {
+            File::options()
+                .write(true)
+                .open(dir2.path().join(name))
+                .unwrap()
+                .set_modified(
+                    std::fs::metadata(dir1.path().join("a"))
+                        .unwrap()
+                        .modified()
+                        .unwrap(),
+                )
+                .unwrap();
+        }
+
+        let diffs = super::compare_dirs(dir1.path(), dir2.path()).unwrap();
+        assert_eq!(diffs.len(), 1);
+        match &diffs[0].diff_type {
+            super::DiffType::HardlinksDiffer {
+                left_group,
+                right_group,
+            } => {
+                assert_eq!(left_group, &["a", "b"]);
+                assert_eq!(right_group, &["a"]);
+            }
+            other => panic!("unexpected diff {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hardlink_diffs_cover_every_group() {
+        let dir1 = tempdir().unwrap();
+        let dir2 = tempdir().unwrap();
+        let link = |dir: &Path, groups: &[&[&str]]| {
+            for group in groups {
+                write_file(&dir.join(group[0]), b"same");
+                for path in &group[1..] {
+                    std::fs::hard_link(dir.join(group[0]), dir.join(path)).unwrap();
+                }
+            }
+        };
+        link(dir1.path(), &[&["a1", "a2"], &["b1", "b2"], &["c", "d"]]);
+        link(dir2.path(), &[&["a1", "c"], &["b1", "d"], &["a2"], &["b2"]]);
+        let mut left_files = FxHashMap::default();
+        let mut right_files = FxHashMap::default();
+        get_dir_files_with_ignore(dir1.path(), &mut left_files, &[]).unwrap();
+        get_dir_files_with_ignore(dir2.path(), &mut right_files, &[]).unwrap();
+
+        let diffs = hardlink_diffs(&left_files, &right_files, &FxHashSet::default());
+        let groups = |left: bool| {
+            let mut groups: Vec<Vec<String>> = diffs
+                .iter()
+                .map(|d| match &d.diff_type {
+                    DiffType::HardlinksDiffer {
+                        left_group,
+                        right_group,
+                    } => if left { left_group } else { right_group }.clone(),
+                    other => panic!("unexpected diff {:?}", other),
+                })
+                .filter(|group| group.len() > 1)
+                .collect();
+            groups.sort();
+            groups.dedup();
+            groups
+        };
+        assert_eq!(groups(true), [["a1", "a2"], ["b1", "b2"], ["c", "d"]]);
+        assert_eq!(groups(false), [["a1", "c"], ["b1", "d"]]);
+
+        // A path that already differs is not reported a second time.
+        let reported: FxHashSet<&str> = ["a1"].into_iter().collect();
+        let diffs = hardlink_diffs(&left_files, &right_files, &reported);
+        assert!(diffs.iter().all(|d| d.path != "a1"));
+        assert!(diffs.iter().any(|d| d.path == "a2"));
+    }
 }
@@ -0,0 +1,328 @@
+//! Duplicate file finder for folder-differ
+//!
+//! Files are grouped by size first, so only files that share a size with
+//! another file are hashed, and then by digest. Paths that are hardlinks to
+//! one inode, or reflinks sharing all their extents, count as a single copy:
+//! they take no extra space.
+
+use crate::hash::{HashAlgorithm, InodeHashes, hash_file_full};
+use crate::links::inode_key;
+use crate::sparse::physical_extents;
+use crate::sync::{Dedupe, DedupeMethod, FileMetadata, Side, SyncAction};
+use crate::{Result, get_dir_files_with_ignore};
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::fs::{File, Metadata};
+use std::path::Path;
+
+/// One path of a duplicate group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DupeFile {
+    pub side: Side,
+    pub path: String,
+    /// (device, inode), where the platform has them.
+    pub inode: Option<(u64, u64)>,
+    /// Where the data lies on disk, see [`physical_extents`].
+    pub extents: Option<Vec<(u64, u64, u64)>>,
+    /// Permissions, owner and times when the file was found.
+    pub metadata: FileMetadata,
+}
+
+impl DupeFile {
+    /// Whether both paths are one copy of the data: links to one inode, or
+    /// files on one device with the same extents.
+    pub fn shares_data(&self, other: &DupeFile) -> bool {
+        let same_device = self
+            .inode
+            .is_some_and(|(dev, _)| other.inode.is_some_and(|(other_dev, _)| dev == other_dev));
+        (self.inode.is_some() && self.inode == other.inode)
+            || (same_device && self.extents.is_some() && self.extents == other.extents)
+    }
+}
+
+/// Files with identical content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DupeGroup {
+    pub size: u64,
+    pub digest: Vec<u8>,
+    /// Sorted by side (left first), then path.
+    pub files: Vec<DupeFile>,
+}
+
+impl DupeGroup {
+    /// Number of separate copies of the data, see [`DupeFile::shares_data`].
+    pub fn copies(&self) -> usize {
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(i, file)| !self.files[..*i].iter().any(|f| f.shares_data(file)))
+            .count()
+    }
+
+    /// Bytes freed by keeping a single copy.
+    ///
+    /// An upper bound: files sharing only some of their extents count as
+    /// separate copies.
+    pub fn reclaimable(&self) -> u64 {
+        self.size * (self.copies() as u64 - 1)
+    }
+}
+
+fn distinct_copies(inodes: impl Iterator<Item = Option<(u64, u64)>>) -> usize {
+    let mut seen = FxHashSet::default();
+    inodes
+        .filter(|inode| inode.is_none_or(|key| seen.insert(key)))
+        .count()
+}
+
+/// Find groups of identical files in `left`, or across `left` and `right`.
+///
+/// Files smaller than `min_size` (and empty files) are ignored. Contents are
+/// hashed in full, never sampled. Groups are sorted by reclaimable bytes,
+/// largest first.
+pub fn find_dupes(
+    left: &Path,
+    right: Option<&Path>,
+    algorithm: HashAlgorithm,
+    min_size: u64,
+) -> Result<Vec<DupeGroup>> {
+    let mut left_files = FxHashMap::default();
+    let mut right_files = FxHashMap::default();
+    let (left_res, right_res) = rayon::join(
+        || get_dir_files_with_ignore(left, &mut left_files, &[]),
+        || match right {
+            Some(right) => get_dir_files_with_ignore(right, &mut right_files, &[]),
+            None => Ok(()),
+        },
+    );
+    left_res?;
+    right_res?;
+
+    let mut by_size: FxHashMap<u64, Vec<(Side, String, Metadata)>> = FxHashMap::default();
+    let tagged = |side: Side| move |(path, meta): (String, Metadata)| (side, path, meta);
+    for (side, path, meta) in left_files
+        .into_iter()
+        .map(tagged(Side::Left))
+        .chain(right_files.into_iter().map(tagged(Side::Right)))
+    {
+        if meta.len() > 0 && meta.len() >= min_size {
+            by_size
+                .entry(meta.len())
+                .or_default()
+                .push((side, path, meta));
+        }
+    }
+    let candidates: Vec<(Side, String, Metadata)> = by_size
+        .into_values()
+        .filter(|files| distinct_copies(files.iter().map(|(_, _, meta)| inode_key(meta))) > 1)
+        .flatten()
+        .collect();
+
+    let inode_hashes = InodeHashes::default();
+    let hashed = candidates
+        .into_par_iter()
+        .map(|(side, path, meta)| {
+            let root = match side {
+                Side::Left => left,
+                Side::Right => right.unwrap_or(left),
+            };
+            let digest =
+                inode_hashes.get_or_hash(&meta, || hash_file_full(&root.join(&path), algorithm))?;
+            let extents = physical_extents(&File::open(root.join(&path))?)?;
+            Ok((
+                (meta.len(), digest),
+                DupeFile {
+                    side,
+                    path,
+                    inode: inode_key(&meta),
+                    extents,
+                    metadata: FileMetadata::from_metadata(&meta),
+                },
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut by_content: FxHashMap<(u64, Vec<u8>), Vec<DupeFile>> = FxHashMap::default();
+    for (key, file) in hashed {
+        by_content.entry(key).or_default().push(file);
+    }
+    let mut groups: Vec<DupeGroup> = by_content
+        .into_iter()
+        .map(|((size, digest), mut files)| {
+            files.sort_by(|a, b| {
+                (a.side == Side::Right, &a.path).cmp(&(b.side == Side::Right, &b.path))
+            });
+            DupeGroup {
+                size,
+                digest,
+                files,
+            }
+        })
+        .filter(|group| group.copies() > 1)
+        .collect();
+    groups.sort_by(|a, b| {
+        b.reclaimable()
+            .cmp(&a.reclaimable())
+            .then_with(|| a.files[0].path.cmp(&b.files[0].path))
+    });
+    Ok(groups)
+}
+
+/// Total bytes freed by deduplicating every group.
+pub fn reclaimable_bytes(groups: &[DupeGroup]) -> u64 {
+    groups.iter().map(DupeGroup::reclaimable).sum()
+}
+
+/// Plan dedupe actions: in each group, every file that does not already share
+/// its data with the first file is replaced by a link to it.
+pub fn plan_dedupe(groups: &[DupeGroup], method: DedupeMethod) -> Vec<SyncAction> {
+    groups
+        .iter()
+        .flat_map(|group| {
+            let original = &group.files[0];
+            group.files[1..]
+                .iter()
+                .filter(|file| !file.shares_data(original))
+                .map(|file| {
+                    SyncAction::Dedupe(Dedupe {
+                        side: file.side,
+                        path: file.path.clone(),
+                        original_side: original.side,
+                        original: original.path.clone(),
+                        method,
+                        metadata: Some(file.metadata),
+                    })
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::{BackupStore, SyncLog, SyncOptions, execute_sync_actions, rollback};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_find_dupes_within_and_across_trees() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        let data = vec![42u8; 4096];
+        std::fs::write(left.path().join("a.bin"), &data).unwrap();
+        std::fs::write(left.path().join("b.bin"), &data).unwrap();
+        std::fs::write(right.path().join("c.bin"), &data).unwrap();
+        // Same size, different content.
+        std::fs::write(left.path().join("other.bin"), vec![7u8; 4096]).unwrap();
+        std::fs::write(left.path().join("small"), b"x").unwrap();
+        std::fs::write(right.path().join("small"), b"x").unwrap();
+
+        let groups = find_dupes(left.path(), None, HashAlgorithm::Blake3, 2).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(reclaimable_bytes(&groups), 4096);
+
+        let groups = find_dupes(left.path(), Some(right.path()), HashAlgorithm::Blake3, 2).unwrap();
+        let files: Vec<(Side, &str)> = groups[0]
+            .files
+            .iter()
+            .map(|f| (f.side, f.path.as_str()))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                (Side::Left, "a.bin"),
+                (Side::Left, "b.bin"),
+                (Side::Right, "c.bin")
+            ]
+        );
+        assert_eq!(reclaimable_bytes(&groups), 2 * 4096);
+    }
+
+    #[test]
+    fn test_reflinked_files_are_one_copy() {
+        let file = |path: &str, inode: u64, physical: u64| DupeFile {
+            side: Side::Left,
+            path: path.to_string(),
+            inode: Some((1, inode)),
+            extents: Some(vec![(0, physical, 4096)]),
+            metadata: FileMetadata {
+                mode: 0o644,
+                uid: 0,
+                gid: 0,
+                accessed: std::time::UNIX_EPOCH,
+                modified: std::time::UNIX_EPOCH,
+            },
+        };
+        let group = DupeGroup {
+            size: 4096,
+            digest: Vec::new(),
+            files: vec![file("a", 10, 500), file("b", 11, 500), file("c", 12, 900)],
+        };
+        assert_eq!(group.copies(), 2);
+        assert_eq!(group.reclaimable(), 4096);
+        let actions = plan_dedupe(std::slice::from_ref(&group), DedupeMethod::Reflink);
+        let paths: Vec<&str> = actions.iter().map(SyncAction::path).collect();
+        assert_eq!(paths, ["c"]);
+
+        // Same extents on another device are a different copy.
+        let mut other = file("d", 13, 500);
+        other.inode = Some((2, 13));
+        assert!(!group.files[0].shares_data(&other));
+
+        // With a filesystem that can reflink, the clone is not reported.
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("a.bin"), vec![42u8; 8192]).unwrap();
+        if crate::copy::reflink_file(&dir.path().join("a.bin"), &dir.path().join("b.bin")).is_ok() {
+            assert!(
+                find_dupes(dir.path(), None, HashAlgorithm::Blake3, 0)
+                    .unwrap()
+                    .is_empty()
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hardlink_dedupe_and_rollback() {
+        let dir = tempdir().unwrap();
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        std::fs::write(dir.path().join("a.bin"), b"same data").unwrap();
+        std::fs::write(dir.path().join("b.bin"), b"same data").unwrap();
+        let b_path = dir.path().join("b.bin");
+        std::fs::set_permissions(&b_path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let b_time = filetime::FileTime::from_unix_time(1_000_000, 5);
+        filetime::set_file_mtime(&b_path, b_time).unwrap();
+
+        let groups = find_dupes(dir.path(), None, HashAlgorithm::Blake3, 0).unwrap();
+        let actions = plan_dedupe(&groups, DedupeMethod::Hardlink);
+        assert_eq!(actions.len(), 1);
+        let mut log = SyncLog::default();
+        let report = execute_sync_actions(
+            &actions,
+            dir.path(),
+            dir.path(),
+            &BackupStore::in_tree("run1"),
+            &SyncOptions::default(),
+            &mut log,
+        );
+        assert!(report.is_success());
+        assert!(
+            find_dupes(dir.path(), None, HashAlgorithm::Blake3, 0)
+                .unwrap()
+                .is_empty()
+        );
+
+        rollback(&log, dir.path(), dir.path()).unwrap();
+        assert_eq!(
+            reclaimable_bytes(&find_dupes(dir.path(), None, HashAlgorithm::Blake3, 0).unwrap()),
+            9
+        );
+        assert_eq!(std::fs::read(&b_path).unwrap(), b"same data");
+        // The duplicate gets its own permissions and times back, not the
+        // original's.
+        let meta = std::fs::metadata(&b_path).unwrap();
+        assert_eq!(meta.mode() & 0o777, 0o600);
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&meta),
+            b_time
+        );
+    }
+}
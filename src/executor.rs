@@ -0,0 +1,262 @@
+//! Parallel execution of sync actions for folder-differ
+//!
+//! Actions run on the global rayon pool in ordered phases: the parent
+//! directories of every copy destination are created first (parents before
+//! children), then copies run in parallel, then files are unlinked from
+//! groups they no longer belong to, then copies that become hardlinks to an
+//! earlier copy and files relinked to their group, then deletes. Each
+//! destination device can be limited to a number of concurrent actions.
+
+use crate::Result;
+use crate::sync::{FailurePolicy, Side, SyncAction, SyncLog, SyncOptions, SyncOutcome, SyncReport};
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+
+/// A counting semaphore limiting concurrent actions on one device.
+struct Semaphore {
+    permits: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Self {
+            permits: Mutex::new(permits.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> SemaphoreGuard<'_> {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.released.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        SemaphoreGuard(self)
+    }
+}
+
+struct SemaphoreGuard<'a>(&'a Semaphore);
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.permits.lock().unwrap() += 1;
+        self.0.released.notify_one();
+    }
+}
+
+#[cfg(unix)]
+fn device_of(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).map(|m| m.dev()).unwrap_or(0)
+}
+
+#[cfg(not(unix))]
+fn device_of(_path: &Path) -> u64 {
+    0
+}
+
+fn is_copy(action: &SyncAction) -> bool {
+    matches!(
+        action,
+        SyncAction::CopyLeftToRight(_) | SyncAction::CopyRightToLeft(_)
+    )
+}
+
+fn is_delete(action: &SyncAction) -> bool {
+    matches!(
+        action,
+        SyncAction::DeleteLeft(_) | SyncAction::DeleteRight(_)
+    )
+}
+
+/// Execute `actions` in parallel, calling `perform` for each one.
+///
+/// `perform` gets a fresh log per action; the logs are appended to `log` in
+/// the original action order once all phases have finished. Under
+/// [`FailurePolicy::FailFast`], no new action starts after the first failure.
+/// An `Err` from `perform` (e.g. the journal could not be written) stops the
+/// run and is returned.
+pub fn execute_actions<F>(
+    actions: &[SyncAction],
+    left: &Path,
+    right: &Path,
+    options: &SyncOptions,
+    log: &mut SyncLog,
+    perform: F,
+) -> Result<SyncReport>
+where
+    F: Fn(&SyncAction, &mut SyncLog) -> Result<SyncOutcome> + Sync,
+{
+    let root = |side: Side| match side {
+        Side::Left => left,
+        Side::Right => right,
+    };
+
+    // Phase 1: destination directories, parents first.
+    let parents: BTreeSet<PathBuf> = actions
+        .iter()
+        .filter(|a| is_copy(a))
+        .filter_map(|a| {
+            let dst = root(a.target()?).join(a.path());
+            dst.parent().map(Path::to_path_buf)
+        })
+        .collect();
+    for dir in &parents {
+        // A failure here resurfaces as the copy's own outcome.
+        let _ = std::fs::create_dir_all(dir);
+    }
+
+    let semaphores: FxHashMap<u64, Semaphore> = match options.max_per_device {
+        Some(limit) => [left, right]
+            .iter()
+            .map(|r| (device_of(r), Semaphore::new(limit)))
+            .collect(),
+        None => FxHashMap::default(),
+    };
+    let devices = [
+        (Side::Left, device_of(left)),
+        (Side::Right, device_of(right)),
+    ];
+    let device_for = |action: &SyncAction| {
+        let side = action.target()?;
+        devices.iter().find(|(s, _)| *s == side).map(|(_, d)| *d)
+    };
+
+    let abort = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<(SyncOutcome, SyncLog)>>> = Mutex::new(vec![None; actions.len()]);
+    let first_error = Mutex::new(None);
+    let run_phase = |indices: Vec<usize>| {
+        indices.into_par_iter().for_each(|i| {
+            if abort.load(Ordering::SeqCst) {
+                return;
+            }
+            let action = &actions[i];
+            let _permit = device_for(action)
+                .and_then(|dev| semaphores.get(&dev))
+                .map(Semaphore::acquire);
+            let mut action_log = SyncLog::default();
+            match perform(action, &mut action_log) {
+                Ok(outcome) => {
+                    if matches!(outcome, SyncOutcome::Failed { .. })
+                        && options.failure_policy == FailurePolicy::FailFast
+                    {
+                        abort.store(true, Ordering::SeqCst);
+                    }
+                    results.lock().unwrap()[i] = Some((outcome, action_log));
+                }
+                Err(e) => {
+                    abort.store(true, Ordering::SeqCst);
+                    first_error.lock().unwrap().get_or_insert(e);
+                }
+            }
+        });
+    };
+
+    // Phase 2: copies (and no-op actions); phase 3: unlinking; phase 4:
+    // hardlinks to files copied in phase 2 or unlinked in phase 3; phase 5:
+    // deletes.
+    let is_link = |action: &SyncAction| match action {
+        SyncAction::Relink(relink) => relink.leader.is_some(),
+        _ => {
+            is_copy(action)
+                && action
+                    .target()
+                    .is_some_and(|side| options.link_leader(side.other(), action.path()).is_some())
+        }
+    };
+    let is_unlink = |action: &SyncAction| matches!(action, SyncAction::Relink(relink) if relink.leader.is_none());
+    let (deletes, others): (Vec<usize>, Vec<usize>) =
+        (0..actions.len()).partition(|&i| is_delete(&actions[i]));
+    let (links, others): (Vec<usize>, Vec<usize>) =
+        others.into_iter().partition(|&i| is_link(&actions[i]));
+    let (unlinks, others): (Vec<usize>, Vec<usize>) =
+        others.into_iter().partition(|&i| is_unlink(&actions[i]));
+    run_phase(others);
+    run_phase(unlinks);
+    run_phase(links);
+    run_phase(deletes);
+
+    if let Some(e) = first_error.into_inner().unwrap() {
+        return Err(e);
+    }
+    let mut report = SyncReport::default();
+    for (action, result) in actions.iter().zip(results.into_inner().unwrap()) {
+        match result {
+            Some((outcome, action_log)) => {
+                report.record(action, &outcome);
+                log.entries.extend(action_log.entries);
+            }
+            None => report.not_attempted += 1,
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_deletes_run_after_copies() {
+        let actions = vec![
+            SyncAction::DeleteRight("a".into()),
+            SyncAction::CopyLeftToRight("b".into()),
+            SyncAction::DeleteLeft("c".into()),
+            SyncAction::CopyRightToLeft("d".into()),
+        ];
+        let order = Mutex::new(Vec::new());
+        let mut log = SyncLog::default();
+        let report = execute_actions(
+            &actions,
+            Path::new("."),
+            Path::new("."),
+            &SyncOptions::default(),
+            &mut log,
+            |action, _| {
+                order.lock().unwrap().push(action.clone());
+                Ok(SyncOutcome::Success)
+            },
+        )
+        .unwrap();
+        assert_eq!(report.succeeded, 4);
+        let order = order.into_inner().unwrap();
+        let first_delete = order.iter().position(is_delete).unwrap();
+        assert!(order[..first_delete].iter().all(is_copy));
+    }
+
+    #[test]
+    fn test_per_device_limit() {
+        let actions: Vec<SyncAction> = (0..16)
+            .map(|i| SyncAction::CopyLeftToRight(format!("f{}", i)))
+            .collect();
+        let running = AtomicUsize::new(0);
+        let max_seen = AtomicUsize::new(0);
+        let options = SyncOptions {
+            max_per_device: Some(2),
+            ..SyncOptions::default()
+        };
+        let mut log = SyncLog::default();
+        execute_actions(
+            &actions,
+            Path::new("."),
+            Path::new("."),
+            &options,
+            &mut log,
+            |_, _| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_seen.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(std::time::Duration::from_millis(5));
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(SyncOutcome::Success)
+            },
+        )
+        .unwrap();
+        assert!(max_seen.load(Ordering::SeqCst) <= 2);
+    }
+}
@@ -2,55 +2,196 @@
 
 use crate::FolderDifferError;
 use crate::Result;
-use blake3;
+use crate::links::{inode_key, link_count};
+use crate::sparse::{data_segments, is_sparse};
 use memmap2::Mmap;
-use std::fs::File;
-use std::io::{BufReader, Read, Seek};
+use rustc_hash::FxHashMap;
+use sha2::Digest;
+use std::fs::{File, Metadata};
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::Path;
+use std::sync::Mutex;
 
-/// Hash a file, using sampling for large files.
+/// Files at least this large are hashed by sampling their head and tail.
+pub(crate) const SAMPLE_MIN_SIZE: u64 = 100 * 1024 * 1024; // 100MB
+const SAMPLE_SIZE: usize = 64 * 1024; // 64KB
+
+/// A streaming hash function used to fingerprint file contents.
+pub trait Hasher {
+    /// Feed the next chunk of data.
+    fn update(&mut self, data: &[u8]);
+    /// Consume the hasher and return the digest.
+    fn finalize(self: Box<Self>) -> Vec<u8>;
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        blake3::Hasher::finalize(&self).as_bytes().to_vec()
+    }
+}
+
+impl Hasher for sha2::Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        Digest::finalize(*self).to_vec()
+    }
+}
+
+impl Hasher for xxhash_rust::xxh3::Xxh3 {
+    fn update(&mut self, data: &[u8]) {
+        xxhash_rust::xxh3::Xxh3::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.digest128().to_be_bytes().to_vec()
+    }
+}
+
+/// The hash algorithms available for comparing file contents.
+///
+/// BLAKE3 is the default. XXH3 is faster but not cryptographic, which is fine
+/// for change detection; SHA-256 gives digests other tools can check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Blake3,
+    Sha256,
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    /// Parse `blake3`, `sha256` or `xxh3`.
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "sha256" | "sha-256" => Ok(HashAlgorithm::Sha256),
+            "xxh3" => Ok(HashAlgorithm::Xxh3),
+            _ => Err(FolderDifferError::Other(format!(
+                "Unknown hash algorithm: {} (expected blake3, sha256 or xxh3)",
+                name
+            ))),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Xxh3 => "xxh3",
+        }
+    }
+
+    /// A fresh hasher for this algorithm.
+    pub fn hasher(&self) -> Box<dyn Hasher> {
+        match self {
+            HashAlgorithm::Blake3 => Box::new(blake3::Hasher::new()),
+            HashAlgorithm::Sha256 => Box::new(sha2::Sha256::new()),
+            HashAlgorithm::Xxh3 => Box::new(xxhash_rust::xxh3::Xxh3::new()),
+        }
+    }
+}
+
+/// Hash a file with BLAKE3, using sampling for large files.
 pub fn hash_file(path: &Path) -> Result<Vec<u8>> {
+    hash_file_with(path, HashAlgorithm::Blake3)
+}
+
+/// Hash a file with `algorithm`, using sampling for large files.
+///
+/// Files over 100MB are sampled (first and last 64KB), sparse files skip
+/// their holes and other large files are memory-mapped.
+pub fn hash_file_with(path: &Path, algorithm: HashAlgorithm) -> Result<Vec<u8>> {
+    hash_file_impl(path, algorithm, true)
+}
+
+/// Hash the whole content of a file with `algorithm`, never sampling.
+///
+/// The digest matches what standard tools such as `sha256sum` or `b3sum`
+/// print for the file.
+pub fn hash_file_full(path: &Path, algorithm: HashAlgorithm) -> Result<Vec<u8>> {
+    hash_file_impl(path, algorithm, false)
+}
+
+fn hash_file_impl(path: &Path, algorithm: HashAlgorithm, sample: bool) -> Result<Vec<u8>> {
     let file = File::open(path)?;
     let metadata = file.metadata()?;
     let file_size = metadata.len();
-    if let Ok(sampled_hash) = hash_sampled_file(path) {
-        return Ok(sampled_hash);
+    let mut hasher = algorithm.hasher();
+    // Sparse files are never sampled: reading only their data is cheap, and
+    // images change in the middle as often as at the ends.
+    if file_size > 1024 * 1024 && is_sparse(&metadata) {
+        feed_sparse(file, hasher.as_mut())?;
+    } else if sample && file_size >= SAMPLE_MIN_SIZE {
+        feed_sampled(file, file_size, hasher.as_mut())?;
+    } else if file_size > 1024 * 1024 {
+        feed_mapped(&file, hasher.as_mut())?;
+    } else {
+        feed_stream(file, hasher.as_mut())?;
     }
-    if file_size < 1024 {
-        return hash_small_file(path);
+    Ok(hasher.finalize())
+}
+
+/// Hash everything `reader` yields with `algorithm`.
+///
+/// Used for content that is not a file on disk, such as an archive member.
+pub fn hash_reader(reader: impl Read, algorithm: HashAlgorithm) -> Result<Vec<u8>> {
+    let mut hasher = algorithm.hasher();
+    feed_stream(reader, hasher.as_mut())?;
+    Ok(hasher.finalize())
+}
+
+/// Digests of hardlinked files by inode, so each inode is hashed once per run.
+///
+/// Files with a single link go straight to [`hash_file`].
+#[derive(Debug, Default)]
+pub struct InodeHashes {
+    digests: Mutex<FxHashMap<(u64, u64), Vec<u8>>>,
+}
+
+impl InodeHashes {
+    /// Hash the file at `path`, whose metadata is `meta`.
+    pub fn hash(&self, path: &Path, meta: &Metadata) -> Result<Vec<u8>> {
+        self.get_or_hash(meta, || hash_file(path))
     }
-    if file_size > 1024 * 1024 {
-        return hash_large_file_blake3(path);
+
+    /// The digest of the file with metadata `meta`, computed by `hash` unless
+    /// another link to the same inode was already hashed.
+    pub fn get_or_hash(
+        &self,
+        meta: &Metadata,
+        hash: impl FnOnce() -> Result<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        let Some(key) = inode_key(meta).filter(|_| link_count(meta) > 1) else {
+            return hash();
+        };
+        if let Some(digest) = self.digests.lock().unwrap().get(&key) {
+            return Ok(digest.clone());
+        }
+        let digest = hash()?;
+        self.digests.lock().unwrap().insert(key, digest.clone());
+        Ok(digest)
     }
-    hash_medium_file_blake3(path)
 }
 
 /// Hash only the first and last 64KB of a large file (>100MB).
 pub fn hash_sampled_file(path: &Path) -> Result<Vec<u8>> {
-    const SAMPLE_SIZE: usize = 64 * 1024; // 64KB
-    const MIN_SIZE: u64 = 100 * 1024 * 1024; // 100MB
     let file = File::open(path)?;
-    let metadata = file.metadata()?;
-    let file_size = metadata.len();
-    if file_size < MIN_SIZE {
+    let file_size = file.metadata()?.len();
+    if file_size < SAMPLE_MIN_SIZE {
         return Err(FolderDifferError::Other(
             "File too small for sampled hash".to_string(),
         ));
     }
-    let mut hasher = blake3::Hasher::new();
-    let mut buf = vec![0u8; SAMPLE_SIZE];
-    // Hash first 64KB
-    let mut reader = BufReader::new(&file);
-    let n = reader.read(&mut buf)?;
-    hasher.update(&buf[..n]);
-    // Hash last 64KB
-    if file_size > SAMPLE_SIZE as u64 {
-        let mut file = File::open(path)?;
-        file.seek(std::io::SeekFrom::End(-(SAMPLE_SIZE as i64)))?;
-        let n = file.read(&mut buf)?;
-        hasher.update(&buf[..n]);
-    }
-    Ok(hasher.finalize().as_bytes().to_vec())
+    let mut hasher = HashAlgorithm::Blake3.hasher();
+    feed_sampled(file, file_size, hasher.as_mut())?;
+    Ok(hasher.finalize())
 }
 
 /// Hash a small file (<1KB).
@@ -64,9 +205,42 @@ pub fn hash_small_file(path: &Path) -> Result<Vec<u8>> {
 
 /// Hash a medium-sized file (1KB-1MB) using BLAKE3.
 pub fn hash_medium_file_blake3(path: &Path) -> Result<Vec<u8>> {
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
-    let mut hasher = blake3::Hasher::new();
+    let mut hasher = HashAlgorithm::Blake3.hasher();
+    feed_stream(File::open(path)?, hasher.as_mut())?;
+    Ok(hasher.finalize())
+}
+
+/// Hash a large file (>1MB) using BLAKE3 and memory mapping.
+pub fn hash_large_file_blake3(path: &Path) -> Result<Vec<u8>> {
+    let mut hasher = HashAlgorithm::Blake3.hasher();
+    feed_mapped(&File::open(path)?, hasher.as_mut())?;
+    Ok(hasher.finalize())
+}
+
+/// Hash a sparse file using BLAKE3, reading only its data segments.
+///
+/// Holes are fed to the hasher as zeros without touching the disk, so the
+/// result equals the hash of the same content stored densely.
+pub fn hash_sparse_file(path: &Path) -> Result<Vec<u8>> {
+    let mut hasher = HashAlgorithm::Blake3.hasher();
+    feed_sparse(File::open(path)?, hasher.as_mut())?;
+    Ok(hasher.finalize())
+}
+
+fn feed_sampled(mut file: File, file_size: u64, hasher: &mut dyn Hasher) -> Result<()> {
+    let mut buf = vec![0u8; SAMPLE_SIZE];
+    let n = read_block(&mut file, &mut buf)?;
+    hasher.update(&buf[..n]);
+    if file_size > SAMPLE_SIZE as u64 {
+        file.seek(SeekFrom::End(-(SAMPLE_SIZE as i64)))?;
+        let n = read_block(&mut file, &mut buf)?;
+        hasher.update(&buf[..n]);
+    }
+    Ok(())
+}
+
+fn feed_stream(reader: impl Read, hasher: &mut dyn Hasher) -> Result<()> {
+    let mut reader = BufReader::new(reader);
     let mut buffer = [0u8; 32768];
     loop {
         let n = reader.read(&mut buffer)?;
@@ -75,15 +249,87 @@ pub fn hash_medium_file_blake3(path: &Path) -> Result<Vec<u8>> {
         }
         hasher.update(&buffer[..n]);
     }
-    Ok(hasher.finalize().as_bytes().to_vec())
+    Ok(())
 }
 
-/// Hash a large file (>1MB) using BLAKE3 and memory mapping.
-pub fn hash_large_file_blake3(path: &Path) -> Result<Vec<u8>> {
-    let file = File::open(path)?;
-    let mmap = unsafe { Mmap::map(&file)? };
-    let hash = blake3::Hasher::new().update(&mmap).finalize();
-    Ok(hash.as_bytes().to_vec())
+fn feed_mapped(file: &File, hasher: &mut dyn Hasher) -> Result<()> {
+    let mmap = unsafe { Mmap::map(file)? };
+    hasher.update(&mmap);
+    Ok(())
+}
+
+fn feed_sparse(mut file: File, hasher: &mut dyn Hasher) -> Result<()> {
+    static ZEROS: [u8; 65536] = [0u8; 65536];
+    let len = file.metadata()?.len();
+    let mut buf = vec![0u8; 65536];
+    let mut pos = 0u64;
+    for (start, end) in data_segments(&file)?.into_iter().chain([(len, len)]) {
+        while pos < start {
+            let n = (start - pos).min(ZEROS.len() as u64) as usize;
+            hasher.update(&ZEROS[..n]);
+            pos += n as u64;
+        }
+        file.seek(SeekFrom::Start(start))?;
+        while pos < end {
+            let want = (end - pos).min(buf.len() as u64) as usize;
+            let n = file.read(&mut buf[..want])?;
+            if n == 0 {
+                // Truncated while hashing; treat the rest as a hole.
+                break;
+            }
+            hasher.update(&buf[..n]);
+            pos += n as u64;
+        }
+    }
+    Ok(())
+}
+
+/// Hash a file in fixed-size blocks, one BLAKE3 digest per block.
+///
+/// The last block may be shorter than `block_size`. Used by delta transfer to
+/// find the blocks of a destination file that can be kept.
+pub fn hash_blocks(path: &Path, block_size: usize) -> Result<Vec<[u8; 32]>> {
+    let mut reader = BufReader::with_capacity(block_size, File::open(path)?);
+    let mut buf = vec![0u8; block_size];
+    let mut hashes = Vec::new();
+    loop {
+        let n = read_block(&mut reader, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hashes.push(*blake3::hash(&buf[..n]).as_bytes());
+    }
+    Ok(hashes)
+}
+
+/// Fill `buf` from `reader`, stopping early only at end of file.
+pub(crate) fn read_block(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Lowercase hex encoding of a digest.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode a hex digest, as written by [`to_hex`] or checksum tools.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
 }
 
 /// Compare two small files for byte equality.
@@ -133,4 +379,81 @@ mod tests {
         assert!(compare_small_files(file1.path(), file2.path()).unwrap());
         assert!(!compare_small_files(file1.path(), file3.path()).unwrap());
     }
+
+    #[test]
+    fn test_sparse_hash_matches_dense() {
+        let mut sparse = NamedTempFile::new().unwrap();
+        sparse.write_all(b"head").unwrap();
+        sparse.as_file().set_len(3 << 20).unwrap();
+        let mut dense_content = vec![0u8; 3 << 20];
+        dense_content[..4].copy_from_slice(b"head");
+        let dense = write_tempfile(&dense_content);
+        assert_eq!(
+            hash_sparse_file(sparse.path()).unwrap(),
+            hash_large_file_blake3(dense.path()).unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_large_sparse_files_are_not_sampled() {
+        use std::os::unix::fs::FileExt;
+        let sparse = |middle: &[u8]| {
+            let file = NamedTempFile::new().unwrap();
+            let len = SAMPLE_MIN_SIZE + (1 << 20);
+            file.as_file().set_len(len).unwrap();
+            file.as_file().write_all_at(b"head", 0).unwrap();
+            file.as_file().write_all_at(middle, len / 2).unwrap();
+            file.as_file().write_all_at(b"tail", len - 4).unwrap();
+            file
+        };
+        let (a, b) = (sparse(b"one"), sparse(b"two"));
+        if !is_sparse(&a.as_file().metadata().unwrap()) {
+            return;
+        }
+        assert_ne!(
+            hash_file_with(a.path(), HashAlgorithm::Blake3).unwrap(),
+            hash_file_with(b.path(), HashAlgorithm::Blake3).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_hash_algorithms() {
+        let file = write_tempfile(b"abc");
+        let sha256 = hash_file_with(file.path(), HashAlgorithm::Sha256).unwrap();
+        assert_eq!(
+            sha256[..4],
+            [0xba, 0x78, 0x16, 0xbf],
+            "SHA-256 of abc starts with ba7816bf"
+        );
+        assert_eq!(
+            hash_file_with(file.path(), HashAlgorithm::Xxh3)
+                .unwrap()
+                .len(),
+            16
+        );
+        assert_eq!(
+            hash_file_with(file.path(), HashAlgorithm::Blake3).unwrap(),
+            hash_small_file(file.path()).unwrap()
+        );
+        assert_eq!(
+            HashAlgorithm::parse("SHA256").unwrap(),
+            HashAlgorithm::Sha256
+        );
+        assert!(HashAlgorithm::parse("md5").is_err());
+    }
+
+    #[test]
+    fn test_hash_blocks() {
+        let mut content = vec![0u8; 10_000];
+        let file1 = write_tempfile(&content);
+        content[5_000] = 1;
+        let file2 = write_tempfile(&content);
+        let blocks1 = hash_blocks(file1.path(), 4096).unwrap();
+        let blocks2 = hash_blocks(file2.path(), 4096).unwrap();
+        assert_eq!(blocks1.len(), 3);
+        assert_eq!(blocks1[0], blocks2[0]);
+        assert_ne!(blocks1[1], blocks2[1]);
+        assert_eq!(blocks1[2], blocks2[2]);
+    }
 }
@@ -0,0 +1,1066 @@
+//! Write-ahead sync journal and crash recovery for folder-differ
+//!
+//! Every sync action is recorded as an `intent` line before it touches the
+//! filesystem, and as a `done` or `failed` line once it has finished. The
+//! journal is a tab-separated text file with one record per line, so a run
+//! that was interrupted can be found on the next invocation and either
+//! resumed or rolled back.
+
+use crate::executor::execute_actions;
+use crate::sync::{BackupStore, Side, SyncAction, SyncLog, SyncLogEntry, SyncOptions};
+use crate::sync::{Dedupe, FileMetadata, block_backup_path, restore_backup, unshare_file};
+use crate::sync::{SyncOutcome, SyncReport, log_sync_action, perform_sync_action};
+use crate::{FolderDifferError, Result, STATE_DIR_NAME};
+use rustc_hash::FxHashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// File name of the journal inside the left tree's state directory.
+const JOURNAL_FILE_NAME: &str = "journal.tsv";
+
+/// A single journal line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JournalRecord {
+    RunStarted {
+        run_id: String,
+        timestamp: SystemTime,
+        left: PathBuf,
+        right: PathBuf,
+        backup_dir: Option<PathBuf>,
+    },
+    Intent {
+        run_id: String,
+        seq: u64,
+        action: SyncAction,
+        /// Whether the target path existed before the action ran.
+        existed: bool,
+        /// Where the previous version of the target is backed up.
+        backup: Option<PathBuf>,
+    },
+    Done {
+        run_id: String,
+        seq: u64,
+        details: String,
+        /// State of the target right after the action, used to detect later changes.
+        after: Option<FileState>,
+    },
+    Skipped {
+        run_id: String,
+        seq: u64,
+        reason: String,
+    },
+    Failed {
+        run_id: String,
+        seq: u64,
+        error: String,
+    },
+    RunFinished {
+        run_id: String,
+        timestamp: SystemTime,
+        status: String,
+    },
+    RolledBack {
+        run_id: String,
+        timestamp: SystemTime,
+    },
+}
+
+impl JournalRecord {
+    /// Serialize the record as a single journal line (without newline).
+    pub fn to_line(&self) -> String {
+        let fields: Vec<String> = match self {
+            JournalRecord::RunStarted {
+                run_id,
+                timestamp,
+                left,
+                right,
+                backup_dir,
+            } => vec![
+                "run".into(),
+                run_id.clone(),
+                format_time(*timestamp),
+                path_field(left),
+                path_field(right),
+                backup_dir.as_deref().map_or("-".into(), path_field),
+            ],
+            JournalRecord::Intent {
+                run_id,
+                seq,
+                action,
+                existed,
+                backup,
+            } => {
+                let mut fields = vec![
+                    "intent".into(),
+                    run_id.clone(),
+                    seq.to_string(),
+                    action.kind().into(),
+                    action.path().into(),
+                    if *existed { "1" } else { "0" }.into(),
+                    backup.as_deref().map_or("-".into(), path_field),
+                ];
+                fields.extend(action.source_field());
+                if let SyncAction::Dedupe(Dedupe {
+                    metadata: Some(metadata),
+                    ..
+                }) = action
+                {
+                    fields.push(metadata.to_field());
+                }
+                fields
+            }
+            JournalRecord::Done {
+                run_id,
+                seq,
+                details,
+                after,
+            } => vec![
+                "done".into(),
+                run_id.clone(),
+                seq.to_string(),
+                details.clone(),
+                after.map_or("-".into(), FileState::to_field),
+            ],
+            JournalRecord::Skipped {
+                run_id,
+                seq,
+                reason,
+            } => vec![
+                "skipped".into(),
+                run_id.clone(),
+                seq.to_string(),
+                reason.clone(),
+            ],
+            JournalRecord::Failed { run_id, seq, error } => {
+                vec![
+                    "failed".into(),
+                    run_id.clone(),
+                    seq.to_string(),
+                    error.clone(),
+                ]
+            }
+            JournalRecord::RunFinished {
+                run_id,
+                timestamp,
+                status,
+            } => vec![
+                "end".into(),
+                run_id.clone(),
+                format_time(*timestamp),
+                status.clone(),
+            ],
+            JournalRecord::RolledBack { run_id, timestamp } => {
+                vec!["rollback".into(), run_id.clone(), format_time(*timestamp)]
+            }
+        };
+        fields
+            .iter()
+            .map(|f| escape_field(f))
+            .collect::<Vec<_>>()
+            .join("\t")
+    }
+
+    /// Parse a journal line produced by [`JournalRecord::to_line`].
+    pub fn parse(line: &str) -> Result<Self> {
+        let fields: Vec<String> = line.split('\t').map(unescape_field).collect();
+        let bad = || FolderDifferError::Other(format!("Malformed journal line: {:?}", line));
+        let field = |i: usize| fields.get(i).cloned().ok_or_else(bad);
+        let seq = |i: usize| field(i)?.parse::<u64>().map_err(|_| bad());
+        let time = |i: usize| parse_time(&field(i)?).ok_or_else(bad);
+        let opt_path = |i: usize| field(i).map(|f| (f != "-").then(|| PathBuf::from(f)));
+        Ok(match fields[0].as_str() {
+            "run" => JournalRecord::RunStarted {
+                run_id: field(1)?,
+                timestamp: time(2)?,
+                left: PathBuf::from(field(3)?),
+                right: PathBuf::from(field(4)?),
+                backup_dir: opt_path(5)?,
+            },
+            "intent" => JournalRecord::Intent {
+                run_id: field(1)?,
+                seq: seq(2)?,
+                action: match SyncAction::from_journal(
+                    &field(3)?,
+                    field(4)?,
+                    fields.get(7).map(String::as_str),
+                )
+                .ok_or_else(bad)?
+                {
+                    SyncAction::Dedupe(dedupe) => SyncAction::Dedupe(Dedupe {
+                        metadata: match fields.get(8) {
+                            Some(f) => Some(FileMetadata::parse(f).ok_or_else(bad)?),
+                            None => None,
+                        },
+                        ..dedupe
+                    }),
+                    action => action,
+                },
+                existed: field(5)? == "1",
+                backup: opt_path(6)?,
+            },
+            "done" => JournalRecord::Done {
+                run_id: field(1)?,
+                seq: seq(2)?,
+                details: field(3)?,
+                after: match fields.get(4) {
+                    Some(f) if f != "-" => Some(FileState::parse(f).ok_or_else(bad)?),
+                    _ => None,
+                },
+            },
+            "skipped" => JournalRecord::Skipped {
+                run_id: field(1)?,
+                seq: seq(2)?,
+                reason: field(3)?,
+            },
+            "failed" => JournalRecord::Failed {
+                run_id: field(1)?,
+                seq: seq(2)?,
+                error: field(3)?,
+            },
+            "end" => JournalRecord::RunFinished {
+                run_id: field(1)?,
+                timestamp: time(2)?,
+                status: field(3)?,
+            },
+            "rollback" => JournalRecord::RolledBack {
+                run_id: field(1)?,
+                timestamp: time(2)?,
+            },
+            _ => return Err(bad()),
+        })
+    }
+}
+
+/// Size and modification time of a file, as recorded after a sync action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileState {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl FileState {
+    /// Current state of `path`, or `None` if it does not exist.
+    pub fn of(path: &Path) -> Option<Self> {
+        let meta = std::fs::symlink_metadata(path).ok()?;
+        Some(Self {
+            size: meta.len(),
+            modified: meta.modified().ok()?,
+        })
+    }
+
+    fn to_field(self) -> String {
+        format!("{}@{}", self.size, format_time(self.modified))
+    }
+
+    fn parse(field: &str) -> Option<Self> {
+        let (size, modified) = field.split_once('@')?;
+        Some(Self {
+            size: size.parse().ok()?,
+            modified: parse_time(modified)?,
+        })
+    }
+}
+
+fn path_field(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+pub(crate) fn format_time(time: SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{}.{:09}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_nanos()
+    )
+}
+
+pub(crate) fn parse_time(field: &str) -> Option<SystemTime> {
+    let (secs, nanos) = field.split_once('.')?;
+    let since_epoch = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+    Some(SystemTime::UNIX_EPOCH + since_epoch)
+}
+
+pub(crate) fn escape_field(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub(crate) fn unescape_field(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// An append-only journal file.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Default journal location for a sync whose left tree is `left`.
+    pub fn path_for(left: &Path) -> PathBuf {
+        left.join(STATE_DIR_NAME).join(JOURNAL_FILE_NAME)
+    }
+
+    /// Open (creating if needed) a journal for appending.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Path of the journal file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append a record and flush it to stable storage.
+    pub fn append(&mut self, record: &JournalRecord) -> Result<()> {
+        writeln!(self.file, "{}", record.to_line())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Read all records from a journal file. A missing journal reads as empty.
+///
+/// A torn final line (from a crash mid-write) is ignored.
+pub fn read_journal(path: &Path) -> Result<Vec<JournalRecord>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let lines: Vec<String> = BufReader::new(file)
+        .lines()
+        .collect::<std::io::Result<_>>()?;
+    let mut records = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        match JournalRecord::parse(line) {
+            Ok(record) => records.push(record),
+            Err(_) if i + 1 == lines.len() => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(records)
+}
+
+/// How a journaled action ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ActionOutcome {
+    Done(String),
+    Skipped(String),
+    Failed(String),
+}
+
+/// One action of a journaled run, with its outcome if it finished.
+#[derive(Debug, Clone)]
+pub struct JournalAction {
+    pub seq: u64,
+    pub action: SyncAction,
+    pub existed: bool,
+    pub backup: Option<PathBuf>,
+    pub outcome: Option<ActionOutcome>,
+    /// State of the target after the action completed (`None` if absent or unknown).
+    pub after: Option<FileState>,
+}
+
+/// A sync run reconstructed from the journal.
+#[derive(Debug, Clone)]
+pub struct JournalRun {
+    pub run_id: String,
+    pub started: SystemTime,
+    pub left: PathBuf,
+    pub right: PathBuf,
+    pub backup_dir: Option<PathBuf>,
+    pub actions: Vec<JournalAction>,
+    /// Final status, if the run finished.
+    pub finished: Option<String>,
+    pub rolled_back: bool,
+}
+
+impl JournalRun {
+    /// True if the run neither finished nor was rolled back.
+    pub fn is_interrupted(&self) -> bool {
+        self.finished.is_none() && !self.rolled_back
+    }
+
+    /// Human-readable status: `complete`, `failed`, `aborted`, `recovered`,
+    /// `interrupted` or `rolled back`.
+    pub fn status(&self) -> &str {
+        if self.rolled_back {
+            "rolled back"
+        } else {
+            self.finished.as_deref().unwrap_or("interrupted")
+        }
+    }
+
+    /// Number of completed actions per action kind, in first-seen order.
+    pub fn action_counts(&self) -> Vec<(&'static str, usize)> {
+        let mut counts: Vec<(&'static str, usize)> = Vec::new();
+        for action in &self.actions {
+            if !matches!(action.outcome, Some(ActionOutcome::Done(_))) {
+                continue;
+            }
+            let kind = action.action.kind();
+            match counts.iter_mut().find(|(k, _)| *k == kind) {
+                Some((_, n)) => *n += 1,
+                None => counts.push((kind, 1)),
+            }
+        }
+        counts
+    }
+
+    /// Number of actions that failed or never finished.
+    pub fn incomplete_count(&self) -> usize {
+        self.actions
+            .iter()
+            .filter(|a| matches!(a.outcome, None | Some(ActionOutcome::Failed(_))))
+            .count()
+    }
+
+    /// Root of the tree on one side of the run.
+    pub fn root(&self, side: Side) -> &Path {
+        match side {
+            Side::Left => &self.left,
+            Side::Right => &self.right,
+        }
+    }
+
+    /// The completed actions of this run as a [`SyncLog`].
+    pub fn to_sync_log(&self) -> SyncLog {
+        let mut log = SyncLog::default();
+        for action in &self.actions {
+            if let Some(ActionOutcome::Done(details)) = &action.outcome {
+                log.entries.push(SyncLogEntry {
+                    action: action.action.clone(),
+                    timestamp: self.started,
+                    details: details.clone(),
+                    backup: action.backup.clone(),
+                    outcome: SyncOutcome::Success,
+                });
+            }
+        }
+        log
+    }
+}
+
+/// Group journal records into runs, in the order the runs were started.
+pub fn load_runs(path: &Path) -> Result<Vec<JournalRun>> {
+    let mut runs: Vec<JournalRun> = Vec::new();
+    for record in read_journal(path)? {
+        if let JournalRecord::RunStarted {
+            run_id,
+            timestamp,
+            left,
+            right,
+            backup_dir,
+        } = record
+        {
+            runs.push(JournalRun {
+                run_id,
+                started: timestamp,
+                left,
+                right,
+                backup_dir,
+                actions: Vec::new(),
+                finished: None,
+                rolled_back: false,
+            });
+            continue;
+        }
+        let run_id = match &record {
+            JournalRecord::Intent { run_id, .. }
+            | JournalRecord::Done { run_id, .. }
+            | JournalRecord::Skipped { run_id, .. }
+            | JournalRecord::Failed { run_id, .. }
+            | JournalRecord::RunFinished { run_id, .. }
+            | JournalRecord::RolledBack { run_id, .. } => run_id,
+            JournalRecord::RunStarted { .. } => unreachable!(),
+        };
+        let Some(run) = runs.iter_mut().rev().find(|r| &r.run_id == run_id) else {
+            continue;
+        };
+        match record {
+            JournalRecord::Intent {
+                seq,
+                action,
+                existed,
+                backup,
+                ..
+            } => run.actions.push(JournalAction {
+                seq,
+                action,
+                existed,
+                backup,
+                outcome: None,
+                after: None,
+            }),
+            JournalRecord::Done {
+                seq,
+                details,
+                after,
+                ..
+            } => {
+                set_outcome(run, seq, ActionOutcome::Done(details));
+                if let Some(action) = run.actions.iter_mut().find(|a| a.seq == seq) {
+                    action.after = after;
+                }
+            }
+            JournalRecord::Skipped { seq, reason, .. } => {
+                set_outcome(run, seq, ActionOutcome::Skipped(reason))
+            }
+            JournalRecord::Failed { seq, error, .. } => {
+                set_outcome(run, seq, ActionOutcome::Failed(error))
+            }
+            JournalRecord::RunFinished { status, .. } => run.finished = Some(status),
+            JournalRecord::RolledBack { .. } => run.rolled_back = true,
+            JournalRecord::RunStarted { .. } => unreachable!(),
+        }
+    }
+    Ok(runs)
+}
+
+fn set_outcome(run: &mut JournalRun, seq: u64, outcome: ActionOutcome) {
+    if let Some(action) = run.actions.iter_mut().find(|a| a.seq == seq) {
+        action.outcome = Some(outcome);
+    }
+}
+
+/// Performs the actions of one sync run, journaling each before and after.
+///
+/// Actions may be performed from several threads at once; journal appends
+/// are serialized and sequence numbers handed out atomically.
+pub struct JournaledSync<'a> {
+    journal: Mutex<&'a mut Journal>,
+    store: &'a BackupStore,
+    options: &'a SyncOptions,
+    left: &'a Path,
+    right: &'a Path,
+    next_seq: AtomicU64,
+}
+
+impl<'a> JournaledSync<'a> {
+    /// Record the start of a run and return a handle for performing its actions.
+    pub fn begin(
+        journal: &'a mut Journal,
+        store: &'a BackupStore,
+        options: &'a SyncOptions,
+        left: &'a Path,
+        right: &'a Path,
+    ) -> Result<Self> {
+        journal.append(&JournalRecord::RunStarted {
+            run_id: store.run_id().to_string(),
+            timestamp: SystemTime::now(),
+            left: absolute(left),
+            right: absolute(right),
+            backup_dir: store.external_dir().map(absolute),
+        })?;
+        Ok(Self {
+            journal: Mutex::new(journal),
+            store,
+            options,
+            left,
+            right,
+            next_seq: AtomicU64::new(0),
+        })
+    }
+
+    /// Journal the intent, perform the action, then journal its outcome.
+    pub fn perform(&self, action: &SyncAction, log: &mut SyncLog) -> Result<SyncOutcome> {
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        let run_id = self.store.run_id().to_string();
+        let (existed, backup) = match action.target() {
+            Some(side) => {
+                let root = match side {
+                    Side::Left => self.left,
+                    Side::Right => self.right,
+                };
+                let existed = root.join(action.path()).exists();
+                // A dedupe keeps no backup; its content lives on in the original.
+                let backup = (existed && !matches!(action, SyncAction::Dedupe(_)))
+                    .then(|| absolute(&self.store.backup_path(root, side, action.path())));
+                (existed, backup)
+            }
+            None => (false, None),
+        };
+        self.append(&JournalRecord::Intent {
+            run_id: run_id.clone(),
+            seq,
+            action: action.clone(),
+            existed,
+            backup,
+        })?;
+        let outcome =
+            perform_sync_action(action, self.left, self.right, self.store, self.options, log);
+        let record = match &outcome {
+            SyncOutcome::Success => {
+                let details = log
+                    .entries
+                    .last()
+                    .map(|e| e.details.clone())
+                    .unwrap_or_default();
+                let after = action.target().and_then(|side| {
+                    let root = match side {
+                        Side::Left => self.left,
+                        Side::Right => self.right,
+                    };
+                    FileState::of(&root.join(action.path()))
+                });
+                JournalRecord::Done {
+                    run_id,
+                    seq,
+                    details,
+                    after,
+                }
+            }
+            SyncOutcome::Skipped(reason) => JournalRecord::Skipped {
+                run_id,
+                seq,
+                reason: reason.clone(),
+            },
+            SyncOutcome::Failed { message, .. } => JournalRecord::Failed {
+                run_id,
+                seq,
+                error: message.clone(),
+            },
+        };
+        self.append(&record)?;
+        Ok(outcome)
+    }
+
+    fn append(&self, record: &JournalRecord) -> Result<()> {
+        self.journal.lock().unwrap().append(record)
+    }
+
+    /// Perform `actions` in parallel according to the run's failure policy.
+    ///
+    /// Only journal I/O errors are returned as `Err`; action failures end up
+    /// in the report.
+    pub fn execute(&self, actions: &[SyncAction], log: &mut SyncLog) -> Result<SyncReport> {
+        execute_actions(
+            actions,
+            self.left,
+            self.right,
+            self.options,
+            log,
+            |action, log| self.perform(action, log),
+        )
+    }
+
+    /// Record that the run ended: `complete`, `failed` (some actions failed)
+    /// or `aborted` (stopped early under fail-fast).
+    pub fn finish(self, report: &SyncReport) -> Result<()> {
+        let status = if report.not_attempted > 0 {
+            "aborted"
+        } else if !report.is_success() {
+            "failed"
+        } else {
+            "complete"
+        };
+        self.append(&JournalRecord::RunFinished {
+            run_id: self.store.run_id().to_string(),
+            timestamp: SystemTime::now(),
+            status: status.to_string(),
+        })
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Undo the filesystem effects of one journaled action.
+///
+/// Works for completed actions as well as ones interrupted part-way: the
+/// journal records whether the target existed and where its backup goes.
+fn undo_action(run: &JournalRun, entry: &JournalAction) -> Result<()> {
+    let Some(side) = entry.action.target() else {
+        return Ok(());
+    };
+    let target = run.root(side).join(entry.action.path());
+    if let SyncAction::Dedupe(dedupe) = &entry.action {
+        return unshare_file(&target, dedupe.metadata.as_ref());
+    }
+    match &entry.backup {
+        Some(backup) if backup.exists() || block_backup_path(backup).exists() => {
+            restore_backup(backup, &target)?
+        }
+        // The target existed but was never moved aside, so it is untouched.
+        _ if entry.existed => {}
+        _ => {
+            if matches!(
+                entry.action,
+                SyncAction::CopyLeftToRight(_) | SyncAction::CopyRightToLeft(_)
+            ) {
+                match std::fs::remove_file(&target) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Roll back every action of a run, newest first, and record the rollback.
+pub fn rollback_run(run: &JournalRun, journal: &mut Journal) -> Result<SyncLog> {
+    rollback_runs(&[run], journal)
+}
+
+/// Roll back several runs, given oldest first (journal order).
+///
+/// Runs are undone newest first.
+/// Refuses (without touching anything) if any file written or deleted by
+/// these runs has changed since, e.g. because it was edited or because a
+/// later run that is not being rolled back touched it.
+pub fn rollback_runs(runs: &[&JournalRun], journal: &mut Journal) -> Result<SyncLog> {
+    let changed = changed_since(runs);
+    if !changed.is_empty() {
+        return Err(FolderDifferError::Other(format!(
+            "Refusing to roll back: {} file(s) changed since the sync: {}",
+            changed.len(),
+            changed
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )));
+    }
+    let mut log = SyncLog::default();
+    for run in runs.iter().rev() {
+        for entry in run.actions.iter().rev() {
+            if matches!(entry.outcome, Some(ActionOutcome::Skipped(_))) {
+                continue;
+            }
+            undo_action(run, entry)?;
+            let details = format!(
+                "Rolled back {} {} (run {})",
+                entry.action.kind(),
+                entry.action.path(),
+                run.run_id
+            );
+            log_sync_action(
+                &mut log,
+                &entry.action,
+                &details,
+                entry.backup.clone(),
+                SyncOutcome::Success,
+            );
+        }
+        journal.append(&JournalRecord::RolledBack {
+            run_id: run.run_id.clone(),
+            timestamp: SystemTime::now(),
+        })?;
+    }
+    Ok(log)
+}
+
+/// Target paths whose current state differs from what the newest completed
+/// action touching them left behind. `runs` must be in journal order.
+pub fn changed_since(runs: &[&JournalRun]) -> Vec<PathBuf> {
+    let mut expected: FxHashMap<PathBuf, ((usize, u64), Option<FileState>)> = FxHashMap::default();
+    for (run_index, run) in runs.iter().enumerate() {
+        for entry in &run.actions {
+            if !matches!(entry.outcome, Some(ActionOutcome::Done(_))) {
+                continue;
+            }
+            let Some(side) = entry.action.target() else {
+                continue;
+            };
+            let order = (run_index, entry.seq);
+            let slot = expected
+                .entry(run.root(side).join(entry.action.path()))
+                .or_insert((order, entry.after));
+            if slot.0 < order {
+                *slot = (order, entry.after);
+            }
+        }
+    }
+    let mut changed: Vec<PathBuf> = expected
+        .into_iter()
+        .filter(|(path, (_, after))| FileState::of(path) != *after)
+        .map(|(path, _)| path)
+        .collect();
+    changed.sort();
+    changed
+}
+
+/// Runs selected for rollback by `--rollback-since`: the given run and every
+/// later one that has not been rolled back yet.
+pub fn runs_since<'r>(runs: &'r [JournalRun], run_id: &str) -> Option<Vec<&'r JournalRun>> {
+    let start = runs.iter().position(|r| r.run_id == run_id)?;
+    Some(runs[start..].iter().filter(|r| !r.rolled_back).collect())
+}
+
+/// Clean up after an interrupted run so that a new sync can resume the work.
+///
+/// Actions that did not complete are undone; completed actions are kept.
+pub fn recover_run(run: &JournalRun, journal: &mut Journal) -> Result<()> {
+    for entry in run.actions.iter().rev() {
+        if matches!(entry.outcome, None | Some(ActionOutcome::Failed(_))) {
+            undo_action(run, entry)?;
+        }
+    }
+    journal.append(&JournalRecord::RunFinished {
+        run_id: run.run_id.clone(),
+        timestamp: SystemTime::now(),
+        status: "recovered".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_record_round_trip() {
+        let records = [
+            JournalRecord::RunStarted {
+                run_id: "r1".into(),
+                timestamp: SystemTime::UNIX_EPOCH + Duration::new(12, 34),
+                left: PathBuf::from("/a/left"),
+                right: PathBuf::from("/a/right"),
+                backup_dir: None,
+            },
+            JournalRecord::Intent {
+                run_id: "r1".into(),
+                seq: 0,
+                action: SyncAction::CopyLeftToRight("dir/we\tird\nname.txt".into()),
+                existed: true,
+                backup: Some(PathBuf::from("/b/back\\up")),
+            },
+            JournalRecord::Intent {
+                run_id: "r1".into(),
+                seq: 1,
+                action: SyncAction::Dedupe(crate::sync::Dedupe {
+                    side: Side::Right,
+                    path: "copy.iso".into(),
+                    original_side: Side::Left,
+                    original: "disk:1.iso".into(),
+                    method: crate::sync::DedupeMethod::Hardlink,
+                    metadata: Some(FileMetadata {
+                        mode: 0o4755,
+                        uid: 1000,
+                        gid: 100,
+                        accessed: SystemTime::UNIX_EPOCH + Duration::new(7, 8),
+                        modified: SystemTime::UNIX_EPOCH + Duration::new(9, 10),
+                    }),
+                }),
+                existed: true,
+                backup: None,
+            },
+            JournalRecord::Intent {
+                run_id: "r1".into(),
+                seq: 2,
+                action: SyncAction::Relink(crate::sync::Relink {
+                    side: Side::Right,
+                    path: "b".into(),
+                    leader: Some("a".into()),
+                }),
+                existed: true,
+                backup: Some(PathBuf::from("/b/b")),
+            },
+            JournalRecord::Intent {
+                run_id: "r1".into(),
+                seq: 3,
+                action: SyncAction::Relink(crate::sync::Relink {
+                    side: Side::Left,
+                    path: "c".into(),
+                    leader: None,
+                }),
+                existed: true,
+                backup: Some(PathBuf::from("/b/c")),
+            },
+            JournalRecord::Failed {
+                run_id: "r1".into(),
+                seq: 0,
+                error: "disk full".into(),
+            },
+            JournalRecord::Done {
+                run_id: "r1".into(),
+                seq: 1,
+                details: "Copied".into(),
+                after: Some(FileState {
+                    size: 3,
+                    modified: SystemTime::UNIX_EPOCH + Duration::new(5, 6),
+                }),
+            },
+        ];
+        for record in &records {
+            let line = record.to_line();
+            assert!(!line.contains('\n'));
+            assert_eq!(&JournalRecord::parse(&line).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn test_journaled_sync_and_rollback() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        fs::write(left.path().join("a.txt"), b"new").unwrap();
+        fs::write(right.path().join("a.txt"), b"old").unwrap();
+        let journal_path = Journal::path_for(left.path());
+        let store = BackupStore::in_tree("r1");
+        let options = SyncOptions::default();
+        let mut journal = Journal::open(&journal_path).unwrap();
+        let mut log = SyncLog::default();
+
+        let run = JournaledSync::begin(&mut journal, &store, &options, left.path(), right.path())
+            .unwrap();
+        let report = run
+            .execute(&[SyncAction::CopyLeftToRight("a.txt".into())], &mut log)
+            .unwrap();
+        run.finish(&report).unwrap();
+        assert_eq!(fs::read(right.path().join("a.txt")).unwrap(), b"new");
+
+        let runs = load_runs(&journal_path).unwrap();
+        assert_eq!(runs.len(), 1);
+        assert!(!runs[0].is_interrupted());
+        assert_eq!(runs[0].to_sync_log().entries.len(), 1);
+
+        rollback_run(&runs[0], &mut journal).unwrap();
+        assert_eq!(fs::read(right.path().join("a.txt")).unwrap(), b"old");
+        assert!(load_runs(&journal_path).unwrap()[0].rolled_back);
+    }
+
+    #[test]
+    fn test_recover_interrupted_copy() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        let journal_path = Journal::path_for(left.path());
+        let mut journal = Journal::open(&journal_path).unwrap();
+        journal
+            .append(&JournalRecord::RunStarted {
+                run_id: "r1".into(),
+                timestamp: SystemTime::now(),
+                left: left.path().to_path_buf(),
+                right: right.path().to_path_buf(),
+                backup_dir: None,
+            })
+            .unwrap();
+        journal
+            .append(&JournalRecord::Intent {
+                run_id: "r1".into(),
+                seq: 0,
+                action: SyncAction::CopyLeftToRight("a.txt".into()),
+                existed: false,
+                backup: None,
+            })
+            .unwrap();
+        // Simulate a crash part-way through the copy.
+        fs::write(right.path().join("a.txt"), b"partial").unwrap();
+
+        let runs = load_runs(&journal_path).unwrap();
+        assert!(runs[0].is_interrupted());
+        recover_run(&runs[0], &mut journal).unwrap();
+        assert!(!right.path().join("a.txt").exists());
+        assert!(!load_runs(&journal_path).unwrap()[0].is_interrupted());
+    }
+
+    fn sync_one(left: &Path, right: &Path, run_id: &str, action: SyncAction) {
+        let store = BackupStore::in_tree(run_id);
+        let options = SyncOptions::default();
+        let mut journal = Journal::open(&Journal::path_for(left)).unwrap();
+        let mut log = SyncLog::default();
+        let run = JournaledSync::begin(&mut journal, &store, &options, left, right).unwrap();
+        let report = run.execute(&[action], &mut log).unwrap();
+        run.finish(&report).unwrap();
+    }
+
+    #[test]
+    fn test_rollback_refuses_changed_files() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        fs::write(left.path().join("a.txt"), b"new").unwrap();
+        sync_one(
+            left.path(),
+            right.path(),
+            "r1",
+            SyncAction::CopyLeftToRight("a.txt".into()),
+        );
+        fs::write(right.path().join("a.txt"), b"edited afterwards").unwrap();
+
+        let journal_path = Journal::path_for(left.path());
+        let runs = load_runs(&journal_path).unwrap();
+        let mut journal = Journal::open(&journal_path).unwrap();
+        assert!(rollback_run(&runs[0], &mut journal).is_err());
+        assert_eq!(
+            fs::read(right.path().join("a.txt")).unwrap(),
+            b"edited afterwards"
+        );
+    }
+
+    #[test]
+    fn test_rollback_since_undoes_later_runs() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        fs::write(left.path().join("a.txt"), b"one").unwrap();
+        sync_one(
+            left.path(),
+            right.path(),
+            "r1",
+            SyncAction::CopyLeftToRight("a.txt".into()),
+        );
+        fs::write(left.path().join("a.txt"), b"two!").unwrap();
+        sync_one(
+            left.path(),
+            right.path(),
+            "r2",
+            SyncAction::CopyLeftToRight("a.txt".into()),
+        );
+
+        let journal_path = Journal::path_for(left.path());
+        let runs = load_runs(&journal_path).unwrap();
+        assert_eq!(runs[1].action_counts(), vec![("CopyLeftToRight", 1)]);
+        // The first run alone can't be undone: the second one overwrote its file.
+        assert!(!changed_since(&[&runs[0]]).is_empty());
+
+        let selected = runs_since(&runs, "r1").unwrap();
+        assert_eq!(selected.len(), 2);
+        let mut journal = Journal::open(&journal_path).unwrap();
+        rollback_runs(&selected, &mut journal).unwrap();
+        assert!(!right.path().join("a.txt").exists());
+        let runs = load_runs(&journal_path).unwrap();
+        assert!(runs.iter().all(|r| r.status() == "rolled back"));
+    }
+}
@@ -2,10 +2,30 @@
 //!
 //! This crate provides modules for directory diffing, file hashing, synchronization actions, and progress reporting.
 
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod binary_diff;
+pub mod cache;
+pub mod content;
+pub mod copy;
 pub mod diff;
+pub mod dupes;
+pub mod executor;
 pub mod hash;
+pub mod journal;
+pub mod links;
+pub mod manifest;
+pub mod normalize;
 pub mod progress;
+pub mod replicas;
+pub mod snapshot;
+pub mod source;
+pub mod sparse;
 pub mod sync;
+pub mod text_diff;
+pub mod three_way;
+#[cfg(all(feature = "watch", target_os = "linux"))]
+pub mod watch;
 
 use rustc_hash::FxHashMap;
 use std::fs::Metadata;
@@ -25,6 +45,43 @@ pub enum FolderDifferError {
 
 pub type Result<T> = std::result::Result<T, FolderDifferError>;
 
+/// Name of the per-tree directory holding folder-differ state (backups, logs).
+///
+/// Directory walkers skip it so that state never shows up as a difference.
+pub const STATE_DIR_NAME: &str = ".folder-differ";
+
+/// The relative path of `name` inside the relative directory `dir` (`""` for
+/// the root), with `/` as separator.
+pub(crate) fn join_rel(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Parse a byte size such as `512`, `64K`, `10M` or `1G` (binary multiples).
+pub fn parse_size(spec: &str) -> Result<u64> {
+    let spec = spec.trim();
+    let (digits, multiplier) = match spec.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let multiplier = match c.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                _ => return Err(FolderDifferError::Other(format!("Invalid size: {}", spec))),
+            };
+            (&spec[..i], multiplier)
+        }
+        _ => (spec, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| FolderDifferError::Other(format!("Invalid size: {}", spec)))
+}
+
 /// Utility function for directory walking with ignore patterns.
 pub fn get_dir_files_with_ignore(
     root: &Path,
@@ -36,6 +93,7 @@ pub fn get_dir_files_with_ignore(
     for pat in ignore_patterns {
         builder.add_ignore(pat);
     }
+    builder.filter_entry(|entry| entry.file_name() != STATE_DIR_NAME);
     let walker = builder.build();
     for result in walker {
         let entry = result?;
@@ -68,4 +126,25 @@ mod tests {
         get_dir_files_with_ignore(dir.path(), &mut files, &[]).unwrap();
         assert!(files.contains_key("foo.txt"));
     }
+
+    #[test]
+    fn test_get_dir_files_skips_state_dir() {
+        let dir = tempdir().unwrap();
+        let state_dir = dir.path().join(STATE_DIR_NAME);
+        std::fs::create_dir(&state_dir).unwrap();
+        File::create(state_dir.join("foo.txt")).unwrap();
+
+        let mut files = FxHashMap::default();
+        get_dir_files_with_ignore(dir.path(), &mut files, &[]).unwrap();
+        assert!(files.is_empty());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64k").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("10M").unwrap(), 10 * 1024 * 1024);
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("").is_err());
+    }
 }
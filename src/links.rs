@@ -0,0 +1,125 @@
+//! Hardlink tracking for folder-differ
+//!
+//! Files are identified by `(device, inode)` so that paths linked to the same
+//! inode are hashed once and their grouping can be compared between trees
+//! and recreated by sync.
+
+use crate::sync::Side;
+use rustc_hash::FxHashMap;
+use std::fs::Metadata;
+use std::sync::Arc;
+
+/// The `(device, inode)` pair identifying a file, where the platform has one.
+#[cfg(unix)]
+pub fn inode_key(meta: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn inode_key(_meta: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Number of hardlinks to a file.
+#[cfg(unix)]
+pub fn link_count(meta: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink()
+}
+
+#[cfg(not(unix))]
+pub fn link_count(_meta: &Metadata) -> u64 {
+    1
+}
+
+/// The hardlink groups of one tree: paths sharing an inode, sorted.
+///
+/// Only groups with at least two paths inside the tree are kept.
+#[derive(Debug, Clone, Default)]
+pub struct LinkGroups {
+    groups: FxHashMap<String, Arc<[String]>>,
+}
+
+impl LinkGroups {
+    /// Group the scanned files of a tree by inode.
+    pub fn from_files(files: &FxHashMap<String, Metadata>) -> Self {
+        let mut by_inode: FxHashMap<(u64, u64), Vec<String>> = FxHashMap::default();
+        for (path, meta) in files {
+            if link_count(meta) > 1
+                && let Some(key) = inode_key(meta)
+            {
+                by_inode.entry(key).or_default().push(path.clone());
+            }
+        }
+        let mut groups = FxHashMap::default();
+        for mut paths in by_inode.into_values().filter(|paths| paths.len() > 1) {
+            paths.sort();
+            let group: Arc<[String]> = paths.into();
+            for path in group.iter() {
+                groups.insert(path.clone(), Arc::clone(&group));
+            }
+        }
+        Self { groups }
+    }
+
+    /// All paths linked to `path` (including itself), if it has any links.
+    pub fn group(&self, path: &str) -> Option<&[String]> {
+        self.groups.get(path).map(|group| &group[..])
+    }
+
+    /// The first path of `path`'s group, unless that is `path` itself.
+    pub fn leader(&self, path: &str) -> Option<&str> {
+        self.group(path)
+            .map(|group| group[0].as_str())
+            .filter(|leader| *leader != path)
+    }
+
+    /// Iterate over the distinct groups.
+    pub fn iter(&self) -> impl Iterator<Item = &[String]> {
+        self.groups
+            .iter()
+            .filter(|(path, group)| group[0] == **path)
+            .map(|(_, group)| &group[..])
+    }
+}
+
+/// Hardlink groups of both trees, used by sync to recreate links.
+#[derive(Debug, Clone, Default)]
+pub struct Hardlinks {
+    pub left: LinkGroups,
+    pub right: LinkGroups,
+}
+
+impl Hardlinks {
+    pub fn side(&self, side: Side) -> &LinkGroups {
+        match side {
+            Side::Left => &self.left,
+            Side::Right => &self.right,
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::get_dir_files_with_ignore;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_link_groups() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), b"x").unwrap();
+        std::fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+        std::fs::write(dir.path().join("c"), b"x").unwrap();
+        let mut files = FxHashMap::default();
+        get_dir_files_with_ignore(dir.path(), &mut files, &[]).unwrap();
+
+        let groups = LinkGroups::from_files(&files);
+        assert_eq!(groups.group("b").unwrap(), ["a", "b"]);
+        assert_eq!(groups.leader("b"), Some("a"));
+        assert_eq!(groups.leader("a"), None);
+        assert!(groups.group("c").is_none());
+        assert_eq!(groups.iter().count(), 1);
+    }
+}
@@ -1,35 +1,672 @@
 use anyhow::Result as AnyResult;
-use ctrlc;
-use folder_differ::{diff, hash, progress};
+use folder_differ::{
+    STATE_DIR_NAME, binary_diff, cache, content, copy, diff, hash, journal, links, manifest,
+    normalize, progress, snapshot, source, sync, text_diff,
+};
 #[cfg(feature = "progress")]
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 use rustc_hash::{FxHashMap, FxHashSet};
 use std::fs::File;
 use std::io::{BufWriter, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 fn print_usage(program: &str) {
     println!(
-        "Usage: {} <left_dir> <right_dir> [--threads N] [--sync] [--dry-run] [--rollback] [--synthetic-benchmark]",
+        "Usage: {} <left_dir> <right_dir> [--threads N] [--hash ALGO] [--sync] [--dry-run] [--backup-dir DIR] [--keep-going] [--preserve LIST] [--max-per-device N] [--bwlimit RATE] [--whole-file] [--copy-method METHOD] [--hardlinks] [--no-cache] [--incremental] [--text-diff] [--patch FILE] [--text-diff-max SIZE] [--binary-diff] [--compare GLOB=KIND] [--normalize LIST] [--ignore-lines REGEX] [--mtime-window SECS] [--ignore-hour-offsets] [--time-diffs] [--resume] [--rollback] [--rollback-run ID] [--rollback-since ID] [--synthetic-benchmark]",
+        program
+    );
+    println!("       {} history <left_dir>", program);
+    println!("       {} cache prune <dir>...", program);
+    println!(
+        "       {} manifest create <dir> <file> [--format native|sha256sum|b3sum] [--hash ALGO]",
+        program
+    );
+    println!(
+        "       {} watch <left_dir> <right_dir> [--hash ALGO] [--settle-ms N] [--sync]",
+        program
+    );
+    println!(
+        "       {} replicas <dir> <dir> [<dir>...] [--hash ALGO]",
+        program
+    );
+    println!(
+        "       {} dupes <dir> [<other_dir>] [--hash ALGO] [--min-size SIZE] [--dedupe hardlink|reflink] [--dry-run]",
+        program
+    );
+    println!(
+        "       {} compare3 <base_dir> <ours_dir> <theirs_dir> [--hash ALGO] [--dry-run] [--sync]",
+        program
+    );
+    println!(
+        "       {} manifest verify <dir> <file> [--hash ALGO]",
         program
     );
     println!("\nOptions:");
     println!(
         "  --threads N              Set number of threads for parallelism (default: 2x logical CPUs)"
     );
+    println!("  --hash ALGO              Content hash: blake3, sha256 or xxh3 (default: blake3)");
     println!("  --sync                   Plan and perform sync actions (copy/delete files)");
     println!("  --dry-run                Show planned sync actions without making changes");
+    println!(
+        "  --backup-dir DIR         Store sync backups in DIR instead of <tree>/.folder-differ/backups"
+    );
+    println!(
+        "  --keep-going             Continue syncing after a failed action (default: stop at the first failure)"
+    );
+    println!(
+        "  --preserve LIST          Metadata to keep when copying: times,mode,owner, all or none (default: times,mode)"
+    );
+    println!(
+        "  --max-per-device N       Run at most N sync actions at once on each destination device"
+    );
+    println!("  --bwlimit RATE           Limit sync copy bandwidth in bytes/sec, e.g. 500K or 10M");
+    println!(
+        "  --whole-file             Always copy whole files (default: large changed files are delta-transferred)"
+    );
+    println!(
+        "  --copy-method METHOD     How sync copies data: auto (reflink if possible), reflink or copy (default: auto)"
+    );
+    println!("  --hardlinks              Recreate hardlink groups when copying linked files");
+    println!(
+        "  --no-cache               Do not use or update the hash cache in <tree>/.folder-differ/hashcache"
+    );
+    println!(
+        "  --text-diff              Add a unified diff of each changed text file to the report"
+    );
+    println!("  --patch FILE             Write the unified diffs of changed text files to FILE");
+    println!("  --text-diff-max SIZE     Largest file diffed as text, e.g. 256K (default: 1M)");
+    println!(
+        "  --binary-diff            Report where each changed non-text file differs (offset, regions, bytes)"
+    );
+    println!(
+        "  --compare GLOB=KIND      Compare files matching GLOB by meaning: json, yaml, text or zip (repeatable)"
+    );
+    println!(
+        "  --normalize LIST         Compare text files ignoring: eol (CRLF vs LF), whitespace (trailing) or all"
+    );
+    println!(
+        "  --ignore-lines REGEX     Compare text files ignoring lines matching REGEX (repeatable)"
+    );
+    println!(
+        "  --mtime-window SECS      Treat mtimes at most SECS apart as equal, e.g. 2 for FAT/exFAT (default: 0)"
+    );
+    println!(
+        "  --ignore-hour-offsets    Treat mtimes a whole number of hours apart (DST/time zone shifts) as equal"
+    );
+    println!(
+        "  --time-diffs             Report files with equal content but different mtimes as TimeDiffers"
+    );
+    println!(
+        "  --resume                 Clean up an interrupted sync recorded in the journal, then sync again"
+    );
     println!("  --rollback               Roll back the last sync operation using backups");
+    println!("  --rollback-run ID        Roll back one specific sync run");
+    println!("  --rollback-since ID      Roll back sync run ID and every run after it");
     println!(
         "  --synthetic-benchmark    Run a synthetic benchmark (creates and scans a large fake tree)"
     );
     println!("  --help                   Show this help message");
 }
 
+/// Drop stale entries from the hash cache of each tree in `dirs`.
+fn prune_caches(dirs: &[String]) -> AnyResult<()> {
+    for dir in dirs {
+        // Pruning keeps entries of every algorithm, so which one is irrelevant.
+        let cache = cache::HashCache::open(Path::new(dir), hash::HashAlgorithm::default())?;
+        let total = cache.len();
+        let removed = cache.prune();
+        cache.save()?;
+        println!("{}: pruned {} of {} cache entries", dir, removed, total);
+    }
+    Ok(())
+}
+
+/// Where the diff report of `left` against `right` is written.
+fn output_path_for(left: &Path, right: &Path) -> AnyResult<PathBuf> {
+    let left_name = left.file_name().and_then(|n| n.to_str()).unwrap_or("left");
+    let right_name = right
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("right");
+    let output_dir = Path::new("./output");
+    std::fs::create_dir_all(output_dir)?;
+    Ok(output_dir.join(format!("{}_vs_{}.txt", left_name, right_name)))
+}
+
+/// The unified diff of a changed file, if both versions of it are text.
+fn text_patch(
+    diff: &diff::Diff,
+    left: &Path,
+    right: &Path,
+    options: &text_diff::TextDiffOptions,
+) -> Option<String> {
+    if !matches!(diff.diff_type, diff::DiffType::Different { .. }) {
+        return None;
+    }
+    text_diff::unified_diff(&diff.path, left, right, options)
+        .unwrap_or_else(|e| {
+            warn!("Could not diff {}: {}", diff.path, e);
+            None
+        })
+        .filter(|patch| !patch.is_empty())
+}
+
+/// Where a changed file differs, as a line for the report.
+///
+/// `None` for text files, whether or not they were diffed as text.
+fn binary_diff_line(diff: &diff::Diff, left: &Path, right: &Path) -> Option<String> {
+    if !matches!(diff.diff_type, diff::DiffType::Different { .. }) {
+        return None;
+    }
+    let is_text = |root: &Path| text_diff::is_text(&root.join(&diff.path)).unwrap_or(false);
+    if is_text(left) && is_text(right) {
+        return None;
+    }
+    match binary_diff::locate_differences(&left.join(&diff.path), &right.join(&diff.path)) {
+        Ok(located) => Some(format!("Binary diff: {}", located)),
+        Err(e) => {
+            warn!("Could not locate differences in {}: {}", diff.path, e);
+            None
+        }
+    }
+}
+
+/// Write `patches` (path and unified diff) to `path`, ordered by path.
+fn write_patch_file(path: &Path, mut patches: Vec<(String, String)>) -> AnyResult<()> {
+    patches.sort();
+    let mut writer = BufWriter::new(File::create(path)?);
+    for (_, patch) in &patches {
+        writer.write_all(patch.as_bytes())?;
+    }
+    writer.flush()?;
+    info!("Wrote {} text diffs to {}", patches.len(), path.display());
+    Ok(())
+}
+
+/// Compare an archive with a directory or another archive.
+fn compare_with_archive(
+    left: &Path,
+    right: &Path,
+    algorithm: hash::HashAlgorithm,
+) -> AnyResult<()> {
+    let diffs = match (left.is_dir(), right.is_dir()) {
+        (true, false) => source::compare_dir_to_source(
+            left,
+            source::open_source(right)?.as_mut(),
+            sync::Side::Right,
+            algorithm,
+        )?,
+        (false, true) => source::compare_dir_to_source(
+            right,
+            source::open_source(left)?.as_mut(),
+            sync::Side::Left,
+            algorithm,
+        )?,
+        _ => source::compare_sources(
+            source::open_source(left)?.as_mut(),
+            source::open_source(right)?.as_mut(),
+            algorithm,
+        )?,
+    };
+    let output_path = output_path_for(left, right)?;
+    let mut writer = BufWriter::new(File::create(&output_path)?);
+    writeln!(writer, "Differences:")?;
+    for diff in &diffs {
+        writeln!(writer, "Diff: {:?}", diff)?;
+    }
+    writeln!(writer, "Total differences found: {}", diffs.len())?;
+    writer.flush()?;
+    info!("Output written to {}", output_path.display());
+    Ok(())
+}
+
+/// The value given after the flag `name` in a subcommand's `args`.
+fn option<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+}
+
+/// A subcommand's positional arguments: everything that is neither a flag
+/// nor the value of one of `value_flags`.
+fn positional<'a>(args: &'a [String], value_flags: &[&str]) -> Vec<&'a String> {
+    args.iter()
+        .enumerate()
+        .filter(|(i, a)| {
+            !a.starts_with("--") && (*i == 0 || !value_flags.contains(&args[i - 1].as_str()))
+        })
+        .map(|(_, a)| a)
+        .collect()
+}
+
+/// `manifest create|verify <dir> <file>`: snapshot a tree or check it.
+fn run_manifest(program: &str, args: &[String]) -> AnyResult<()> {
+    let positional = positional(args, &["--format", "--hash"]);
+    let (Some(command), Some(dir), Some(file)) =
+        (positional.first(), positional.get(1), positional.get(2))
+    else {
+        print_usage(program);
+        std::process::exit(1);
+    };
+    let algorithm = option(args, "--hash")
+        .map(hash::HashAlgorithm::parse)
+        .transpose()?;
+    let (dir, file) = (Path::new(dir.as_str()), Path::new(file.as_str()));
+    match command.as_str() {
+        "create" => {
+            let (format, default_algorithm) = match option(args, "--format").unwrap_or("native") {
+                "native" => (
+                    manifest::ManifestFormat::Native,
+                    hash::HashAlgorithm::Blake3,
+                ),
+                "sha256sum" => (
+                    manifest::ManifestFormat::Checksums,
+                    hash::HashAlgorithm::Sha256,
+                ),
+                "b3sum" => (
+                    manifest::ManifestFormat::Checksums,
+                    hash::HashAlgorithm::Blake3,
+                ),
+                other => anyhow::bail!("Unknown manifest format: {}", other),
+            };
+            let manifest = manifest::Manifest::create(dir, algorithm.unwrap_or(default_algorithm))?;
+            manifest.write(file, format)?;
+            println!(
+                "Wrote {} entries to {}",
+                manifest.entries.len(),
+                file.display()
+            );
+        }
+        "verify" => {
+            let manifest = manifest::Manifest::read(file, algorithm)?;
+            let diffs = manifest::compare_to_manifest(dir, &manifest)?;
+            for diff in &diffs {
+                println!("Diff: {:?}", diff);
+            }
+            println!("Total differences found: {}", diffs.len());
+            if !diffs.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        _ => {
+            print_usage(program);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+/// The comparison state for `left` against `right`, with hash caches unless
+/// disabled or unavailable.
+fn compare_context_for(
+    left: &Path,
+    right: &Path,
+    algorithm: hash::HashAlgorithm,
+    use_cache: bool,
+) -> diff::CompareContext {
+    if !use_cache {
+        return diff::CompareContext::new(algorithm);
+    }
+    diff::CompareContext::with_caches(left, right, algorithm).unwrap_or_else(|e| {
+        warn!("Hash cache unavailable, hashing everything: {}", e);
+        diff::CompareContext::new(algorithm)
+    })
+}
+
+/// Plan the sync of `diffs`, then print the plan or carry it out.
+fn plan_and_sync(
+    mut diffs: Vec<diff::Diff>,
+    left: &Path,
+    right: &Path,
+    dry_run: bool,
+    options: &sync::SyncOptions,
+    backup_dir: Option<&str>,
+) -> AnyResult<()> {
+    diffs.sort_by(|a, b| a.path.cmp(&b.path));
+    let actions = sync::plan_sync_actions(&diffs, "left-to-right", options);
+    if dry_run {
+        for action in &actions {
+            println!("Planned: {:?}", action);
+        }
+    } else if !run_sync(&actions, left, right, options, backup_dir)?.is_success() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Refuse to start `what` while the journal of `left` has an interrupted run.
+fn ensure_no_interrupted_run(left: &Path, what: &str) -> AnyResult<()> {
+    if journal::load_runs(&journal::Journal::path_for(left))?
+        .iter()
+        .any(|run| run.is_interrupted())
+    {
+        anyhow::bail!(
+            "A sync run was interrupted; resume or roll it back before {}",
+            what
+        );
+    }
+    Ok(())
+}
+
+/// Perform `actions` as one journaled sync run and print its summary.
+fn run_sync(
+    actions: &[sync::SyncAction],
+    left: &Path,
+    right: &Path,
+    options: &sync::SyncOptions,
+    backup_dir: Option<&str>,
+) -> AnyResult<sync::SyncReport> {
+    let run_id = sync::new_run_id();
+    let store = match backup_dir {
+        Some(dir) => sync::BackupStore::external(dir, run_id.as_str()),
+        None => sync::BackupStore::in_tree(run_id.as_str()),
+    };
+    let mut log = sync::SyncLog::default();
+    let mut journal = journal::Journal::open(&journal::Journal::path_for(left))?;
+    let run = journal::JournaledSync::begin(&mut journal, &store, options, left, right)?;
+    let report = run.execute(actions, &mut log)?;
+    run.finish(&report)?;
+    for (action, outcome) in &report.failed {
+        if let sync::SyncOutcome::Failed { kind, message } = outcome {
+            error!(
+                "{} {}: {} ({:?})",
+                action.kind(),
+                action.path(),
+                message,
+                kind
+            );
+        }
+    }
+    println!("Sync run {}: {}", run_id, report.summary());
+    Ok(report)
+}
+
+/// `replicas <dir>...`: show which replicas disagree on which paths.
+fn run_replicas(program: &str, args: &[String]) -> AnyResult<()> {
+    use folder_differ::replicas::{compare_replicas, outlier_counts};
+    let roots: Vec<PathBuf> = positional(args, &["--hash"])
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+    if roots.len() < 2 {
+        print_usage(program);
+        std::process::exit(1);
+    }
+    let algorithm = option(args, "--hash")
+        .map(hash::HashAlgorithm::parse)
+        .transpose()?
+        .unwrap_or_default();
+    let names = |replicas: &[usize]| {
+        replicas
+            .iter()
+            .map(|&replica| roots[replica].display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let diffs = compare_replicas(&roots, algorithm)?;
+    for diff in &diffs {
+        match diff.outliers() {
+            Some(outliers) => println!("{}: outliers {}", diff.path, names(&outliers)),
+            None => println!("{}: no majority", diff.path),
+        }
+        for (index, version) in diff.versions.iter().enumerate() {
+            println!(
+                "  version {} ({} bytes): {}",
+                index + 1,
+                version.size,
+                names(&version.replicas)
+            );
+        }
+        if !diff.missing.is_empty() {
+            println!("  missing: {}", names(&diff.missing));
+        }
+    }
+    for (root, count) in roots.iter().zip(outlier_counts(&diffs, roots.len())) {
+        println!("{}: outlier on {} paths", root.display(), count);
+    }
+    println!("Total paths in disagreement: {}", diffs.len());
+    if !diffs.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// `dupes <dir> [<other_dir>]`: report identical files, and optionally link
+/// them together.
+fn run_dupes(program: &str, args: &[String]) -> AnyResult<()> {
+    use folder_differ::dupes::{find_dupes, plan_dedupe, reclaimable_bytes};
+    let positional = positional(args, &["--hash", "--min-size", "--dedupe"]);
+    let Some(left) = positional.first() else {
+        print_usage(program);
+        std::process::exit(1);
+    };
+    let left = Path::new(left.as_str());
+    let right = positional.get(1).map(|right| Path::new(right.as_str()));
+    let algorithm = option(args, "--hash")
+        .map(hash::HashAlgorithm::parse)
+        .transpose()?
+        .unwrap_or_default();
+    let min_size = option(args, "--min-size")
+        .map(folder_differ::parse_size)
+        .transpose()?
+        .unwrap_or(1);
+    let method = option(args, "--dedupe")
+        .map(sync::DedupeMethod::parse)
+        .transpose()?;
+
+    let groups = find_dupes(left, right, algorithm, min_size)?;
+    for group in &groups {
+        println!(
+            "{} copies of {} bytes ({} reclaimable):",
+            group.copies(),
+            group.size,
+            group.reclaimable()
+        );
+        for file in &group.files {
+            match right {
+                Some(_) => println!("  {}: {}", file.side.as_str(), file.path),
+                None => println!("  {}", file.path),
+            }
+        }
+    }
+    println!(
+        "Duplicate groups: {}, reclaimable: {} bytes",
+        groups.len(),
+        reclaimable_bytes(&groups)
+    );
+    let Some(method) = method else {
+        return Ok(());
+    };
+    let actions = plan_dedupe(&groups, method);
+    if args.contains(&"--dry-run".to_string()) {
+        for action in &actions {
+            println!("Planned: {:?}", action);
+        }
+        return Ok(());
+    }
+    if !cfg!(feature = "sync") {
+        anyhow::bail!("Deduplication needs sync support, which is not enabled in this build");
+    }
+    ensure_no_interrupted_run(left, "deduplicating")?;
+    let right = right.unwrap_or(left);
+    if !run_sync(&actions, left, right, &sync::SyncOptions::default(), None)?.is_success() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// `compare3 <base> <ours> <theirs>`: classify the changes of two trees that
+/// share a base, and optionally apply the ones that do not conflict.
+fn run_compare3(program: &str, args: &[String]) -> AnyResult<()> {
+    use folder_differ::three_way::{ThreeWayStatus, compare3_with};
+    let positional = positional(args, &["--hash"]);
+    let (Some(base), Some(ours), Some(theirs)) =
+        (positional.first(), positional.get(1), positional.get(2))
+    else {
+        print_usage(program);
+        std::process::exit(1);
+    };
+    let (base, ours, theirs) = (
+        Path::new(base.as_str()),
+        Path::new(ours.as_str()),
+        Path::new(theirs.as_str()),
+    );
+    let algorithm = option(args, "--hash")
+        .map(hash::HashAlgorithm::parse)
+        .transpose()?
+        .unwrap_or_default();
+    #[cfg(feature = "sync")]
+    let do_sync = args.contains(&"--sync".to_string());
+    #[cfg(not(feature = "sync"))]
+    let do_sync = false;
+    let dry_run = args.contains(&"--dry-run".to_string());
+
+    let diffs = compare3_with(base, ours, theirs, algorithm)?;
+    let count = |status: ThreeWayStatus| diffs.iter().filter(|d| d.status == status).count();
+    for diff in diffs
+        .iter()
+        .filter(|d| d.status != ThreeWayStatus::Unchanged)
+    {
+        println!("{:?}: {}", diff.status, diff.path);
+    }
+    println!(
+        "Changed in ours: {}, changed in theirs: {}, changed identically: {}, conflicts: {}",
+        count(ThreeWayStatus::ChangedInOurs),
+        count(ThreeWayStatus::ChangedInTheirs),
+        count(ThreeWayStatus::ChangedIdentically),
+        count(ThreeWayStatus::Conflict)
+    );
+    if !dry_run && !do_sync {
+        return Ok(());
+    }
+    let actions = sync::plan_merge_actions(&diffs);
+    if dry_run {
+        for action in &actions {
+            println!("Planned: {:?}", action);
+        }
+        return Ok(());
+    }
+    ensure_no_interrupted_run(ours, "merging")?;
+    if !run_sync(&actions, ours, theirs, &sync::SyncOptions::default(), None)?.is_success() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// `watch <left> <right>`: keep comparing the trees as they change.
+#[cfg(all(feature = "watch", target_os = "linux"))]
+fn run_watch(program: &str, args: &[String]) -> AnyResult<()> {
+    use folder_differ::watch::{DiffChange, TreeWatcher};
+    let positional = positional(args, &["--hash", "--settle-ms"]);
+    let (Some(left), Some(right)) = (positional.first(), positional.get(1)) else {
+        print_usage(program);
+        std::process::exit(1);
+    };
+    let (left, right) = (Path::new(left.as_str()), Path::new(right.as_str()));
+    let algorithm = option(args, "--hash")
+        .map(hash::HashAlgorithm::parse)
+        .transpose()?
+        .unwrap_or_default();
+    let settle = option(args, "--settle-ms")
+        .map(str::parse)
+        .transpose()?
+        .map_or(Duration::from_millis(200), Duration::from_millis);
+    #[cfg(feature = "sync")]
+    let do_sync = args.contains(&"--sync".to_string());
+    #[cfg(not(feature = "sync"))]
+    let do_sync = false;
+    if do_sync {
+        ensure_no_interrupted_run(left, "watching")?;
+    }
+
+    let context = compare_context_for(left, right, algorithm, true);
+    let mut watcher = TreeWatcher::new(left, right, context)?;
+    if let Err(e) = watcher.context().save_caches() {
+        warn!("Could not save hash cache: {}", e);
+    }
+    let mut pending: Vec<diff::Diff> = watcher.diffs().cloned().collect();
+    for diff in &pending {
+        println!("Diff: {:?}", diff);
+    }
+    println!("Watching {} and {}", left.display(), right.display());
+    loop {
+        if do_sync && !pending.is_empty() {
+            let options = sync::SyncOptions::default();
+            let actions = sync::plan_sync_actions(&pending, "left-to-right", &options);
+            run_sync(&actions, left, right, &options, None)?;
+        }
+        pending.clear();
+        for change in watcher.wait(settle)? {
+            match change {
+                DiffChange::Added(diff) => {
+                    println!("New diff: {:?}", diff);
+                    pending.push(diff);
+                }
+                DiffChange::Changed(diff) => {
+                    println!("Changed diff: {:?}", diff);
+                    pending.push(diff);
+                }
+                DiffChange::Resolved(path) => println!("Resolved: {}", path),
+            }
+        }
+    }
+}
+
+/// Print the sync runs recorded in the journal of `left`.
+fn print_history(left: &Path) -> AnyResult<()> {
+    let runs = journal::load_runs(&journal::Journal::path_for(left))?;
+    if runs.is_empty() {
+        println!("No sync runs recorded for {}", left.display());
+        return Ok(());
+    }
+    for run in &runs {
+        let counts = run
+            .action_counts()
+            .iter()
+            .map(|(kind, n)| format!("{}: {}", kind, n))
+            .collect::<Vec<_>>();
+        let total: usize = run.action_counts().iter().map(|(_, n)| n).sum();
+        println!(
+            "{}  {}  {:<12} {} -> {}",
+            run.run_id,
+            sync::format_timestamp(run.started),
+            run.status(),
+            run.left.display(),
+            run.right.display()
+        );
+        println!("    {} actions ({})", total, counts.join(", "));
+        if run.incomplete_count() > 0 {
+            println!(
+                "    {} actions failed or unfinished",
+                run.incomplete_count()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// jwalk `process_read_dir` callback that drops folder-differ's state directory.
+fn skip_state_dir(
+    _depth: Option<usize>,
+    _path: &Path,
+    _state: &mut (),
+    children: &mut Vec<jwalk::Result<jwalk::DirEntry<((), ())>>>,
+) {
+    children.retain(|child| {
+        child
+            .as_ref()
+            .map(|entry| entry.file_name != STATE_DIR_NAME)
+            .unwrap_or(true)
+    });
+}
+
 fn main() -> AnyResult<()> {
     // Initialize logger
     env_logger::init();
@@ -45,8 +682,57 @@ fn main() -> AnyResult<()> {
         print_usage(&args[0]);
         return Ok(());
     }
+    if args.get(1).map(String::as_str) == Some("history") {
+        let Some(left_dir) = args.get(2) else {
+            print_usage(&args[0]);
+            std::process::exit(1);
+        };
+        return print_history(Path::new(left_dir));
+    }
+    if args.get(1).map(String::as_str) == Some("watch") {
+        #[cfg(all(feature = "watch", target_os = "linux"))]
+        return run_watch(&args[0], &args[2..]);
+        #[cfg(not(all(feature = "watch", target_os = "linux")))]
+        {
+            eprintln!("Watch mode is not enabled in this build.");
+            std::process::exit(1);
+        }
+    }
+    if args.get(1).map(String::as_str) == Some("replicas") {
+        return run_replicas(&args[0], &args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("dupes") {
+        return run_dupes(&args[0], &args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("compare3") {
+        return run_compare3(&args[0], &args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("manifest") {
+        return run_manifest(&args[0], &args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("cache") {
+        if args.get(2).map(String::as_str) != Some("prune") || args.len() < 4 {
+            print_usage(&args[0]);
+            std::process::exit(1);
+        }
+        return prune_caches(&args[3..]);
+    }
     // Thread count CLI option
     let mut thread_count: Option<usize> = None;
+    let mut backup_dir: Option<String> = None;
+    let mut rollback_run_id: Option<String> = None;
+    let mut rollback_since: Option<String> = None;
+    let mut preserve_spec: Option<String> = None;
+    let mut max_per_device: Option<usize> = None;
+    let mut bwlimit: Option<String> = None;
+    let mut copy_method: Option<String> = None;
+    let mut hash_name: Option<String> = None;
+    let mut patch_path: Option<String> = None;
+    let mut text_diff_max: Option<String> = None;
+    let mut compare_rules: Vec<String> = Vec::new();
+    let mut normalize_spec: Option<String> = None;
+    let mut ignore_line_patterns: Vec<String> = Vec::new();
+    let mut mtime_window: Option<String> = None;
     let mut left_dir_arg = None;
     let mut right_dir_arg = None;
     let mut i = 1;
@@ -56,6 +742,52 @@ fn main() -> AnyResult<()> {
                 thread_count = Some(n);
             }
             i += 2;
+        } else if args[i] == "--backup-dir" && i + 1 < args.len() {
+            backup_dir = Some(args[i + 1].clone());
+            i += 2;
+        } else if args[i] == "--rollback-run" && i + 1 < args.len() {
+            rollback_run_id = Some(args[i + 1].clone());
+            i += 2;
+        } else if args[i] == "--rollback-since" && i + 1 < args.len() {
+            rollback_since = Some(args[i + 1].clone());
+            i += 2;
+        } else if args[i] == "--preserve" && i + 1 < args.len() {
+            preserve_spec = Some(args[i + 1].clone());
+            i += 2;
+        } else if args[i] == "--max-per-device" && i + 1 < args.len() {
+            if let Ok(n) = args[i + 1].parse::<usize>() {
+                max_per_device = Some(n);
+            }
+            i += 2;
+        } else if args[i] == "--bwlimit" && i + 1 < args.len() {
+            bwlimit = Some(args[i + 1].clone());
+            i += 2;
+        } else if args[i] == "--copy-method" && i + 1 < args.len() {
+            copy_method = Some(args[i + 1].clone());
+            i += 2;
+        } else if args[i] == "--hash" && i + 1 < args.len() {
+            hash_name = Some(args[i + 1].clone());
+            i += 2;
+        } else if args[i] == "--patch" && i + 1 < args.len() {
+            patch_path = Some(args[i + 1].clone());
+            i += 2;
+        } else if args[i] == "--text-diff-max" && i + 1 < args.len() {
+            text_diff_max = Some(args[i + 1].clone());
+            i += 2;
+        } else if args[i] == "--compare" && i + 1 < args.len() {
+            compare_rules.push(args[i + 1].clone());
+            i += 2;
+        } else if args[i] == "--normalize" && i + 1 < args.len() {
+            normalize_spec = Some(args[i + 1].clone());
+            i += 2;
+        } else if args[i] == "--ignore-lines" && i + 1 < args.len() {
+            ignore_line_patterns.push(args[i + 1].clone());
+            i += 2;
+        } else if args[i] == "--mtime-window" && i + 1 < args.len() {
+            mtime_window = Some(args[i + 1].clone());
+            i += 2;
+        } else if args[i].starts_with("--") {
+            i += 1;
         } else if left_dir_arg.is_none() {
             left_dir_arg = Some(args[i].clone());
             i += 1;
@@ -99,25 +831,202 @@ fn main() -> AnyResult<()> {
     #[cfg(not(feature = "sync"))]
     let do_sync = false;
     #[cfg(feature = "sync")]
-    let do_rollback = args.contains(&"--rollback".to_string());
+    let do_rollback = args.contains(&"--rollback".to_string())
+        || rollback_run_id.is_some()
+        || rollback_since.is_some();
     #[cfg(not(feature = "sync"))]
     let do_rollback = false;
     let dry_run = args.contains(&"--dry-run".to_string());
+    #[cfg(feature = "sync")]
+    let do_resume = args.contains(&"--resume".to_string());
+    #[cfg(not(feature = "sync"))]
+    let do_resume = false;
+    let hash_algorithm = match &hash_name {
+        Some(name) => hash::HashAlgorithm::parse(name)?,
+        None => hash::HashAlgorithm::default(),
+    };
+    let embed_text_diffs = args.contains(&"--text-diff".to_string());
+    let locate_binary = args.contains(&"--binary-diff".to_string());
+    let text_diff_options = (embed_text_diffs || patch_path.is_some())
+        .then(|| -> AnyResult<_> {
+            let mut options = text_diff::TextDiffOptions::default();
+            if let Some(size) = &text_diff_max {
+                options.max_size = folder_differ::parse_size(size)?;
+            }
+            Ok(options)
+        })
+        .transpose()?;
+    let mut comparers = content::ComparerRegistry::new();
+    for rule in &compare_rules {
+        comparers.register_spec(rule)?;
+    }
+    let mut normalization = match &normalize_spec {
+        Some(spec) => normalize::TextNormalization::parse(spec)?,
+        None => normalize::TextNormalization::default(),
+    };
+    for pattern in &ignore_line_patterns {
+        normalization.ignore_lines_matching(pattern)?;
+    }
+    let mut time_tolerance = diff::TimeTolerance {
+        ignore_hour_offsets: args.contains(&"--ignore-hour-offsets".to_string()),
+        ..diff::TimeTolerance::default()
+    };
+    if let Some(secs) = &mtime_window {
+        time_tolerance.window = secs
+            .parse::<f64>()
+            .ok()
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .ok_or_else(|| anyhow::anyhow!("Invalid --mtime-window: {}", secs))?;
+    }
+    let report_time_diffs = args.contains(&"--time-diffs".to_string());
+    if args.contains(&"--hardlinks".to_string()) && args.contains(&"--incremental".to_string()) {
+        anyhow::bail!("--hardlinks cannot be combined with --incremental");
+    }
+
+    // An archive on either side is streamed as an entry source instead.
+    if left.is_file() || right.is_file() {
+        if do_sync || dry_run || do_rollback {
+            anyhow::bail!("--sync, --dry-run and --rollback need two directories");
+        }
+        return compare_with_archive(left, right, hash_algorithm);
+    }
+    let mut sync_options = sync::SyncOptions::default();
+    if args.contains(&"--keep-going".to_string()) {
+        sync_options.failure_policy = sync::FailurePolicy::KeepGoing;
+    }
+    if let Some(spec) = &preserve_spec {
+        sync_options.preserve = sync::PreserveOptions::parse(spec)?;
+    }
+    sync_options.max_per_device = max_per_device;
+    sync_options.whole_file = args.contains(&"--whole-file".to_string());
+    if let Some(method) = &copy_method {
+        sync_options.copy_method = copy::CopyMethod::parse(method)?;
+    }
+    if let Some(rate) = &bwlimit {
+        let bytes_per_sec = folder_differ::parse_size(rate)?;
+        sync_options.throttle = Some(Arc::new(copy::Throttle::new(bytes_per_sec)));
+    }
+
+    // Recover from or roll back earlier runs recorded in the sync journal
+    let journal_path = journal::Journal::path_for(left);
+    if do_rollback {
+        let runs = journal::load_runs(&journal_path)?;
+        let selected: Vec<&journal::JournalRun> = if let Some(run_id) = &rollback_since {
+            journal::runs_since(&runs, run_id).unwrap_or_default()
+        } else if let Some(run_id) = &rollback_run_id {
+            runs.iter()
+                .filter(|run| &run.run_id == run_id && !run.rolled_back)
+                .collect()
+        } else {
+            runs.iter()
+                .rev()
+                .find(|run| !run.rolled_back)
+                .into_iter()
+                .collect()
+        };
+        if selected.is_empty() {
+            eprintln!(
+                "No matching sync run to roll back in {}",
+                journal_path.display()
+            );
+            std::process::exit(1);
+        }
+        let mut journal = journal::Journal::open(&journal_path)?;
+        let log = journal::rollback_runs(&selected, &mut journal)?;
+        for entry in &log.entries {
+            println!("{}", entry.details);
+        }
+        for run in &selected {
+            info!("Rolled back sync run {}", run.run_id);
+        }
+        return Ok(());
+    }
+    if do_sync && !dry_run {
+        let runs = journal::load_runs(&journal_path)?;
+        if let Some(run) = runs.iter().find(|run| run.is_interrupted()) {
+            if !do_resume {
+                eprintln!(
+                    "Sync run {} was interrupted. Re-run with --resume to continue it or --rollback to undo it.",
+                    run.run_id
+                );
+                std::process::exit(1);
+            }
+            let mut journal = journal::Journal::open(&journal_path)?;
+            journal::recover_run(run, &mut journal)?;
+            info!("Recovered interrupted sync run {}", run.run_id);
+        }
+    }
 
     // Output file logic
-    let left_name = left.file_name().and_then(|n| n.to_str()).unwrap_or("left");
-    let right_name = right
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("right");
-    let output_dir = Path::new("./output");
-    std::fs::create_dir_all(output_dir)?;
-    let output_path = output_dir.join(format!("{}_vs_{}.txt", left_name, right_name));
+    let output_path = output_path_for(left, right)?;
     let output_file = File::create(&output_path)?;
     let writer = BufWriter::new(output_file);
 
     // Timing: start
     let total_start = Instant::now();
+    let plan_sync = do_sync || dry_run;
+    let use_cache = !args.contains(&"--no-cache".to_string());
+
+    if args.contains(&"--incremental".to_string()) {
+        let mut context = compare_context_for(left, right, hash_algorithm, use_cache);
+        context.comparers = comparers.clone();
+        context.normalization = normalization.clone();
+        context.time_tolerance = time_tolerance;
+        context.report_time_diffs = report_time_diffs;
+        let previous = snapshot::Snapshot::load(left, right).unwrap_or_else(|e| {
+            warn!("Snapshot unreadable, comparing everything: {}", e);
+            None
+        });
+        let result = snapshot::compare_incremental(left, right, previous.as_ref(), &context)?;
+        if let Err(e) = context.save_caches() {
+            warn!("Could not save hash cache: {}", e);
+        }
+        if let Err(e) = result.snapshot.save(left) {
+            warn!("Could not save snapshot: {}", e);
+        }
+        let mut writer = writer;
+        let mut patches = Vec::new();
+        writeln!(writer, "Differences:")?;
+        for diff in &result.diffs {
+            writeln!(writer, "Diff: {:?}", diff)?;
+            let patch = text_diff_options
+                .as_ref()
+                .and_then(|options| text_patch(diff, left, right, options));
+            if let Some(patch) = patch {
+                if embed_text_diffs {
+                    writeln!(writer, "{}", patch.trim_end())?;
+                }
+                patches.push((diff.path.clone(), patch));
+            } else if locate_binary && let Some(line) = binary_diff_line(diff, left, right) {
+                writeln!(writer, "{}", line)?;
+            }
+        }
+        writeln!(writer, "Total differences found: {}", result.diffs.len())?;
+        writer.flush()?;
+        if let Some(path) = &patch_path {
+            write_patch_file(Path::new(path), patches)?;
+        }
+        info!(
+            "Incremental diff: {} directories re-read, {} unchanged; {} files compared, {} reused",
+            result.stats.dirs_reread,
+            result.stats.dirs_reused,
+            result.stats.files_compared,
+            result.stats.files_reused
+        );
+        info!("Output written to {}", output_path.display());
+        if plan_sync {
+            plan_and_sync(
+                result.diffs,
+                left,
+                right,
+                dry_run || !do_sync,
+                &sync_options,
+                backup_dir.as_deref(),
+            )?;
+        }
+        info!("Total duration: {:.2?}", total_start.elapsed());
+        return Ok(());
+    }
 
     // PHASE 1: Count files and directories (with progress bar)
     let scan_start = Instant::now();
@@ -172,6 +1081,7 @@ fn main() -> AnyResult<()> {
     let dir_total = left_dir_count.load(Ordering::SeqCst) + right_dir_count.load(Ordering::SeqCst);
     count_pb.finish_with_message("Counting complete");
     let scan_total = file_total + dir_total;
+    debug!("Total entries to scan: {}", scan_total);
     let phase1_time = scan_start.elapsed();
     info!("Phase 1 (counting) duration: {:.2?}", phase1_time);
 
@@ -191,22 +1101,24 @@ fn main() -> AnyResult<()> {
         .unwrap(),
     );
     let mut left_files = FxHashMap::default();
-    for entry in jwalk::WalkDir::new(left) {
-        if let Ok(dir_entry) = entry {
-            if dir_entry.file_type().is_file() {
-                let rel_path = dir_entry
-                    .path()
-                    .strip_prefix(left)
-                    .unwrap()
-                    .to_string_lossy()
-                    .to_string();
-                if let Ok(meta) = dir_entry.metadata() {
-                    left_files.insert(rel_path, meta);
-                }
+    for dir_entry in jwalk::WalkDir::new(left)
+        .process_read_dir(skip_state_dir)
+        .into_iter()
+        .flatten()
+    {
+        if dir_entry.file_type().is_file() {
+            let rel_path = dir_entry
+                .path()
+                .strip_prefix(left)
+                .unwrap()
+                .to_string_lossy()
+                .to_string();
+            if let Ok(meta) = dir_entry.metadata() {
+                left_files.insert(rel_path, meta);
             }
-            #[cfg(feature = "progress")]
-            left_scan_pb.inc(1);
         }
+        #[cfg(feature = "progress")]
+        left_scan_pb.inc(1);
     }
     #[cfg(feature = "progress")]
     left_scan_pb.finish_with_message("Left scan complete");
@@ -222,22 +1134,24 @@ fn main() -> AnyResult<()> {
         .unwrap(),
     );
     let mut right_files = FxHashMap::default();
-    for entry in jwalk::WalkDir::new(right) {
-        if let Ok(dir_entry) = entry {
-            if dir_entry.file_type().is_file() {
-                let rel_path = dir_entry
-                    .path()
-                    .strip_prefix(right)
-                    .unwrap()
-                    .to_string_lossy()
-                    .to_string();
-                if let Ok(meta) = dir_entry.metadata() {
-                    right_files.insert(rel_path, meta);
-                }
+    for dir_entry in jwalk::WalkDir::new(right)
+        .process_read_dir(skip_state_dir)
+        .into_iter()
+        .flatten()
+    {
+        if dir_entry.file_type().is_file() {
+            let rel_path = dir_entry
+                .path()
+                .strip_prefix(right)
+                .unwrap()
+                .to_string_lossy()
+                .to_string();
+            if let Ok(meta) = dir_entry.metadata() {
+                right_files.insert(rel_path, meta);
             }
-            #[cfg(feature = "progress")]
-            right_scan_pb.inc(1);
         }
+        #[cfg(feature = "progress")]
+        right_scan_pb.inc(1);
     }
     #[cfg(feature = "progress")]
     right_scan_pb.finish_with_message("Right scan complete");
@@ -251,6 +1165,14 @@ fn main() -> AnyResult<()> {
     let all_only_in_left = Arc::new(std::sync::atomic::AtomicBool::new(true));
     let processed_count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
     let total_diffs = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let collected_diffs = Mutex::new(Vec::new());
+    let collected_patches = Mutex::new(Vec::new());
+    let diffed_paths = Mutex::new(FxHashSet::default());
+    let mut compare_context = compare_context_for(left, right, hash_algorithm, use_cache);
+    compare_context.comparers = comparers;
+    compare_context.normalization = normalization;
+    compare_context.time_tolerance = time_tolerance;
+    compare_context.report_time_diffs = report_time_diffs;
     let all_paths: FxHashSet<_> = left_files.keys().chain(right_files.keys()).collect();
     let total_files = all_paths.len();
     info!("Processing {} files in parallel...", total_files);
@@ -276,77 +1198,33 @@ fn main() -> AnyResult<()> {
         let processed_count = Arc::clone(&processed_count);
         let total_diffs = Arc::clone(&total_diffs);
         let pb = pb.clone();
+        let collected_diffs = &collected_diffs;
+        let collected_patches = &collected_patches;
+        let diffed_paths = &diffed_paths;
+        let text_diff_options = &text_diff_options;
+        let patch_path = &patch_path;
         rayon::scope(|s| {
             s.spawn(|_| {
                 let mut local_buf = Vec::with_capacity(chunk.len());
+                let mut local_diffs = Vec::new();
+                let mut local_patches = Vec::new();
+                let mut local_paths = Vec::new();
                 for path in chunk {
                     let _count =
                         processed_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
                     let diff_opt = match (left_files.get(*path), right_files.get(*path)) {
-                        (Some(left_meta), Some(right_meta)) => {
-                            let left_size = left_meta.len();
-                            let right_size = right_meta.len();
-                            let left_time = left_meta.modified().ok();
-                            let right_time = right_meta.modified().ok();
-                            if left_size != right_size {
-                                Some(diff::Diff {
-                                    path: (*path).clone(),
-                                    diff_type: diff::DiffType::Different {
-                                        left_size,
-                                        right_size,
-                                        left_time,
-                                        right_time,
-                                    },
-                                })
-                            } else if left_time != right_time {
-                                if left_size < 1024 {
-                                    let left_path = left.join(*path);
-                                    let right_path = right.join(*path);
-                                    if let Ok(are_equal) =
-                                        hash::compare_small_files(&left_path, &right_path)
-                                    {
-                                        if !are_equal {
-                                            Some(diff::Diff {
-                                                path: (*path).clone(),
-                                                diff_type: diff::DiffType::Different {
-                                                    left_size,
-                                                    right_size,
-                                                    left_time,
-                                                    right_time,
-                                                },
-                                            })
-                                        } else {
-                                            None
-                                        }
-                                    } else {
-                                        None
-                                    }
-                                } else {
-                                    let left_hash = hash::hash_file(&left.join(*path));
-                                    let right_hash = hash::hash_file(&right.join(*path));
-                                    if let (Ok(left_hash), Ok(right_hash)) = (left_hash, right_hash)
-                                    {
-                                        if left_hash != right_hash {
-                                            Some(diff::Diff {
-                                                path: (*path).clone(),
-                                                diff_type: diff::DiffType::Different {
-                                                    left_size,
-                                                    right_size,
-                                                    left_time,
-                                                    right_time,
-                                                },
-                                            })
-                                        } else {
-                                            None
-                                        }
-                                    } else {
-                                        None
-                                    }
-                                }
-                            } else {
-                                None
-                            }
-                        }
+                        (Some(left_meta), Some(right_meta)) => diff::compare_entry(
+                            path,
+                            left,
+                            right,
+                            left_meta,
+                            right_meta,
+                            &compare_context,
+                        )
+                        .unwrap_or_else(|e| {
+                            warn!("Could not compare {}: {}", path, e);
+                            None
+                        }),
                         (Some(_), None) => Some(diff::Diff {
                             path: (*path).clone(),
                             diff_type: diff::DiffType::OnlyInLeft,
@@ -362,11 +1240,39 @@ fn main() -> AnyResult<()> {
                     };
                     if let Some(diff) = diff_opt {
                         local_buf.push(format!("Diff: {:?}", diff));
+                        let patch = text_diff_options
+                            .as_ref()
+                            .and_then(|options| text_patch(&diff, left, right, options));
+                        if let Some(patch) = patch {
+                            if embed_text_diffs {
+                                local_buf.push(patch.trim_end().to_string());
+                            }
+                            if patch_path.is_some() {
+                                local_patches.push((diff.path.clone(), patch));
+                            }
+                        } else if locate_binary
+                            && let Some(line) = binary_diff_line(&diff, left, right)
+                        {
+                            local_buf.push(line);
+                        }
                         total_diffs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        local_paths.push(diff.path.clone());
+                        if plan_sync {
+                            local_diffs.push(diff);
+                        }
                     }
                     #[cfg(feature = "progress")]
                     pb.inc(1);
                 }
+                if !local_diffs.is_empty() {
+                    collected_diffs.lock().unwrap().extend(local_diffs);
+                }
+                if !local_patches.is_empty() {
+                    collected_patches.lock().unwrap().extend(local_patches);
+                }
+                if !local_paths.is_empty() {
+                    diffed_paths.lock().unwrap().extend(local_paths);
+                }
                 if !local_buf.is_empty() {
                     let mut w = writer.lock().unwrap();
                     for line in local_buf {
@@ -376,6 +1282,18 @@ fn main() -> AnyResult<()> {
             });
         });
     });
+    let diffed_paths = diffed_paths.into_inner().unwrap();
+    let reported: FxHashSet<&str> = diffed_paths.iter().map(String::as_str).collect();
+    for diff in diff::hardlink_diffs(&left_files, &right_files, &reported) {
+        writeln!(writer.lock().unwrap(), "Diff: {:?}", diff).ok();
+        total_diffs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        if plan_sync {
+            collected_diffs.lock().unwrap().push(diff);
+        }
+    }
+    if let Err(e) = compare_context.save_caches() {
+        warn!("Could not save hash cache: {}", e);
+    }
     #[cfg(feature = "progress")]
     pb.finish_with_message("Diff calculation and output complete");
     let phase3_time = phase3_start.elapsed();
@@ -387,6 +1305,27 @@ fn main() -> AnyResult<()> {
         w.flush().ok();
     }
     info!("Output written to {}", output_path.display());
+    if let Some(path) = &patch_path {
+        write_patch_file(Path::new(path), collected_patches.into_inner().unwrap())?;
+    }
+
+    // PHASE 4: Sync (optional)
+    if plan_sync {
+        if args.contains(&"--hardlinks".to_string()) {
+            sync_options.hardlinks = Some(Arc::new(links::Hardlinks {
+                left: links::LinkGroups::from_files(&left_files),
+                right: links::LinkGroups::from_files(&right_files),
+            }));
+        }
+        plan_and_sync(
+            collected_diffs.into_inner().unwrap(),
+            left,
+            right,
+            dry_run || !do_sync,
+            &sync_options,
+            backup_dir.as_deref(),
+        )?;
+    }
 
     let total_time = total_start.elapsed();
     info!("Total duration: {:.2?}", total_time);
@@ -0,0 +1,355 @@
+//! Checksum manifests for folder-differ
+//!
+//! A manifest snapshots a tree: the size, mtime, mode and digest of every
+//! file. It can be written in folder-differ's own format or as a
+//! `sha256sum`/`b3sum`-compatible checksum list, and a live directory can be
+//! diffed against it instead of against a second directory.
+//!
+//! The native format is tab-separated text: a header line naming the hash
+//! algorithm, then `size mtime mode digest path` per file.
+
+use crate::diff::{Diff, DiffType};
+use crate::hash::{HashAlgorithm, from_hex, hash_file_full, to_hex};
+use crate::journal::{escape_field, format_time, parse_time, unescape_field};
+use crate::source::file_mode;
+use crate::{FolderDifferError, Result, get_dir_files_with_ignore};
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::fs::{File, Metadata};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+const HEADER_PREFIX: &str = "# folder-differ manifest v1 ";
+
+/// On-disk layout of a manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestFormat {
+    /// folder-differ's format, with size, mtime and mode.
+    Native,
+    /// `<hex digest>  <path>` lines as printed by `sha256sum` or `b3sum`.
+    Checksums,
+}
+
+/// One file recorded in a manifest.
+///
+/// Checksum files only carry the digest, so the metadata is optional.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: Option<u64>,
+    pub mtime: Option<SystemTime>,
+    pub mode: Option<u32>,
+    pub digest: Vec<u8>,
+}
+
+/// A snapshot of a tree's files and their digests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub algorithm: HashAlgorithm,
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Hash every file under `root` with `algorithm`.
+    ///
+    /// Files are hashed in full (no sampling) so that the digests match
+    /// standard checksum tools.
+    pub fn create(root: &Path, algorithm: HashAlgorithm) -> Result<Self> {
+        let mut files = FxHashMap::default();
+        get_dir_files_with_ignore(root, &mut files, &[])?;
+        let mut entries = files
+            .par_iter()
+            .map(|(path, meta)| {
+                Ok(ManifestEntry {
+                    path: path.clone(),
+                    size: Some(meta.len()),
+                    mtime: meta.modified().ok(),
+                    mode: file_mode(meta),
+                    digest: hash_file_full(&root.join(path), algorithm)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Self { algorithm, entries })
+    }
+
+    /// Write the manifest to `path` in `format`.
+    pub fn write(&self, path: &Path, format: ManifestFormat) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        match format {
+            ManifestFormat::Native => {
+                writeln!(writer, "{}{}", HEADER_PREFIX, self.algorithm.as_str())?;
+                for entry in &self.entries {
+                    writeln!(
+                        writer,
+                        "{}\t{}\t{}\t{}\t{}",
+                        entry.size.map_or("-".to_string(), |s| s.to_string()),
+                        entry.mtime.map_or("-".to_string(), format_time),
+                        entry.mode.map_or("-".to_string(), |m| format!("{:o}", m)),
+                        to_hex(&entry.digest),
+                        escape_field(&entry.path)
+                    )?;
+                }
+            }
+            ManifestFormat::Checksums => {
+                for entry in &self.entries {
+                    // Same escaping as GNU coreutils: a leading backslash
+                    // marks a line whose path contains `\` or a newline.
+                    if entry.path.contains(['\\', '\n']) {
+                        let escaped = entry.path.replace('\\', "\\\\").replace('\n', "\\n");
+                        writeln!(writer, "\\{}  {}", to_hex(&entry.digest), escaped)?;
+                    } else {
+                        writeln!(writer, "{}  {}", to_hex(&entry.digest), entry.path)?;
+                    }
+                }
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Read a manifest in either format.
+    ///
+    /// Native manifests name their algorithm. For checksum files it is taken
+    /// from `algorithm`, or guessed from the file name (`*sha256*` or `*b3*`).
+    pub fn read(path: &Path, algorithm: Option<HashAlgorithm>) -> Result<Self> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let bad = |n: usize| {
+            FolderDifferError::Other(format!(
+                "Malformed manifest line {} in {}",
+                n,
+                path.display()
+            ))
+        };
+        let first = lines.next().transpose()?;
+        if let Some(name) = first.as_deref().and_then(|l| l.strip_prefix(HEADER_PREFIX)) {
+            let algorithm = HashAlgorithm::parse(name)?;
+            let mut entries = Vec::new();
+            for (index, line) in lines.enumerate() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                entries.push(parse_native_line(&line).ok_or_else(|| bad(index + 2))?);
+            }
+            return Ok(Self { algorithm, entries });
+        }
+
+        let algorithm = algorithm.or_else(|| guess_algorithm(path)).ok_or_else(|| {
+            FolderDifferError::Other(format!(
+                "Cannot tell the hash algorithm of {}; pass it explicitly",
+                path.display()
+            ))
+        })?;
+        let mut entries = Vec::new();
+        for (index, line) in first.into_iter().map(Ok).chain(lines).enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            entries.push(parse_checksum_line(&line).ok_or_else(|| bad(index + 1))?);
+        }
+        Ok(Self { algorithm, entries })
+    }
+}
+
+fn guess_algorithm(path: &Path) -> Option<HashAlgorithm> {
+    let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+    if name.contains("sha256") {
+        Some(HashAlgorithm::Sha256)
+    } else if name.contains("b3") || name.contains("blake3") {
+        Some(HashAlgorithm::Blake3)
+    } else if name.contains("xxh3") {
+        Some(HashAlgorithm::Xxh3)
+    } else {
+        None
+    }
+}
+
+fn parse_native_line(line: &str) -> Option<ManifestEntry> {
+    let mut fields = line.splitn(5, '\t');
+    let optional = |field: &str| (field != "-").then(|| field.to_string());
+    let size = optional(fields.next()?);
+    let mtime = optional(fields.next()?);
+    let mode = optional(fields.next()?);
+    Some(ManifestEntry {
+        size: match size {
+            Some(size) => Some(size.parse().ok()?),
+            None => None,
+        },
+        mtime: match mtime {
+            Some(mtime) => Some(parse_time(&mtime)?),
+            None => None,
+        },
+        mode: match mode {
+            Some(mode) => Some(u32::from_str_radix(&mode, 8).ok()?),
+            None => None,
+        },
+        digest: from_hex(fields.next()?)?,
+        path: unescape_field(fields.next()?),
+    })
+}
+
+fn parse_checksum_line(line: &str) -> Option<ManifestEntry> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let (digest, path) = line.split_once(' ')?;
+    // Text mode lines have a second space, binary mode lines a `*`.
+    let path = path.strip_prefix([' ', '*']).unwrap_or(path);
+    let path = if escaped {
+        unescape_field(path)
+    } else {
+        path.to_string()
+    };
+    Some(ManifestEntry {
+        path,
+        size: None,
+        mtime: None,
+        mode: None,
+        digest: from_hex(digest)?,
+    })
+}
+
+/// Diff the live directory `root` (left) against `manifest` (right).
+///
+/// Every file present on both sides is hashed and compared by digest. Files
+/// with matching content but different recorded permissions are reported as
+/// [`DiffType::ModeDiffers`].
+pub fn compare_to_manifest(root: &Path, manifest: &Manifest) -> Result<Vec<Diff>> {
+    let mut files = FxHashMap::default();
+    get_dir_files_with_ignore(root, &mut files, &[])?;
+    let recorded: FxHashMap<&str, &ManifestEntry> = manifest
+        .entries
+        .iter()
+        .map(|e| (e.path.as_str(), e))
+        .collect();
+    let all_paths: FxHashSet<&str> = files
+        .keys()
+        .map(String::as_str)
+        .chain(recorded.keys().copied())
+        .collect();
+    let mut diffs: Vec<Diff> = all_paths
+        .par_iter()
+        .map(|path| {
+            let diff_type = match (files.get(*path), recorded.get(path)) {
+                (Some(meta), Some(entry)) => {
+                    compare_file(&root.join(path), meta, entry, manifest.algorithm)?
+                }
+                (Some(_), None) => Some(DiffType::OnlyInLeft),
+                (None, Some(_)) => Some(DiffType::OnlyInRight),
+                (None, None) => None,
+            };
+            Ok(diff_type.map(|diff_type| Diff {
+                path: path.to_string(),
+                diff_type,
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+    diffs.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(diffs)
+}
+
+fn compare_file(
+    path: &Path,
+    meta: &Metadata,
+    entry: &ManifestEntry,
+    algorithm: HashAlgorithm,
+) -> Result<Option<DiffType>> {
+    let different = || DiffType::Different {
+        left_size: meta.len(),
+        right_size: entry.size.unwrap_or(meta.len()),
+        left_time: meta.modified().ok(),
+        right_time: entry.mtime,
+        normalization: None,
+    };
+    if entry.size.is_some_and(|size| size != meta.len()) {
+        return Ok(Some(different()));
+    }
+    if hash_file_full(path, algorithm)? != entry.digest {
+        return Ok(Some(different()));
+    }
+    Ok(match (file_mode(meta), entry.mode) {
+        (Some(left_mode), Some(right_mode)) if left_mode != right_mode => {
+            Some(DiffType::ModeDiffers {
+                left_mode,
+                right_mode,
+            })
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_native_manifest_round_trip_and_verify() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("a.txt"), b"abc\n").unwrap();
+        std::fs::write(dir.path().join("sub/b.txt"), b"tab\there").unwrap();
+        let out = tempdir().unwrap();
+        let manifest_path = out.path().join("tree.manifest");
+
+        let manifest = Manifest::create(dir.path(), HashAlgorithm::Blake3).unwrap();
+        manifest
+            .write(&manifest_path, ManifestFormat::Native)
+            .unwrap();
+        let read = Manifest::read(&manifest_path, None).unwrap();
+        assert_eq!(read, manifest);
+        assert!(compare_to_manifest(dir.path(), &read).unwrap().is_empty());
+
+        std::fs::write(dir.path().join("a.txt"), b"xyz\n").unwrap();
+        std::fs::remove_file(dir.path().join("sub/b.txt")).unwrap();
+        std::fs::write(dir.path().join("new.txt"), b"new").unwrap();
+        let diffs = compare_to_manifest(dir.path(), &read).unwrap();
+        let kinds: Vec<(&str, &str)> = diffs
+            .iter()
+            .map(|d| {
+                let kind = match d.diff_type {
+                    DiffType::Different { .. } => "different",
+                    DiffType::OnlyInLeft => "left",
+                    DiffType::OnlyInRight => "right",
+                    _ => "other",
+                };
+                (d.path.as_str(), kind)
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ("a.txt", "different"),
+                ("new.txt", "left"),
+                ("sub/b.txt", "right")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sha256sum_format() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), b"abc\n").unwrap();
+        let out = tempdir().unwrap();
+        let sums = out.path().join("SHA256SUMS");
+
+        Manifest::create(dir.path(), HashAlgorithm::Sha256)
+            .unwrap()
+            .write(&sums, ManifestFormat::Checksums)
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&sums).unwrap(),
+            "edeaaff3f1774ad2888673770c6d64097e391bc362d7d6fb34982ddf0efd18cb  a.txt\n"
+        );
+        let read = Manifest::read(&sums, None).unwrap();
+        assert_eq!(read.algorithm, HashAlgorithm::Sha256);
+        assert!(compare_to_manifest(dir.path(), &read).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+//! Text normalization for folder-differ
+//!
+//! Checkouts made on different platforms differ in line endings, editors
+//! leave trailing whitespace behind, and generated files carry timestamps.
+//! With a [`TextNormalization`], text files are hashed line by line after
+//! such noise is removed, so only changes that survive it are reported.
+//! Files that look binary are never normalized.
+
+use crate::hash::{HashAlgorithm, read_block};
+use crate::{FolderDifferError, Result};
+use regex::bytes::Regex;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Read};
+use std::path::Path;
+
+/// Bytes inspected for a NUL to tell binary files from text.
+const SNIFF_SIZE: usize = 8192;
+
+/// What to disregard when comparing text files. The default disregards
+/// nothing.
+#[derive(Debug, Clone, Default)]
+pub struct TextNormalization {
+    /// Treat CRLF line endings as LF.
+    pub line_endings: bool,
+    /// Ignore whitespace at the end of each line.
+    pub trailing_whitespace: bool,
+    /// Drop lines matching any of these patterns (matched without the line
+    /// ending).
+    pub ignore_lines: Vec<Regex>,
+}
+
+impl TextNormalization {
+    /// Parse a comma-separated list of `eol` and `whitespace` (or `all`).
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut normalization = Self::default();
+        for item in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match item {
+                "eol" => normalization.line_endings = true,
+                "whitespace" => normalization.trailing_whitespace = true,
+                "all" => {
+                    normalization.line_endings = true;
+                    normalization.trailing_whitespace = true;
+                }
+                other => {
+                    return Err(FolderDifferError::Other(format!(
+                        "Unknown --normalize item: {}",
+                        other
+                    )));
+                }
+            }
+        }
+        Ok(normalization)
+    }
+
+    /// Also drop lines matching `pattern`.
+    pub fn ignore_lines_matching(&mut self, pattern: &str) -> Result<()> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| FolderDifferError::Other(format!("Invalid pattern {}: {}", pattern, e)))?;
+        self.ignore_lines.push(regex);
+        Ok(())
+    }
+
+    /// Whether nothing is normalized.
+    pub fn is_empty(&self) -> bool {
+        !self.line_endings && !self.trailing_whitespace && self.ignore_lines.is_empty()
+    }
+
+    /// What is disregarded, e.g. `line endings, lines matching ^# Generated`.
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if self.line_endings {
+            parts.push("line endings".to_string());
+        }
+        if self.trailing_whitespace {
+            parts.push("trailing whitespace".to_string());
+        }
+        parts.extend(
+            self.ignore_lines
+                .iter()
+                .map(|regex| format!("lines matching {}", regex)),
+        );
+        parts.join(", ")
+    }
+
+    /// `line` (without its `\n`) as it is hashed, or `None` if it is ignored.
+    fn normalize_line<'a>(&self, mut line: &'a [u8]) -> Option<&'a [u8]> {
+        if self.line_endings {
+            line = line.strip_suffix(b"\r").unwrap_or(line);
+        }
+        if self.trailing_whitespace {
+            line = line.trim_ascii_end();
+        }
+        if self.ignore_lines.iter().any(|regex| regex.is_match(line)) {
+            return None;
+        }
+        Some(line)
+    }
+
+    /// Digest of the normalized content of `path`, or `None` if the file
+    /// looks binary (has a NUL byte near the start).
+    ///
+    /// The whole file is read; nothing is sampled or cached.
+    pub fn hash_file(&self, path: &Path, algorithm: HashAlgorithm) -> Result<Option<Vec<u8>>> {
+        let mut file = File::open(path)?;
+        let mut head = vec![0u8; SNIFF_SIZE];
+        let n = read_block(&mut file, &mut head)?;
+        head.truncate(n);
+        if head.contains(&0) {
+            return Ok(None);
+        }
+        let mut reader = BufReader::new(Cursor::new(head).chain(file));
+        let mut hasher = algorithm.hasher();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            let (content, newline) = match line.strip_suffix(b"\n") {
+                Some(content) => (content, true),
+                None => (line.as_slice(), false),
+            };
+            if let Some(content) = self.normalize_line(content) {
+                hasher.update(content);
+                if newline {
+                    hasher.update(b"\n");
+                }
+            }
+        }
+        Ok(Some(hasher.finalize()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::tests::backdate;
+    use crate::diff::{CompareContext, DiffType, compare_dirs_with};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_normalized_comparison() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        std::fs::write(left.path().join("crlf.txt"), "a\r\nb\r\n").unwrap();
+        std::fs::write(right.path().join("crlf.txt"), "a\nb\n").unwrap();
+        std::fs::write(left.path().join("ws.txt"), "a  \nb\t\n").unwrap();
+        std::fs::write(right.path().join("ws.txt"), "a\nb\n").unwrap();
+        std::fs::write(
+            left.path().join("gen.h"),
+            "// Generated 2024-01-01\nint x;\n",
+        )
+        .unwrap();
+        std::fs::write(
+            right.path().join("gen.h"),
+            "// Generated 2025-06-30\nint x;\n",
+        )
+        .unwrap();
+        std::fs::write(left.path().join("real.txt"), "a\r\nb\r\n").unwrap();
+        std::fs::write(right.path().join("real.txt"), "a\nc\n").unwrap();
+        // Binary files are compared as they are.
+        std::fs::write(left.path().join("data.bin"), b"\x00a\r\n").unwrap();
+        std::fs::write(right.path().join("data.bin"), b"\x00a\n").unwrap();
+        backdate(&right.path().join("gen.h"), 0);
+
+        let mut normalization = TextNormalization::parse("eol,whitespace").unwrap();
+        normalization
+            .ignore_lines_matching("^// Generated ")
+            .unwrap();
+        let context = CompareContext {
+            normalization,
+            ..CompareContext::default()
+        };
+        let mut diffs = compare_dirs_with(left.path(), right.path(), &context).unwrap();
+        diffs.sort_by(|a, b| a.path.cmp(&b.path));
+        let paths: Vec<&str> = diffs.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, ["data.bin", "real.txt"]);
+        let notes: Vec<Option<&str>> = diffs
+            .iter()
+            .map(|d| match &d.diff_type {
+                DiffType::Different { normalization, .. } => normalization.as_deref(),
+                _ => panic!("unexpected {:?}", d),
+            })
+            .collect();
+        assert_eq!(
+            notes,
+            [
+                None,
+                Some("line endings, trailing whitespace, lines matching ^// Generated ")
+            ]
+        );
+
+        // Without normalization every pair differs.
+        assert_eq!(
+            compare_dirs_with(left.path(), right.path(), &CompareContext::default())
+                .unwrap()
+                .len(),
+            5
+        );
+    }
+}
@@ -1,7 +1,7 @@
 //! Progress bar and benchmarking utilities for folder-differ
 
-use crate::Result;
-#[cfg(feature = "progress")]
+use crate::{Result, STATE_DIR_NAME};
+#[cfg(all(feature = "progress", feature = "benchmarking"))]
 use indicatif::ProgressBar;
 use std::path::Path;
 use std::sync::atomic::AtomicUsize;
@@ -73,6 +73,9 @@ pub fn count_files_dirs(
         let entries: Vec<_> = entries.filter_map(|e| e.ok()).collect();
         rayon::scope(|s| {
             for entry in &entries {
+                if entry.file_name() == STATE_DIR_NAME {
+                    continue;
+                }
                 let path = entry.path();
                 if path.is_dir() {
                     dir_count.fetch_add(1, Ordering::SeqCst);
@@ -0,0 +1,246 @@
+//! N-way replica comparison for folder-differ
+//!
+//! Several copies of one dataset are scanned together and, for every path on
+//! which they disagree, the replicas are grouped by the content they hold.
+//! Each path is compared once across all replicas instead of pairwise.
+
+use crate::hash::{HashAlgorithm, InodeHashes, hash_file_with};
+use crate::{Result, get_dir_files_with_ignore};
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::fs::Metadata;
+use std::path::PathBuf;
+
+/// One content of a path, and the replicas holding it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentVersion {
+    pub size: u64,
+    /// Digest of the content; `None` when the version was told apart by size
+    /// and mtime alone, without hashing.
+    pub digest: Option<Vec<u8>>,
+    /// Indexes into the list of replica roots, in ascending order.
+    pub replicas: Vec<usize>,
+}
+
+/// A path on which the replicas disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicaDiff {
+    pub path: String,
+    /// Distinct contents, most widely held first.
+    pub versions: Vec<ContentVersion>,
+    /// Replicas that do not have the path.
+    pub missing: Vec<usize>,
+}
+
+impl ReplicaDiff {
+    fn replica_count(&self) -> usize {
+        self.missing.len()
+            + self
+                .versions
+                .iter()
+                .map(|v| v.replicas.len())
+                .sum::<usize>()
+    }
+
+    /// The replicas in the majority: those holding the content (or lacking
+    /// the path) that more than half of all replicas agree on.
+    fn majority_replicas(&self) -> Option<&[usize]> {
+        let half = self.replica_count() / 2;
+        self.versions
+            .iter()
+            .map(|v| v.replicas.as_slice())
+            .chain([self.missing.as_slice()])
+            .find(|replicas| replicas.len() > half)
+    }
+
+    /// The version held by more than half of the replicas, if any.
+    ///
+    /// `None` also when the majority lacks the path; see [`Self::outliers`].
+    pub fn majority(&self) -> Option<&ContentVersion> {
+        let majority = self.majority_replicas()?;
+        self.versions
+            .iter()
+            .find(|v| v.replicas.as_slice() == majority)
+    }
+
+    /// The replicas that disagree with the majority, or `None` when there is
+    /// no majority.
+    pub fn outliers(&self) -> Option<Vec<usize>> {
+        let majority = self.majority_replicas()?;
+        let mut outliers: Vec<usize> = self
+            .versions
+            .iter()
+            .flat_map(|v| &v.replicas)
+            .chain(&self.missing)
+            .filter(|replica| !majority.contains(replica))
+            .copied()
+            .collect();
+        outliers.sort_unstable();
+        Some(outliers)
+    }
+}
+
+/// Split `holders` (replica index and metadata of the file it holds) into
+/// distinct contents.
+///
+/// Files of different size differ. Within a size, files that all share one
+/// mtime are taken as identical, like the two-way comparison does; otherwise
+/// each is hashed.
+fn split_versions(
+    path: &str,
+    roots: &[PathBuf],
+    holders: Vec<(usize, &Metadata)>,
+    algorithm: HashAlgorithm,
+    inode_hashes: &InodeHashes,
+) -> Result<Vec<ContentVersion>> {
+    let mut by_size: FxHashMap<u64, Vec<(usize, &Metadata)>> = FxHashMap::default();
+    for (replica, meta) in holders {
+        by_size.entry(meta.len()).or_default().push((replica, meta));
+    }
+    let mut versions = Vec::new();
+    for (size, group) in by_size {
+        let first_time = group[0].1.modified().ok();
+        if group
+            .iter()
+            .all(|(_, meta)| meta.modified().ok() == first_time)
+        {
+            versions.push(ContentVersion {
+                size,
+                digest: None,
+                replicas: group.iter().map(|(replica, _)| *replica).collect(),
+            });
+            continue;
+        }
+        let mut by_digest: Vec<ContentVersion> = Vec::new();
+        for (replica, meta) in group {
+            let digest = inode_hashes.get_or_hash(meta, || {
+                hash_file_with(&roots[replica].join(path), algorithm)
+            })?;
+            match by_digest
+                .iter_mut()
+                .find(|v| v.digest.as_ref() == Some(&digest))
+            {
+                Some(version) => version.replicas.push(replica),
+                None => by_digest.push(ContentVersion {
+                    size,
+                    digest: Some(digest),
+                    replicas: vec![replica],
+                }),
+            }
+        }
+        versions.extend(by_digest);
+    }
+    for version in &mut versions {
+        version.replicas.sort_unstable();
+    }
+    versions.sort_by(|a, b| {
+        b.replicas
+            .len()
+            .cmp(&a.replicas.len())
+            .then(a.replicas[0].cmp(&b.replicas[0]))
+    });
+    Ok(versions)
+}
+
+/// Compare all `roots` at once and report every path they disagree on,
+/// sorted by path.
+pub fn compare_replicas(roots: &[PathBuf], algorithm: HashAlgorithm) -> Result<Vec<ReplicaDiff>> {
+    let files = roots
+        .par_iter()
+        .map(|root| {
+            let mut files = FxHashMap::default();
+            get_dir_files_with_ignore(root, &mut files, &[])?;
+            Ok(files)
+        })
+        .collect::<Result<Vec<FxHashMap<String, Metadata>>>>()?;
+    let all_paths: FxHashSet<&String> = files.iter().flat_map(|f| f.keys()).collect();
+    let inode_hashes = InodeHashes::default();
+    let mut diffs = all_paths
+        .par_iter()
+        .map(|path| {
+            let mut holders = Vec::new();
+            let mut missing = Vec::new();
+            for (replica, replica_files) in files.iter().enumerate() {
+                match replica_files.get(*path) {
+                    Some(meta) => holders.push((replica, meta)),
+                    None => missing.push(replica),
+                }
+            }
+            let versions = split_versions(path, roots, holders, algorithm, &inode_hashes)?;
+            Ok(
+                (versions.len() > 1 || !missing.is_empty()).then(|| ReplicaDiff {
+                    path: (*path).clone(),
+                    versions,
+                    missing,
+                }),
+            )
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    diffs.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(diffs)
+}
+
+/// Number of paths on which each replica is an outlier, indexed like `roots`.
+pub fn outlier_counts(diffs: &[ReplicaDiff], replica_count: usize) -> Vec<usize> {
+    let mut counts = vec![0; replica_count];
+    for replica in diffs.iter().filter_map(ReplicaDiff::outliers).flatten() {
+        counts[replica] += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::tests::backdate;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_compare_replicas_majority_and_outliers() {
+        let dirs: Vec<_> = (0..5).map(|_| tempdir().unwrap()).collect();
+        let roots: Vec<PathBuf> = dirs.iter().map(|d| d.path().to_path_buf()).collect();
+        for root in &roots {
+            std::fs::write(root.join("same.csv"), b"a,b\n").unwrap();
+            std::fs::write(root.join("drift.csv"), b"v1\n").unwrap();
+            std::fs::write(root.join("split.csv"), b"one\n").unwrap();
+        }
+        std::fs::write(roots[3].join("drift.csv"), b"v2\n").unwrap();
+        std::fs::write(roots[0].join("split.csv"), b"two\n").unwrap();
+        std::fs::write(roots[1].join("split.csv"), b"two\n").unwrap();
+        std::fs::write(roots[2].join("split.csv"), b"three\n").unwrap();
+        std::fs::remove_file(roots[4].join("split.csv")).unwrap();
+        std::fs::write(roots[2].join("stray.tmp"), b"x").unwrap();
+        for (index, root) in roots.iter().enumerate() {
+            for name in ["drift.csv", "split.csv"] {
+                if root.join(name).exists() {
+                    backdate(&root.join(name), 1_000 + index as u64);
+                }
+            }
+        }
+
+        let diffs = compare_replicas(&roots, HashAlgorithm::Blake3).unwrap();
+        let paths: Vec<&str> = diffs.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, ["drift.csv", "split.csv", "stray.tmp"]);
+
+        let drift = &diffs[0];
+        assert_eq!(drift.versions.len(), 2);
+        assert_eq!(drift.majority().unwrap().replicas, [0, 1, 2, 4]);
+        assert_eq!(drift.outliers(), Some(vec![3]));
+
+        let split = &diffs[1];
+        assert_eq!(split.versions[0].replicas, [0, 1]);
+        assert_eq!(split.missing, [4]);
+        assert_eq!(split.majority(), None);
+        assert_eq!(split.outliers(), None);
+
+        // Most replicas lack the path: the one holding it is the outlier.
+        let stray = &diffs[2];
+        assert_eq!(stray.majority(), None);
+        assert_eq!(stray.outliers(), Some(vec![2]));
+
+        assert_eq!(outlier_counts(&diffs, roots.len()), [0, 0, 1, 1, 0]);
+    }
+}
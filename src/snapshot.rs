@@ -0,0 +1,613 @@
+//! Scan snapshots for incremental re-diffs
+//!
+//! After a comparison, the scanned metadata of both trees and the verdict for
+//! every path are saved in `<left>/.folder-differ/snapshot`. The next run
+//! re-reads only the directories whose mtime changed since then; files in
+//! untouched directories keep their recorded metadata and verdict without
+//! being stat'ed or hashed again.
+//!
+//! A directory's mtime changes when entries are created, deleted or renamed
+//! in it, not when an existing file is rewritten in place, so such edits are
+//! only picked up by a full comparison.
+//!
+//! The file is tab-separated text: the right tree's root, then one line per
+//! directory (`D side mtime_ns path`), file
+//! (`F side size mtime_ns allocated path`) and differing path
+//! (`V kind note path`, where `note` is the normalization a `different`
+//! verdict was reached under, or empty).
+
+use crate::cache::mtime_ns;
+use crate::diff::{CompareContext, Diff, DiffType, compare_entry};
+use crate::journal::{escape_field, unescape_field};
+use crate::sparse::allocated_bytes;
+use crate::{FolderDifferError, Result, STATE_DIR_NAME, join_rel};
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::fs::{File, Metadata};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// File name of the snapshot inside the left tree's state directory.
+pub const SNAPSHOT_FILE_NAME: &str = "snapshot";
+
+const HEADER: &str = "# folder-differ snapshot v2";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileRecord {
+    size: u64,
+    mtime_ns: i64,
+    allocated: u64,
+}
+
+impl FileRecord {
+    fn from_meta(meta: &Metadata) -> Self {
+        Self {
+            size: meta.len(),
+            mtime_ns: mtime_ns(meta),
+            allocated: allocated_bytes(meta),
+        }
+    }
+
+    fn mtime(&self) -> Option<SystemTime> {
+        u64::try_from(self.mtime_ns)
+            .ok()
+            .map(|ns| UNIX_EPOCH + Duration::from_nanos(ns))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct DirRecord {
+    mtime_ns: i64,
+    /// Names of the files and subdirectories directly inside.
+    files: Vec<String>,
+    subdirs: Vec<String>,
+}
+
+/// Scanned metadata of one tree.
+#[derive(Debug, Clone, Default)]
+pub struct TreeSnapshot {
+    dirs: FxHashMap<String, DirRecord>,
+    files: FxHashMap<String, FileRecord>,
+}
+
+impl TreeSnapshot {
+    /// Number of files recorded.
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+}
+
+/// How a path present in both trees differed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Verdict {
+    Different { normalization: Option<String> },
+    AllocationDiffers,
+    TimeDiffers,
+}
+
+impl Verdict {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Verdict::Different { .. } => "different",
+            Verdict::AllocationDiffers => "allocation",
+            Verdict::TimeDiffers => "time",
+        }
+    }
+
+    /// The note recorded with the verdict, empty if there is none.
+    fn note(&self) -> &str {
+        match self {
+            Verdict::Different {
+                normalization: Some(note),
+            } => note,
+            _ => "",
+        }
+    }
+
+    fn parse(kind: &str, note: &str) -> Option<Self> {
+        match kind {
+            "different" => Some(Verdict::Different {
+                normalization: (!note.is_empty()).then(|| note.to_string()),
+            }),
+            "allocation" => Some(Verdict::AllocationDiffers),
+            "time" => Some(Verdict::TimeDiffers),
+            _ => None,
+        }
+    }
+}
+
+/// The result of one comparison, to be reused by the next.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    right_root: PathBuf,
+    pub left: TreeSnapshot,
+    pub right: TreeSnapshot,
+    verdicts: FxHashMap<String, Verdict>,
+}
+
+/// Work done (and saved) by an incremental comparison.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IncrementalStats {
+    pub dirs_reread: usize,
+    pub dirs_reused: usize,
+    pub files_compared: usize,
+    pub files_reused: usize,
+}
+
+/// Output of [`compare_incremental`].
+#[derive(Debug)]
+pub struct IncrementalDiff {
+    /// Diffs sorted by path.
+    pub diffs: Vec<Diff>,
+    /// Snapshot of this comparison, for the next run.
+    pub snapshot: Snapshot,
+    pub stats: IncrementalStats,
+}
+
+fn split_rel(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+fn canonical(root: &Path) -> PathBuf {
+    root.canonicalize().unwrap_or_else(|_| root.to_path_buf())
+}
+
+impl Snapshot {
+    /// Location of the snapshot for comparisons whose left tree is `left`.
+    pub fn path_for(left: &Path) -> PathBuf {
+        left.join(STATE_DIR_NAME).join(SNAPSHOT_FILE_NAME)
+    }
+
+    /// Load the snapshot of the last comparison of `left` against `right`.
+    ///
+    /// Gives `None` if there is none, or if it was taken against another
+    /// right tree or in another format.
+    pub fn load(left: &Path, right: &Path) -> Result<Option<Self>> {
+        let path = Self::path_for(left);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = BufReader::new(file).lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            log::debug!("Discarding snapshot {} in an old format", path.display());
+            return Ok(None);
+        }
+        let mut snapshot = Snapshot::default();
+        for (index, line) in lines.enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            snapshot.parse_line(&line).ok_or_else(|| {
+                FolderDifferError::Other(format!(
+                    "Malformed snapshot line {} in {}",
+                    index + 2,
+                    path.display()
+                ))
+            })?;
+        }
+        if snapshot.right_root != canonical(right) {
+            log::debug!(
+                "Ignoring snapshot {}: it was taken against {}",
+                path.display(),
+                snapshot.right_root.display()
+            );
+            return Ok(None);
+        }
+        // Rebuild each directory's list of children from the paths.
+        for tree in [&mut snapshot.left, &mut snapshot.right] {
+            let subdirs: Vec<String> = tree
+                .dirs
+                .keys()
+                .filter(|d| !d.is_empty())
+                .cloned()
+                .collect();
+            for dir in subdirs {
+                let (parent, name) = split_rel(&dir);
+                if let Some(record) = tree.dirs.get_mut(parent) {
+                    record.subdirs.push(name.to_string());
+                }
+            }
+            for path in tree.files.keys() {
+                let (parent, name) = split_rel(path);
+                if let Some(record) = tree.dirs.get_mut(parent) {
+                    record.files.push(name.to_string());
+                }
+            }
+        }
+        Ok(Some(snapshot))
+    }
+
+    fn parse_line(&mut self, line: &str) -> Option<()> {
+        let mut fields = line.split('\t');
+        match fields.next()? {
+            "R" => self.right_root = PathBuf::from(unescape_field(fields.next()?)),
+            "D" => {
+                let side = fields.next()?;
+                let mtime_ns = fields.next()?.parse().ok()?;
+                let path = unescape_field(fields.next()?);
+                let record = DirRecord {
+                    mtime_ns,
+                    ..DirRecord::default()
+                };
+                self.side_mut(side)?.dirs.insert(path, record);
+            }
+            "F" => {
+                let side = fields.next()?;
+                let record = FileRecord {
+                    size: fields.next()?.parse().ok()?,
+                    mtime_ns: fields.next()?.parse().ok()?,
+                    allocated: fields.next()?.parse().ok()?,
+                };
+                let path = unescape_field(fields.next()?);
+                self.side_mut(side)?.files.insert(path, record);
+            }
+            "V" => {
+                let kind = fields.next()?;
+                let verdict = Verdict::parse(kind, &unescape_field(fields.next()?))?;
+                self.verdicts
+                    .insert(unescape_field(fields.next()?), verdict);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn side_mut(&mut self, side: &str) -> Option<&mut TreeSnapshot> {
+        match side {
+            "L" => Some(&mut self.left),
+            "R" => Some(&mut self.right),
+            _ => None,
+        }
+    }
+
+    /// Write the snapshot into the state directory of `left`, atomically.
+    pub fn save(&self, left: &Path) -> Result<()> {
+        let path = Self::path_for(left);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writeln!(writer, "{}", HEADER)?;
+        writeln!(
+            writer,
+            "R\t{}",
+            escape_field(&self.right_root.to_string_lossy())
+        )?;
+        for (side, tree) in [("L", &self.left), ("R", &self.right)] {
+            for (dir, record) in &tree.dirs {
+                writeln!(
+                    writer,
+                    "D\t{}\t{}\t{}",
+                    side,
+                    record.mtime_ns,
+                    escape_field(dir)
+                )?;
+            }
+            for (file, record) in &tree.files {
+                writeln!(
+                    writer,
+                    "F\t{}\t{}\t{}\t{}\t{}",
+                    side,
+                    record.size,
+                    record.mtime_ns,
+                    record.allocated,
+                    escape_field(file)
+                )?;
+            }
+        }
+        for (path, verdict) in &self.verdicts {
+            writeln!(
+                writer,
+                "V\t{}\t{}\t{}",
+                verdict.as_str(),
+                escape_field(verdict.note()),
+                escape_field(path)
+            )?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_data()?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// A tree scanned against its previous snapshot.
+#[derive(Default)]
+struct Scan {
+    tree: TreeSnapshot,
+    /// Metadata of the files in re-read directories.
+    fresh: FxHashMap<String, Metadata>,
+    dirs_reread: usize,
+    dirs_reused: usize,
+}
+
+fn scan_tree(root: &Path, previous: Option<&TreeSnapshot>) -> Result<Scan> {
+    let mut scan = Scan::default();
+    scan_dir(root, "", previous, &mut scan)?;
+    Ok(scan)
+}
+
+fn scan_dir(
+    root: &Path,
+    rel: &str,
+    previous: Option<&TreeSnapshot>,
+    scan: &mut Scan,
+) -> Result<()> {
+    let dir = root.join(rel);
+    let meta = match std::fs::metadata(&dir) {
+        Ok(meta) => meta,
+        // Removed since its parent was listed.
+        Err(e) if e.kind() == ErrorKind::NotFound && !rel.is_empty() => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mtime = mtime_ns(&meta);
+    if let Some(previous) = previous
+        && let Some(record) = previous.dirs.get(rel).filter(|r| r.mtime_ns == mtime)
+    {
+        scan.dirs_reused += 1;
+        for name in &record.files {
+            let path = join_rel(rel, name);
+            if let Some(file) = previous.files.get(&path) {
+                scan.tree.files.insert(path, *file);
+            }
+        }
+        for name in &record.subdirs {
+            scan_dir(root, &join_rel(rel, name), Some(previous), scan)?;
+        }
+        scan.tree.dirs.insert(rel.to_string(), record.clone());
+        return Ok(());
+    }
+
+    scan.dirs_reread += 1;
+    let mut record = DirRecord {
+        mtime_ns: mtime,
+        ..DirRecord::default()
+    };
+    for entry in std::fs::read_dir(&dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Hidden entries (and the state directory) are skipped, as in a full scan.
+        if name.starts_with('.') {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            record.subdirs.push(name);
+        } else if file_type.is_file() {
+            let meta = entry.metadata()?;
+            let path = join_rel(rel, &name);
+            scan.tree
+                .files
+                .insert(path.clone(), FileRecord::from_meta(&meta));
+            scan.fresh.insert(path, meta);
+            record.files.push(name);
+        }
+    }
+    for name in &record.subdirs {
+        scan_dir(root, &join_rel(rel, name), previous, scan)?;
+    }
+    scan.tree.dirs.insert(rel.to_string(), record);
+    Ok(())
+}
+
+fn stat_file(root: &Path, path: &str, fresh: &FxHashMap<String, Metadata>) -> Result<Metadata> {
+    match fresh.get(path) {
+        Some(meta) => Ok(meta.clone()),
+        None => Ok(std::fs::metadata(root.join(path))?),
+    }
+}
+
+/// Compare `left` and `right`, reusing what `previous` recorded.
+///
+/// Paths whose directory is unchanged on both sides keep their previous
+/// verdict; every other path present in both trees is compared with
+/// [`compare_entry`]. Without a previous snapshot this is a full comparison.
+/// Hardlink grouping is not checked.
+pub fn compare_incremental(
+    left: &Path,
+    right: &Path,
+    previous: Option<&Snapshot>,
+    context: &CompareContext,
+) -> Result<IncrementalDiff> {
+    let (left_scan, right_scan) = rayon::join(
+        || scan_tree(left, previous.map(|p| &p.left)),
+        || scan_tree(right, previous.map(|p| &p.right)),
+    );
+    let (left_scan, right_scan) = (left_scan?, right_scan?);
+    let all_paths: FxHashSet<&String> = left_scan
+        .tree
+        .files
+        .keys()
+        .chain(right_scan.tree.files.keys())
+        .collect();
+
+    let results: Vec<(Option<Diff>, bool)> = all_paths
+        .par_iter()
+        .map(|path| {
+            let (left_record, right_record) = match (
+                left_scan.tree.files.get(*path),
+                right_scan.tree.files.get(*path),
+            ) {
+                (Some(l), Some(r)) => (l, r),
+                (Some(_), None) => {
+                    return Ok((
+                        Some(Diff {
+                            path: (*path).clone(),
+                            diff_type: DiffType::OnlyInLeft,
+                        }),
+                        false,
+                    ));
+                }
+                (None, Some(_)) => {
+                    return Ok((
+                        Some(Diff {
+                            path: (*path).clone(),
+                            diff_type: DiffType::OnlyInRight,
+                        }),
+                        false,
+                    ));
+                }
+                (None, None) => return Ok((None, false)),
+            };
+            let dirty = left_scan.fresh.contains_key(*path) || right_scan.fresh.contains_key(*path);
+            if let Some(previous) = previous.filter(|_| !dirty) {
+                let diff_type = match previous.verdicts.get(*path) {
+                    None => return Ok((None, false)),
+                    Some(Verdict::Different { normalization }) => DiffType::Different {
+                        left_size: left_record.size,
+                        right_size: right_record.size,
+                        left_time: left_record.mtime(),
+                        right_time: right_record.mtime(),
+                        normalization: normalization.clone(),
+                    },
+                    Some(Verdict::AllocationDiffers) => DiffType::AllocationDiffers {
+                        size: left_record.size,
+                        left_allocated: left_record.allocated,
+                        right_allocated: right_record.allocated,
+                    },
+                    Some(Verdict::TimeDiffers) => DiffType::TimeDiffers {
+                        size: left_record.size,
+                        left_time: left_record.mtime(),
+                        right_time: right_record.mtime(),
+                    },
+                };
+                return Ok((
+                    Some(Diff {
+                        path: (*path).clone(),
+                        diff_type,
+                    }),
+                    false,
+                ));
+            }
+            let left_meta = stat_file(left, path, &left_scan.fresh)?;
+            let right_meta = stat_file(right, path, &right_scan.fresh)?;
+            let diff = compare_entry(path, left, right, &left_meta, &right_meta, context)?;
+            Ok((diff, true))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut stats = IncrementalStats {
+        dirs_reread: left_scan.dirs_reread + right_scan.dirs_reread,
+        dirs_reused: left_scan.dirs_reused + right_scan.dirs_reused,
+        ..IncrementalStats::default()
+    };
+    let in_both = left_scan
+        .tree
+        .files
+        .keys()
+        .filter(|path| right_scan.tree.files.contains_key(*path))
+        .count();
+    stats.files_compared = results.iter().filter(|(_, compared)| *compared).count();
+    stats.files_reused = in_both - stats.files_compared;
+
+    let mut diffs: Vec<Diff> = results.into_iter().filter_map(|(diff, _)| diff).collect();
+    diffs.sort_by(|a, b| a.path.cmp(&b.path));
+    let verdicts = diffs
+        .iter()
+        .filter_map(|diff| {
+            let verdict = match &diff.diff_type {
+                DiffType::Different { normalization, .. } => Verdict::Different {
+                    normalization: normalization.clone(),
+                },
+                DiffType::AllocationDiffers { .. } => Verdict::AllocationDiffers,
+                DiffType::TimeDiffers { .. } => Verdict::TimeDiffers,
+                _ => return None,
+            };
+            Some((diff.path.clone(), verdict))
+        })
+        .collect();
+    let snapshot = Snapshot {
+        right_root: canonical(right),
+        left: left_scan.tree,
+        right: right_scan.tree,
+        verdicts,
+    };
+    Ok(IncrementalDiff {
+        diffs,
+        snapshot,
+        stats,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::HashAlgorithm;
+    use crate::normalize::TextNormalization;
+    use tempfile::tempdir;
+
+    fn paths(diffs: &[Diff]) -> Vec<&str> {
+        diffs.iter().map(|d| d.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_incremental_rediff() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        for root in [left.path(), right.path()] {
+            std::fs::create_dir_all(root.join("static")).unwrap();
+            std::fs::create_dir_all(root.join("busy")).unwrap();
+            std::fs::write(root.join("static/a.txt"), b"same").unwrap();
+            std::fs::write(root.join("busy/b.txt"), b"same").unwrap();
+        }
+        std::fs::write(right.path().join("static/c.txt"), b"right").unwrap();
+        std::fs::write(left.path().join("static/c.txt"), b"left side").unwrap();
+        // As after an earlier run, so saving the snapshot leaves the root's mtime alone.
+        std::fs::create_dir(left.path().join(STATE_DIR_NAME)).unwrap();
+        let context = CompareContext::new(HashAlgorithm::Blake3);
+
+        let first = compare_incremental(left.path(), right.path(), None, &context).unwrap();
+        assert_eq!(paths(&first.diffs), ["static/c.txt"]);
+        first.snapshot.save(left.path()).unwrap();
+        let previous = Snapshot::load(left.path(), right.path()).unwrap().unwrap();
+        assert_eq!(previous.left.file_count(), 3);
+        assert!(Snapshot::load(left.path(), left.path()).unwrap().is_none());
+
+        std::fs::write(left.path().join("busy/new.txt"), b"new").unwrap();
+        let second =
+            compare_incremental(left.path(), right.path(), Some(&previous), &context).unwrap();
+        assert_eq!(paths(&second.diffs), ["busy/new.txt", "static/c.txt"]);
+        assert!(matches!(
+            second.diffs[1].diff_type,
+            DiffType::Different { .. }
+        ));
+        // Only the left `busy` directory was read again; `static/c.txt`
+        // kept its verdict without being compared.
+        assert_eq!(second.stats.dirs_reread, 1);
+        assert_eq!(second.stats.files_compared, 1);
+        assert_eq!(second.stats.files_reused, 2);
+    }
+
+    #[test]
+    fn test_reused_verdict_keeps_normalization() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        std::fs::write(left.path().join("a.txt"), "one\r\ntwo\r\n").unwrap();
+        std::fs::write(right.path().join("a.txt"), "one\nthree!\n").unwrap();
+        std::fs::create_dir(left.path().join(STATE_DIR_NAME)).unwrap();
+        let mut context = CompareContext::new(HashAlgorithm::Blake3);
+        context.normalization = TextNormalization::parse("eol").unwrap();
+
+        let first = compare_incremental(left.path(), right.path(), None, &context).unwrap();
+        first.snapshot.save(left.path()).unwrap();
+        let previous = Snapshot::load(left.path(), right.path()).unwrap().unwrap();
+        let second =
+            compare_incremental(left.path(), right.path(), Some(&previous), &context).unwrap();
+        assert_eq!(second.stats.files_reused, 1);
+        assert_eq!(second.diffs, first.diffs);
+        assert!(matches!(
+            &second.diffs[0].diff_type,
+            DiffType::Different {
+                normalization: Some(_),
+                ..
+            }
+        ));
+    }
+}
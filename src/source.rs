@@ -0,0 +1,339 @@
+//! Entry sources for folder-differ
+//!
+//! An [`EntrySource`] yields the regular files of a tree one at a time, each
+//! with a reader over its content, so that a side of a comparison does not
+//! have to be a directory: an archive can be compared while it is streamed,
+//! without extracting it.
+
+use crate::diff::{Diff, DiffType};
+use crate::hash::{HashAlgorithm, hash_file_full, hash_reader};
+use crate::sync::Side;
+use crate::{FolderDifferError, Result, STATE_DIR_NAME};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::fs::{File, Metadata};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// A regular file provided by an [`EntrySource`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceEntry {
+    /// Path relative to the root of the source, `/`-separated.
+    pub path: String,
+    pub size: u64,
+    pub mtime: Option<SystemTime>,
+    /// Permission bits, where the source records them.
+    pub mode: Option<u32>,
+}
+
+/// Something that can list the files of a tree along with their content.
+pub trait EntrySource {
+    /// Call `visit` once for every regular file, in the source's order.
+    ///
+    /// The reader is only valid during the call; content that `visit` does
+    /// not read is skipped.
+    fn visit(
+        &mut self,
+        visit: &mut dyn FnMut(&SourceEntry, &mut dyn Read) -> Result<()>,
+    ) -> Result<()>;
+}
+
+/// A directory on disk as an [`EntrySource`].
+#[derive(Debug, Clone)]
+pub struct DirSource {
+    root: PathBuf,
+}
+
+impl DirSource {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+}
+
+/// All regular files under `root` by relative path, hidden and
+/// gitignored ones included, since archive members are never filtered
+/// either.
+fn dir_files(root: &Path) -> Result<FxHashMap<String, Metadata>> {
+    let mut files = FxHashMap::default();
+    let walker = ignore::WalkBuilder::new(root)
+        .standard_filters(false)
+        .filter_entry(|entry| entry.file_name() != STATE_DIR_NAME)
+        .build();
+    for result in walker {
+        let entry = result?;
+        if entry.file_type().is_some_and(|t| t.is_file())
+            && let Ok(rel_path) = entry.path().strip_prefix(root)
+        {
+            files.insert(rel_path.to_string_lossy().to_string(), entry.metadata()?);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(unix)]
+pub(crate) fn file_mode(meta: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn file_mode(_meta: &Metadata) -> Option<u32> {
+    None
+}
+
+impl EntrySource for DirSource {
+    fn visit(
+        &mut self,
+        visit: &mut dyn FnMut(&SourceEntry, &mut dyn Read) -> Result<()>,
+    ) -> Result<()> {
+        let mut files: Vec<(String, Metadata)> = dir_files(&self.root)?.into_iter().collect();
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        for (path, meta) in files {
+            let entry = SourceEntry {
+                path,
+                size: meta.len(),
+                mtime: meta.modified().ok(),
+                mode: file_mode(&meta),
+            };
+            let mut file = File::open(self.root.join(&entry.path))?;
+            visit(&entry, &mut file)?;
+        }
+        Ok(())
+    }
+}
+
+/// True if `path` names an archive that [`open_source`] can read.
+pub fn is_archive_path(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    [".tar", ".tar.gz", ".tgz", ".tar.zst", ".tzst", ".zip"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+/// Open `path` as an entry source: a directory, or an archive by extension.
+pub fn open_source(path: &Path) -> Result<Box<dyn EntrySource>> {
+    if path.is_dir() {
+        return Ok(Box::new(DirSource::new(path)));
+    }
+    if !is_archive_path(path) {
+        return Err(FolderDifferError::Other(format!(
+            "{} is neither a directory nor a supported archive",
+            path.display()
+        )));
+    }
+    #[cfg(feature = "archive")]
+    {
+        Ok(Box::new(crate::archive::ArchiveSource::open(path)?))
+    }
+    #[cfg(not(feature = "archive"))]
+    {
+        Err(FolderDifferError::Other(format!(
+            "Cannot read {}: built without archive support",
+            path.display()
+        )))
+    }
+}
+
+/// Normalize a member path to be relative to the archive root.
+///
+/// Leading `/` and `.` components are dropped; paths that are empty or
+/// escape the root with `..` give `None`.
+pub fn relative_member_path(path: &Path) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy()),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+            Component::ParentDir => return None,
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+/// Diff the directory `dir` against `source`, which sits on `source_side`.
+///
+/// The source is read once, in order. Each of its files that also exists in
+/// `dir` with the same size is hashed while streaming and compared to the
+/// digest of the file on disk. Diffs are sorted by path.
+pub fn compare_dir_to_source(
+    dir: &Path,
+    source: &mut dyn EntrySource,
+    source_side: Side,
+    algorithm: HashAlgorithm,
+) -> Result<Vec<Diff>> {
+    let files = dir_files(dir)?;
+    let only_in = |side: Side| match side {
+        Side::Left => DiffType::OnlyInLeft,
+        Side::Right => DiffType::OnlyInRight,
+    };
+    let mut seen = FxHashSet::default();
+    let mut diffs = Vec::new();
+    source.visit(&mut |entry, content| {
+        let Some(meta) = files.get(&entry.path) else {
+            diffs.push(Diff {
+                path: entry.path.clone(),
+                diff_type: only_in(source_side),
+            });
+            return Ok(());
+        };
+        seen.insert(entry.path.clone());
+        let same = meta.len() == entry.size
+            && hash_reader(content, algorithm)?
+                == hash_file_full(&dir.join(&entry.path), algorithm)?;
+        if !same {
+            let dir_side = (meta.len(), meta.modified().ok());
+            let source_side_info = (entry.size, entry.mtime);
+            let ((left_size, left_time), (right_size, right_time)) = match source_side {
+                Side::Left => (source_side_info, dir_side),
+                Side::Right => (dir_side, source_side_info),
+            };
+            diffs.push(Diff {
+                path: entry.path.clone(),
+                diff_type: DiffType::Different {
+                    left_size,
+                    right_size,
+                    left_time,
+                    right_time,
+                    normalization: None,
+                },
+            });
+        }
+        Ok(())
+    })?;
+    diffs.extend(
+        files
+            .keys()
+            .filter(|path| !seen.contains(*path))
+            .map(|path| Diff {
+                path: path.clone(),
+                diff_type: only_in(source_side.other()),
+            }),
+    );
+    diffs.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(diffs)
+}
+
+/// Diff two entry sources, e.g. two releases of an archive.
+///
+/// Each source is read once: every file of `left` is hashed and remembered,
+/// then `right` is streamed and checked against it. Files with the same
+/// content whose recorded permissions differ are reported as
+/// [`DiffType::ModeDiffers`]; sizes, mtimes and modes come from the sources'
+/// own metadata (the archive headers). Diffs are sorted by path.
+pub fn compare_sources(
+    left: &mut dyn EntrySource,
+    right: &mut dyn EntrySource,
+    algorithm: HashAlgorithm,
+) -> Result<Vec<Diff>> {
+    let mut left_entries: FxHashMap<String, (SourceEntry, Vec<u8>)> = FxHashMap::default();
+    left.visit(&mut |entry, content| {
+        let digest = hash_reader(content, algorithm)?;
+        left_entries.insert(entry.path.clone(), (entry.clone(), digest));
+        Ok(())
+    })?;
+    let mut diffs = Vec::new();
+    right.visit(&mut |entry, content| {
+        let Some((left_entry, left_digest)) = left_entries.remove(&entry.path) else {
+            diffs.push(Diff {
+                path: entry.path.clone(),
+                diff_type: DiffType::OnlyInRight,
+            });
+            return Ok(());
+        };
+        let diff_type =
+            if left_entry.size != entry.size || hash_reader(content, algorithm)? != left_digest {
+                Some(DiffType::Different {
+                    left_size: left_entry.size,
+                    right_size: entry.size,
+                    left_time: left_entry.mtime,
+                    right_time: entry.mtime,
+                    normalization: None,
+                })
+            } else {
+                match (left_entry.mode, entry.mode) {
+                    (Some(left_mode), Some(right_mode)) if left_mode != right_mode => {
+                        Some(DiffType::ModeDiffers {
+                            left_mode,
+                            right_mode,
+                        })
+                    }
+                    _ => None,
+                }
+            };
+        diffs.extend(diff_type.map(|diff_type| Diff {
+            path: entry.path.clone(),
+            diff_type,
+        }));
+        Ok(())
+    })?;
+    diffs.extend(left_entries.into_keys().map(|path| Diff {
+        path,
+        diff_type: DiffType::OnlyInLeft,
+    }));
+    diffs.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_relative_member_path() {
+        assert_eq!(
+            relative_member_path(Path::new("./app/bin/run")),
+            Some("app/bin/run".to_string())
+        );
+        assert_eq!(
+            relative_member_path(Path::new("/etc/conf")),
+            Some("etc/conf".to_string())
+        );
+        assert_eq!(relative_member_path(Path::new("./")), None);
+        assert_eq!(relative_member_path(Path::new("../escape")), None);
+    }
+
+    #[test]
+    fn test_compare_dir_to_dir_source() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        std::fs::write(left.path().join("same.txt"), b"same").unwrap();
+        std::fs::write(right.path().join("same.txt"), b"same").unwrap();
+        std::fs::write(left.path().join("changed.txt"), b"left").unwrap();
+        std::fs::write(right.path().join("changed.txt"), b"rght").unwrap();
+        std::fs::write(left.path().join("extra.txt"), b"x").unwrap();
+
+        let mut source = DirSource::new(right.path());
+        let diffs =
+            compare_dir_to_source(left.path(), &mut source, Side::Right, HashAlgorithm::Blake3)
+                .unwrap();
+        let summary: Vec<(&str, bool)> = diffs
+            .iter()
+            .map(|d| (d.path.as_str(), matches!(d.diff_type, DiffType::OnlyInLeft)))
+            .collect();
+        assert_eq!(summary, [("changed.txt", false), ("extra.txt", true)]);
+    }
+
+    #[test]
+    fn test_hidden_files_are_compared() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        std::fs::write(left.path().join(".env"), b"A=1").unwrap();
+        std::fs::write(right.path().join(".env"), b"A=2").unwrap();
+        std::fs::write(left.path().join(".gitignore"), b"*.log").unwrap();
+        std::fs::write(right.path().join(".gitignore"), b"*.log").unwrap();
+        std::fs::write(left.path().join("run.log"), b"x").unwrap();
+
+        let mut source = DirSource::new(right.path());
+        let diffs =
+            compare_dir_to_source(left.path(), &mut source, Side::Right, HashAlgorithm::Blake3)
+                .unwrap();
+        let paths: Vec<&str> = diffs.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, [".env", "run.log"]);
+    }
+}
@@ -0,0 +1,197 @@
+//! Sparse file support for folder-differ
+//!
+//! Holes are found with `lseek(SEEK_DATA/SEEK_HOLE)` so that hashing and
+//! copying only touch the allocated parts of a file. Where the platform or
+//! filesystem cannot report holes, the whole file is treated as data.
+//! Physical extents come from `FS_IOC_FIEMAP`, to tell files sharing their
+//! data (reflinks) from copies.
+
+use std::fs::{File, Metadata};
+use std::io;
+
+/// Bytes actually allocated on disk for a file.
+#[cfg(unix)]
+pub fn allocated_bytes(meta: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.blocks() * 512
+}
+
+#[cfg(not(unix))]
+pub fn allocated_bytes(meta: &Metadata) -> u64 {
+    meta.len()
+}
+
+/// True if at least one 4KB block of the file is a hole.
+pub fn is_sparse(meta: &Metadata) -> bool {
+    allocated_bytes(meta) + 4096 <= meta.len()
+}
+
+/// The `(start, end)` byte ranges of `file` that hold data, in order.
+///
+/// Everything outside these ranges reads as zeros.
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "macos"))]
+pub fn data_segments(file: &File) -> io::Result<Vec<(u64, u64)>> {
+    use std::os::fd::AsRawFd;
+    let len = file.metadata()?.len();
+    let fd = file.as_raw_fd();
+    let seek = |offset: u64, whence| {
+        // SAFETY: `fd` is open for the lifetime of `file`.
+        let pos = unsafe { libc::lseek(fd, offset as libc::off_t, whence) };
+        if pos < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(pos as u64)
+        }
+    };
+    let mut segments = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let start = match seek(offset, libc::SEEK_DATA) {
+            Ok(start) => start,
+            // No data after `offset`: the rest of the file is a hole.
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) && offset == 0 => {
+                return Ok(vec![(0, len)]);
+            }
+            Err(e) => return Err(e),
+        };
+        let end = seek(start, libc::SEEK_HOLE)?.min(len);
+        segments.push((start, end));
+        offset = end;
+    }
+    Ok(segments)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd", target_os = "macos")))]
+pub fn data_segments(file: &File) -> io::Result<Vec<(u64, u64)>> {
+    let len = file.metadata()?.len();
+    Ok(if len > 0 { vec![(0, len)] } else { Vec::new() })
+}
+
+/// Where the data of `file` lies on disk: `(logical, physical, length)` of
+/// each extent, in order.
+///
+/// `None` if the filesystem cannot say (no `FS_IOC_FIEMAP`, off Linux) or
+/// some data has no fixed location yet, e.g. is stored inline. Files on one
+/// device with the same extents share their data.
+#[cfg(target_os = "linux")]
+pub fn physical_extents(file: &File) -> io::Result<Option<Vec<(u64, u64, u64)>>> {
+    use std::os::fd::AsRawFd;
+    const FS_IOC_FIEMAP: u64 = 0xC020_660B;
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    // Unknown location, delayed allocation, inline or tail-packed data.
+    const FIEMAP_EXTENT_UNPLACED: u32 = 0x2 | 0x4 | 0x200 | 0x400;
+    const BATCH: usize = 64;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct Extent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    struct Request {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [Extent; BATCH],
+    }
+
+    let mut extents = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut request = Request {
+            start,
+            length: u64::MAX - start,
+            flags: FIEMAP_FLAG_SYNC,
+            mapped_extents: 0,
+            extent_count: BATCH as u32,
+            reserved: 0,
+            extents: [Extent::default(); BATCH],
+        };
+        // SAFETY: `request` is a `struct fiemap` with room for `extent_count`
+        // extents, and the descriptor is open for the duration of the call.
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut request) };
+        if ret != 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::EOPNOTSUPP | libc::ENOTTY) => Ok(None),
+                _ => Err(e),
+            };
+        }
+        let mapped = &request.extents[..(request.mapped_extents as usize).min(BATCH)];
+        for extent in mapped {
+            if extent.flags & FIEMAP_EXTENT_UNPLACED != 0 {
+                return Ok(None);
+            }
+            extents.push((extent.logical, extent.physical, extent.length));
+        }
+        match mapped.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.logical + last.length;
+            }
+            _ => return Ok(Some(extents)),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn physical_extents(_file: &File) -> io::Result<Option<Vec<(u64, u64, u64)>>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Seek, SeekFrom, Write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_data_segments_of_sparse_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("sparse.img");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&[1u8; 4096]).unwrap();
+        file.seek(SeekFrom::Start(1 << 20)).unwrap();
+        file.write_all(&[2u8; 4096]).unwrap();
+        file.set_len(2 << 20).unwrap();
+        drop(file);
+
+        let file = File::open(&path).unwrap();
+        let segments = data_segments(&file).unwrap();
+        // The data is always covered, whether or not the filesystem reports holes.
+        assert!(segments.iter().any(|&(s, e)| s == 0 && e >= 4096));
+        assert!(
+            segments
+                .iter()
+                .any(|&(s, e)| s <= 1 << 20 && e >= (1 << 20) + 4096)
+        );
+        if is_sparse(&file.metadata().unwrap()) {
+            let covered: u64 = segments.iter().map(|(s, e)| e - s).sum();
+            assert!(covered < 2 << 20);
+        }
+    }
+
+    #[test]
+    fn test_physical_extents_tell_copies_apart() {
+        let dir = tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        std::fs::write(&a, [7u8; 8192]).unwrap();
+        std::fs::write(&b, [7u8; 8192]).unwrap();
+        let extents = |path| physical_extents(&File::open(path).unwrap()).unwrap();
+        if let (Some(a_extents), Some(b_extents)) = (extents(&a), extents(&b)) {
+            assert!(!a_extents.is_empty());
+            assert_ne!(a_extents, b_extents);
+            assert_eq!(extents(&a), Some(a_extents));
+        }
+    }
+}
@@ -1,14 +1,26 @@
 //! Synchronization actions, logging, and rollback for folder-differ
 
-use crate::Result;
+use crate::copy::{
+    CopyMethod, CopyStats, DELTA_BLOCK_SIZE, DELTA_MIN_SIZE, Throttle, copy_file, delta_update,
+    link_file, reflink_file, restore_blocks, sparse_copy,
+};
 use crate::diff::{Diff, DiffType};
+use crate::hash::read_block;
+use crate::journal::{format_time, parse_time};
+use crate::links::Hardlinks;
+use crate::links::{inode_key, link_count};
+use crate::sparse::is_sparse;
+use crate::three_way::{ThreeWayDiff, ThreeWayStatus};
+use crate::{FolderDifferError, Result, STATE_DIR_NAME, executor};
+use std::collections::BTreeMap;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::SystemTime;
 
 /// Represents an action to synchronize files between directories.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SyncAction {
     CopyLeftToRight(String),
     CopyRightToLeft(String),
@@ -16,6 +28,191 @@ pub enum SyncAction {
     DeleteRight(String),
     Conflict(String),
     NoOp(String),
+    /// Replace a file with a link to an identical file, see [`Dedupe`].
+    Dedupe(Dedupe),
+    /// Change which paths a file is hardlinked to, see [`Relink`].
+    Relink(Relink),
+}
+
+/// How a duplicate is made to share the data of its original.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupeMethod {
+    /// One inode for both paths; they also share permissions and times.
+    Hardlink,
+    /// Separate files sharing extents (`FICLONE`), each keeping its metadata.
+    Reflink,
+}
+
+impl DedupeMethod {
+    /// Parse `hardlink` or `reflink`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        match spec {
+            "hardlink" => Ok(DedupeMethod::Hardlink),
+            "reflink" => Ok(DedupeMethod::Reflink),
+            _ => Err(FolderDifferError::Other(format!(
+                "Unknown dedupe method: {} (expected hardlink or reflink)",
+                spec
+            ))),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DedupeMethod::Hardlink => "hardlink",
+            DedupeMethod::Reflink => "reflink",
+        }
+    }
+}
+
+/// A duplicate file to be replaced by a link to its original.
+///
+/// The duplicate is not backed up: its content stays available through the
+/// original, and rolling back gives it its own copy of the data again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dedupe {
+    pub side: Side,
+    pub path: String,
+    pub original_side: Side,
+    pub original: String,
+    pub method: DedupeMethod,
+    /// The duplicate's own permissions, owner and times, which a hardlink
+    /// replaces with the original's; rolling back restores them.
+    pub metadata: Option<FileMetadata>,
+}
+
+impl Dedupe {
+    /// Method and original as one journal field, e.g. `hardlink:left:a/b.txt`.
+    pub fn source_field(&self) -> String {
+        format!(
+            "{}:{}:{}",
+            self.method.as_str(),
+            self.original_side.as_str(),
+            self.original
+        )
+    }
+
+    /// Rebuild a dedupe of `path` on `side` from its [`Self::source_field`].
+    pub fn from_source_field(side: Side, path: String, field: &str) -> Option<Self> {
+        let mut parts = field.splitn(3, ':');
+        let method = DedupeMethod::parse(parts.next()?).ok()?;
+        let original_side = match parts.next()? {
+            "left" => Side::Left,
+            "right" => Side::Right,
+            _ => return None,
+        };
+        Some(Self {
+            side,
+            path,
+            original_side,
+            original: parts.next()?.to_string(),
+            method,
+            metadata: None,
+        })
+    }
+}
+
+/// A file whose hardlinks are made to match the other tree's grouping.
+///
+/// The old file is moved to the backup store whole; as that is a rename, it
+/// keeps its inode, and restoring it restores its old links.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relink {
+    pub side: Side,
+    pub path: String,
+    /// Path of the same tree to link to; `None` gives the file an inode of
+    /// its own instead.
+    pub leader: Option<String>,
+}
+
+impl SyncAction {
+    /// Name of the action variant, as recorded in the sync journal.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SyncAction::CopyLeftToRight(_) => "CopyLeftToRight",
+            SyncAction::CopyRightToLeft(_) => "CopyRightToLeft",
+            SyncAction::DeleteLeft(_) => "DeleteLeft",
+            SyncAction::DeleteRight(_) => "DeleteRight",
+            SyncAction::Conflict(_) => "Conflict",
+            SyncAction::NoOp(_) => "NoOp",
+            SyncAction::Dedupe(dedupe) => match dedupe.side {
+                Side::Left => "DedupeLeft",
+                Side::Right => "DedupeRight",
+            },
+            SyncAction::Relink(relink) => match relink.side {
+                Side::Left => "RelinkLeft",
+                Side::Right => "RelinkRight",
+            },
+        }
+    }
+
+    /// Rebuild an action from its journal kind and relative path.
+    ///
+    /// Dedupe and relink actions also need their source, see
+    /// [`Self::from_journal`].
+    pub fn from_kind(kind: &str, rel_path: String) -> Option<Self> {
+        Some(match kind {
+            "CopyLeftToRight" => SyncAction::CopyLeftToRight(rel_path),
+            "CopyRightToLeft" => SyncAction::CopyRightToLeft(rel_path),
+            "DeleteLeft" => SyncAction::DeleteLeft(rel_path),
+            "DeleteRight" => SyncAction::DeleteRight(rel_path),
+            "Conflict" => SyncAction::Conflict(rel_path),
+            "NoOp" => SyncAction::NoOp(rel_path),
+            _ => return None,
+        })
+    }
+
+    /// Rebuild an action from its journal kind, relative path and
+    /// [`Self::source_field`].
+    pub fn from_journal(kind: &str, rel_path: String, source: Option<&str>) -> Option<Self> {
+        let side = match kind {
+            "DedupeLeft" | "RelinkLeft" => Side::Left,
+            "DedupeRight" | "RelinkRight" => Side::Right,
+            _ => return Self::from_kind(kind, rel_path),
+        };
+        if kind.starts_with("Relink") {
+            return Some(SyncAction::Relink(Relink {
+                side,
+                path: rel_path,
+                leader: source.map(str::to_string),
+            }));
+        }
+        Dedupe::from_source_field(side, rel_path, source?).map(SyncAction::Dedupe)
+    }
+
+    /// What else the journal needs to rebuild the action: the original of a
+    /// dedupe, the leader of a relink.
+    pub fn source_field(&self) -> Option<String> {
+        match self {
+            SyncAction::Dedupe(dedupe) => Some(dedupe.source_field()),
+            SyncAction::Relink(relink) => relink.leader.clone(),
+            _ => None,
+        }
+    }
+
+    /// The relative path the action applies to.
+    pub fn path(&self) -> &str {
+        match self {
+            SyncAction::CopyLeftToRight(p)
+            | SyncAction::CopyRightToLeft(p)
+            | SyncAction::DeleteLeft(p)
+            | SyncAction::DeleteRight(p)
+            | SyncAction::Conflict(p)
+            | SyncAction::NoOp(p) => p,
+            SyncAction::Dedupe(dedupe) => &dedupe.path,
+            SyncAction::Relink(relink) => &relink.path,
+        }
+    }
+
+    /// The side whose tree is modified by the action, if any.
+    pub fn target(&self) -> Option<Side> {
+        match self {
+            SyncAction::CopyLeftToRight(_) | SyncAction::DeleteRight(_) => Some(Side::Right),
+            SyncAction::CopyRightToLeft(_) | SyncAction::DeleteLeft(_) => Some(Side::Left),
+            SyncAction::Conflict(_) | SyncAction::NoOp(_) => None,
+            SyncAction::Dedupe(dedupe) => Some(dedupe.side),
+            SyncAction::Relink(relink) => Some(relink.side),
+        }
+    }
 }
 
 /// A log entry for a sync action.
@@ -24,6 +221,9 @@ pub struct SyncLogEntry {
     pub action: SyncAction,
     pub timestamp: SystemTime,
     pub details: String,
+    /// Where the previous version of the affected file was backed up, if any.
+    pub backup: Option<PathBuf>,
+    pub outcome: SyncOutcome,
 }
 
 /// A log of all sync actions performed.
@@ -39,28 +239,119 @@ pub struct SyncState {
 }
 
 /// Plan sync actions based on diffs and sync mode.
-pub fn plan_sync_actions(diffs: &[Diff], _sync_mode: &str) -> Vec<SyncAction> {
+///
+/// Hardlink groups are only rebuilt if `options` preserves hardlinks;
+/// otherwise a differing grouping is left alone. The right tree then takes
+/// the left tree's grouping: members of a left group are relinked to its
+/// first path, and paths linked to something they should not be are given
+/// inodes of their own. Each path gets one action; a path that is copied
+/// anyway is relinked instead of copied, or left to the copy, which gives it
+/// a new inode, if it only needs detaching.
+pub fn plan_sync_actions(
+    diffs: &[Diff],
+    _sync_mode: &str,
+    options: &SyncOptions,
+) -> Vec<SyncAction> {
+    let mut relinks: BTreeMap<&str, Option<&str>> = BTreeMap::new();
+    let mut actions: Vec<SyncAction> = diffs
+        .iter()
+        .filter_map(|diff| {
+            Some(match &diff.diff_type {
+                DiffType::OnlyInLeft => SyncAction::CopyLeftToRight(diff.path.clone()),
+                DiffType::OnlyInRight => SyncAction::CopyRightToLeft(diff.path.clone()),
+                DiffType::Different { .. } => SyncAction::CopyLeftToRight(diff.path.clone()),
+                // Content already matches; rewriting it would not change anything.
+                DiffType::AllocationDiffers { .. }
+                | DiffType::TimeDiffers { .. }
+                | DiffType::ModeDiffers { .. } => SyncAction::NoOp(diff.path.clone()),
+                DiffType::HardlinksDiffer { .. } if options.hardlinks.is_none() => {
+                    SyncAction::NoOp(diff.path.clone())
+                }
+                DiffType::HardlinksDiffer {
+                    left_group,
+                    right_group,
+                } => {
+                    let leader = left_group[0].as_str();
+                    for path in &left_group[1..] {
+                        relinks.insert(path, Some(leader));
+                    }
+                    for path in right_group.iter().filter(|p| !left_group.contains(p)) {
+                        // Linking a path (from its own group's diff) wins.
+                        relinks.entry(path).or_insert(None);
+                    }
+                    return None;
+                }
+            })
+        })
+        .collect();
+    for action in &mut actions {
+        if let SyncAction::CopyLeftToRight(path) = action
+            && let Some(leader) = relinks.remove(path.as_str())
+            && let Some(leader) = leader
+        {
+            *action = SyncAction::Relink(Relink {
+                side: Side::Right,
+                path: path.clone(),
+                leader: Some(leader.to_string()),
+            });
+        }
+    }
+    actions.extend(relinks.into_iter().map(|(path, leader)| {
+        SyncAction::Relink(Relink {
+            side: Side::Right,
+            path: path.to_string(),
+            leader: leader.map(str::to_string),
+        })
+    }));
+    actions
+}
+
+/// Plan the actions that merge a three-way comparison into both trees.
+///
+/// `ours` is the left tree and `theirs` the right one. A change made on one
+/// side only is carried over to the other (copied, or deleted if the path was
+/// removed); conflicts become [`SyncAction::Conflict`] and are left alone.
+/// Unchanged paths get no action.
+pub fn plan_merge_actions(diffs: &[ThreeWayDiff]) -> Vec<SyncAction> {
     diffs
         .iter()
-        .map(|diff| match &diff.diff_type {
-            DiffType::OnlyInLeft => SyncAction::CopyLeftToRight(diff.path.clone()),
-            DiffType::OnlyInRight => SyncAction::CopyRightToLeft(diff.path.clone()),
-            DiffType::Different { .. } => SyncAction::CopyLeftToRight(diff.path.clone()),
+        .filter_map(|diff| {
+            let path = diff.path.clone();
+            Some(match diff.status {
+                ThreeWayStatus::Unchanged => return None,
+                ThreeWayStatus::ChangedInOurs if diff.in_ours => SyncAction::CopyLeftToRight(path),
+                ThreeWayStatus::ChangedInOurs => SyncAction::DeleteRight(path),
+                ThreeWayStatus::ChangedInTheirs if diff.in_theirs => {
+                    SyncAction::CopyRightToLeft(path)
+                }
+                ThreeWayStatus::ChangedInTheirs => SyncAction::DeleteLeft(path),
+                ThreeWayStatus::ChangedIdentically => SyncAction::NoOp(path),
+                ThreeWayStatus::Conflict => SyncAction::Conflict(path),
+            })
         })
         .collect()
 }
 
 /// Log a sync action.
-pub fn log_sync_action(log: &mut SyncLog, action: &SyncAction, details: &str) {
+pub fn log_sync_action(
+    log: &mut SyncLog,
+    action: &SyncAction,
+    details: &str,
+    backup: Option<PathBuf>,
+    outcome: SyncOutcome,
+) {
     log.entries.push(SyncLogEntry {
         action: action.clone(),
         timestamp: SystemTime::now(),
         details: details.to_string(),
+        backup,
+        outcome,
     });
 }
 
 /// Save the sync log to disk.
 pub fn save_sync_log(log: &SyncLog, path: &Path) -> Result<()> {
+    std::fs::create_dir_all(path)?;
     let log_path = path.join(".sync-log.txt");
     let mut file = OpenOptions::new()
         .create(true)
@@ -82,148 +373,1391 @@ pub fn save_sync_state(_state: &SyncState, path: &Path) -> Result<()> {
     Ok(())
 }
 
-/// Create a backup of a file before modification.
-pub fn backup_file(path: &Path) -> Result<Option<PathBuf>> {
-    if path.exists() {
-        let backup_path = path.with_extension("bak");
-        std::fs::copy(path, &backup_path)?;
+/// Which of the two compared trees a path belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    /// Lowercase name used in backup paths and log output.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Side::Left => "left",
+            Side::Right => "right",
+        }
+    }
+
+    /// The opposite side.
+    pub fn other(&self) -> Side {
+        match self {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        }
+    }
+}
+
+/// Where backups of overwritten or deleted files are kept during a sync run.
+///
+/// By default backups live inside each tree under
+/// `.folder-differ/backups/<run-id>/<relative path>`, which the directory
+/// walkers skip. With an external directory they are stored under
+/// `<dir>/<run-id>/<side>/<relative path>` instead.
+#[derive(Debug, Clone)]
+pub struct BackupStore {
+    external: Option<PathBuf>,
+    run_id: String,
+}
+
+impl BackupStore {
+    /// Keep backups inside each synced tree's state directory.
+    pub fn in_tree(run_id: impl Into<String>) -> Self {
+        Self {
+            external: None,
+            run_id: run_id.into(),
+        }
+    }
+
+    /// Keep backups in a directory outside both trees.
+    pub fn external(dir: impl Into<PathBuf>, run_id: impl Into<String>) -> Self {
+        Self {
+            external: Some(dir.into()),
+            run_id: run_id.into(),
+        }
+    }
+
+    /// The sync run this store belongs to.
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    /// Directory holding this run's backups for one side.
+    pub fn run_dir(&self, tree_root: &Path, side: Side) -> PathBuf {
+        match &self.external {
+            Some(dir) => dir.join(&self.run_id).join(side.as_str()),
+            None => tree_root
+                .join(STATE_DIR_NAME)
+                .join("backups")
+                .join(&self.run_id),
+        }
+    }
+
+    /// The external backup directory, if backups are not kept in-tree.
+    pub fn external_dir(&self) -> Option<&Path> {
+        self.external.as_deref()
+    }
+
+    /// Where a backup of `tree_root/rel_path` is (or would be) stored.
+    pub fn backup_path(&self, tree_root: &Path, side: Side, rel_path: &str) -> PathBuf {
+        self.run_dir(tree_root, side).join(rel_path)
+    }
+
+    /// Move `tree_root/rel_path` into the store, returning the backup path.
+    ///
+    /// Returns `None` if there was nothing to back up.
+    pub fn backup(&self, tree_root: &Path, side: Side, rel_path: &str) -> Result<Option<PathBuf>> {
+        let path = tree_root.join(rel_path);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let backup_path = self.backup_path(tree_root, side, rel_path);
+        if let Some(parent) = backup_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        move_file(&path, &backup_path)?;
         Ok(Some(backup_path))
-    } else {
-        Ok(None)
     }
 }
 
+/// Generate a new run identifier, e.g. `20250101T120000Z-4242`.
+///
+/// Identifiers sort chronologically.
+pub fn new_run_id() -> String {
+    let (year, month, day, hour, minute, second) = utc_parts(SystemTime::now());
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z-{}",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        std::process::id()
+    )
+}
+
+/// Format a timestamp as `YYYY-MM-DD HH:MM:SS UTC`.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc_parts(time);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Split a timestamp into UTC (year, month, day, hour, minute, second).
+fn utc_parts(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+/// Convert days since the Unix epoch to a (year, month, day) civil date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Rename a file, falling back to copy and remove across filesystems.
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to)?;
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
 /// Restore a file from its backup.
 pub fn restore_file(backup_path: &Path, orig_path: &Path) -> Result<()> {
-    std::fs::copy(backup_path, orig_path)?;
-    Ok(())
+    if let Some(parent) = orig_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    move_file(backup_path, orig_path)
+}
+
+/// Suffix of block backups, which hold only the overwritten parts of a file.
+const BLOCK_BACKUP_SUFFIX: &str = ".folder-differ-blocks";
+
+/// Where a file updated in place keeps the blocks it overwrote, given the
+/// path its full backup would have.
+pub fn block_backup_path(backup: &Path) -> PathBuf {
+    let mut path = backup.as_os_str().to_owned();
+    path.push(BLOCK_BACKUP_SUFFIX);
+    PathBuf::from(path)
+}
+
+fn is_block_backup(path: &Path) -> bool {
+    path.as_os_str()
+        .to_string_lossy()
+        .ends_with(BLOCK_BACKUP_SUFFIX)
+}
+
+/// Put back the old version of `target` from `backup`: a full copy is moved
+/// back, a block backup is applied and then removed.
+///
+/// `backup` may also be the full backup path of a file that was updated in
+/// place; its block backup is used then.
+pub fn restore_backup(backup: &Path, target: &Path) -> Result<()> {
+    let blocks = if is_block_backup(backup) {
+        Some(backup.to_path_buf())
+    } else if !backup.exists() {
+        Some(block_backup_path(backup)).filter(|blocks| blocks.exists())
+    } else {
+        None
+    };
+    match blocks {
+        Some(blocks) => {
+            restore_blocks(&blocks, target)?;
+            std::fs::remove_file(&blocks)?;
+            Ok(())
+        }
+        None => restore_file(backup, target),
+    }
+}
+
+/// Delete a file, keeping a backup in the store.
+pub fn delete_file_with_backup(
+    store: &BackupStore,
+    tree_root: &Path,
+    side: Side,
+    rel_path: &str,
+) -> Result<Option<PathBuf>> {
+    store.backup(tree_root, side, rel_path)
+}
+
+/// The result of performing a single sync action.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncOutcome {
+    Success,
+    /// Nothing was done, for the given reason.
+    Skipped(String),
+    Failed {
+        kind: std::io::ErrorKind,
+        message: String,
+    },
+}
+
+impl SyncOutcome {
+    fn from_error(err: &FolderDifferError) -> Self {
+        let kind = match err {
+            FolderDifferError::Io(e) => e.kind(),
+            _ => std::io::ErrorKind::Other,
+        };
+        SyncOutcome::Failed {
+            kind,
+            message: err.to_string(),
+        }
+    }
+}
+
+/// What to do when a sync action fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FailurePolicy {
+    /// Stop at the first failed action.
+    #[default]
+    FailFast,
+    /// Perform every action and report all failures at the end.
+    KeepGoing,
 }
 
-/// Delete a file with backup.
-pub fn delete_file_with_backup(path: &Path) -> Result<Option<PathBuf>> {
-    let backup = backup_file(path)?;
-    std::fs::remove_file(path)?;
-    Ok(backup)
+/// Which file metadata to carry over from source to destination when copying.
+///
+/// Preserving modification times lets a freshly synced pair compare as equal
+/// through the cheap size/mtime check instead of being rehashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreserveOptions {
+    /// Access and modification times.
+    pub times: bool,
+    /// Permission bits.
+    pub mode: bool,
+    /// Owner and group. Silently skipped when not permitted (i.e. unprivileged).
+    pub owner: bool,
+}
+
+impl Default for PreserveOptions {
+    fn default() -> Self {
+        Self {
+            times: true,
+            mode: true,
+            owner: false,
+        }
+    }
+}
+
+impl PreserveOptions {
+    /// Preserve nothing beyond what the copy itself carries over.
+    pub fn none() -> Self {
+        Self {
+            times: false,
+            mode: false,
+            owner: false,
+        }
+    }
+
+    /// Preserve times, mode and ownership.
+    pub fn all() -> Self {
+        Self {
+            times: true,
+            mode: true,
+            owner: true,
+        }
+    }
+
+    /// Parse a comma-separated list such as `times,mode`, `all` or `none`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut opts = Self::none();
+        for item in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match item {
+                "times" => opts.times = true,
+                "mode" => opts.mode = true,
+                "owner" => opts.owner = true,
+                "all" => opts = Self::all(),
+                "none" => opts = Self::none(),
+                other => {
+                    return Err(FolderDifferError::Other(format!(
+                        "Unknown --preserve item: {}",
+                        other
+                    )));
+                }
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// Options controlling how sync actions are performed.
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    pub failure_policy: FailurePolicy,
+    pub preserve: PreserveOptions,
+    /// Maximum concurrent actions per destination device (unlimited if `None`).
+    pub max_per_device: Option<usize>,
+    /// Bandwidth limit shared by all copies of a run.
+    pub throttle: Option<Arc<Throttle>>,
+    /// Always copy whole files instead of delta-transferring large ones.
+    pub whole_file: bool,
+    /// Whether copies may or must use reflinks.
+    pub copy_method: CopyMethod,
+    /// Hardlink groups of both trees; when set, copies of linked files are
+    /// linked to an already synced member of their group on the destination.
+    pub hardlinks: Option<Arc<Hardlinks>>,
+}
+
+impl SyncOptions {
+    /// The path a copy of `rel_path` from `source` should be hardlinked to
+    /// instead of copied, if hardlinks are being preserved.
+    pub fn link_leader(&self, source: Side, rel_path: &str) -> Option<&str> {
+        self.hardlinks.as_ref()?.side(source).leader(rel_path)
+    }
+}
+
+/// Aggregate result of executing a list of sync actions.
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    pub succeeded: usize,
+    pub skipped: usize,
+    pub failed: Vec<(SyncAction, SyncOutcome)>,
+    /// Actions never attempted because an earlier one failed under fail-fast.
+    pub not_attempted: usize,
+}
+
+impl SyncReport {
+    /// Count one action's outcome.
+    pub fn record(&mut self, action: &SyncAction, outcome: &SyncOutcome) {
+        match outcome {
+            SyncOutcome::Success => self.succeeded += 1,
+            SyncOutcome::Skipped(_) => self.skipped += 1,
+            SyncOutcome::Failed { .. } => self.failed.push((action.clone(), outcome.clone())),
+        }
+    }
+
+    /// True if no action failed.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// One-line summary, e.g. `3 succeeded, 1 skipped, 0 failed`.
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} succeeded, {} skipped, {} failed",
+            self.succeeded,
+            self.skipped,
+            self.failed.len()
+        );
+        if self.not_attempted > 0 {
+            summary.push_str(&format!(", {} not attempted", self.not_attempted));
+        }
+        summary
+    }
 }
 
 /// Perform a sync action.
+///
+/// Failures are reported through the returned [`SyncOutcome`] (and the log)
+/// rather than as an error, so the caller's [`FailurePolicy`] decides whether
+/// to continue. A failed copy leaves the destination as it was.
 pub fn perform_sync_action(
     action: &SyncAction,
     left: &Path,
     right: &Path,
+    store: &BackupStore,
+    options: &SyncOptions,
     log: &mut SyncLog,
-) -> Result<()> {
-    match action {
+) -> SyncOutcome {
+    let mut stats = None;
+    let (outcome, backup) = match action {
         SyncAction::CopyLeftToRight(rel_path) => {
-            let src = left.join(rel_path);
-            let dst = right.join(rel_path);
-            if let Some(parent) = dst.parent() {
-                std::fs::create_dir_all(parent)?;
-            }
-            let backup = backup_file(&dst)?;
-            let res = std::fs::copy(&src, &dst);
-            let msg = if let Ok(_) = res {
-                format!("Copied {} to right. Backup: {:?}", rel_path, backup)
-            } else {
-                format!("FAILED to copy {} to right", rel_path)
-            };
-            log_sync_action(log, action, &msg);
+            match copy_with_backup(left, right, Side::Right, rel_path, store, options) {
+                Ok((backup, copied)) => {
+                    stats = Some(copied);
+                    (SyncOutcome::Success, backup)
+                }
+                Err(e) => (SyncOutcome::from_error(&e), None),
+            }
         }
         SyncAction::CopyRightToLeft(rel_path) => {
-            let src = right.join(rel_path);
-            let dst = left.join(rel_path);
-            if let Some(parent) = dst.parent() {
-                std::fs::create_dir_all(parent)?;
-            }
-            let backup = backup_file(&dst)?;
-            let res = std::fs::copy(&src, &dst);
-            let msg = if let Ok(_) = res {
-                format!("Copied {} to left. Backup: {:?}", rel_path, backup)
-            } else {
-                format!("FAILED to copy {} to left", rel_path)
-            };
-            log_sync_action(log, action, &msg);
+            match copy_with_backup(right, left, Side::Left, rel_path, store, options) {
+                Ok((backup, copied)) => {
+                    stats = Some(copied);
+                    (SyncOutcome::Success, backup)
+                }
+                Err(e) => (SyncOutcome::from_error(&e), None),
+            }
         }
         SyncAction::DeleteLeft(rel_path) => {
-            let path = left.join(rel_path);
-            let backup = delete_file_with_backup(&path)?;
-            let msg = if backup.is_some() {
-                format!("Deleted {} from left. Backup: {:?}", rel_path, backup)
-            } else {
-                format!("FAILED to delete {} from left", rel_path)
-            };
-            log_sync_action(log, action, &msg);
+            match delete_file_with_backup(store, left, Side::Left, rel_path) {
+                Ok(Some(backup)) => (SyncOutcome::Success, Some(backup)),
+                Ok(None) => (SyncOutcome::Skipped("already absent".to_string()), None),
+                Err(e) => (SyncOutcome::from_error(&e), None),
+            }
         }
         SyncAction::DeleteRight(rel_path) => {
-            let path = right.join(rel_path);
-            let backup = delete_file_with_backup(&path)?;
-            let msg = if backup.is_some() {
-                format!("Deleted {} from right. Backup: {:?}", rel_path, backup)
+            match delete_file_with_backup(store, right, Side::Right, rel_path) {
+                Ok(Some(backup)) => (SyncOutcome::Success, Some(backup)),
+                Ok(None) => (SyncOutcome::Skipped("already absent".to_string()), None),
+                Err(e) => (SyncOutcome::from_error(&e), None),
+            }
+        }
+        SyncAction::Conflict(_) => (
+            SyncOutcome::Skipped("conflict, manual resolution required".to_string()),
+            None,
+        ),
+        SyncAction::NoOp(_) => (SyncOutcome::Skipped("no operation".to_string()), None),
+        SyncAction::Dedupe(dedupe) => match dedupe_file(dedupe, left, right) {
+            Ok(Ok(linked)) => {
+                stats = Some(linked);
+                (SyncOutcome::Success, None)
+            }
+            Ok(Err(reason)) => (SyncOutcome::Skipped(reason.to_string()), None),
+            Err(e) => (SyncOutcome::from_error(&e), None),
+        },
+        SyncAction::Relink(relink) => match relink_file(relink, left, right, store) {
+            Ok(Ok(backup)) => (SyncOutcome::Success, Some(backup)),
+            Ok(Err(reason)) => (SyncOutcome::Skipped(reason.to_string()), None),
+            Err(e) => (SyncOutcome::from_error(&e), None),
+        },
+    };
+    let rel_path = action.path();
+    let copied = stats.map(|s| format!(" ({})", s)).unwrap_or_default();
+    let msg = match (&outcome, action) {
+        (SyncOutcome::Success, SyncAction::CopyLeftToRight(_)) => {
+            format!(
+                "Copied {} to right{}. Backup: {:?}",
+                rel_path, copied, backup
+            )
+        }
+        (SyncOutcome::Success, SyncAction::CopyRightToLeft(_)) => {
+            format!(
+                "Copied {} to left{}. Backup: {:?}",
+                rel_path, copied, backup
+            )
+        }
+        (SyncOutcome::Success, SyncAction::DeleteLeft(_)) => {
+            format!("Deleted {} from left. Backup: {:?}", rel_path, backup)
+        }
+        (SyncOutcome::Success, SyncAction::DeleteRight(_)) => {
+            format!("Deleted {} from right. Backup: {:?}", rel_path, backup)
+        }
+        (SyncOutcome::Success, SyncAction::Dedupe(dedupe)) => {
+            format!(
+                "Deduplicated {} on {} against {} on {}{}",
+                rel_path,
+                dedupe.side.as_str(),
+                dedupe.original,
+                dedupe.original_side.as_str(),
+                copied
+            )
+        }
+        (SyncOutcome::Success, SyncAction::Relink(relink)) => match &relink.leader {
+            Some(leader) => format!(
+                "Linked {} to {} on {}. Backup: {:?}",
+                rel_path,
+                leader,
+                relink.side.as_str(),
+                backup
+            ),
+            None => format!(
+                "Gave {} on {} an inode of its own. Backup: {:?}",
+                rel_path,
+                relink.side.as_str(),
+                backup
+            ),
+        },
+        (SyncOutcome::Success, _) => format!("Performed {} {}", action.kind(), rel_path),
+        (SyncOutcome::Skipped(reason), _) => {
+            format!("Skipped {} {}: {}", action.kind(), rel_path, reason)
+        }
+        (SyncOutcome::Failed { message, .. }, _) => {
+            format!("FAILED {} {}: {}", action.kind(), rel_path, message)
+        }
+    };
+    log_sync_action(log, action, &msg, backup, outcome.clone());
+    outcome
+}
+
+/// Copy `src_root/rel_path` over `dst_root/rel_path`, backing up the old file.
+///
+/// Large files that can be updated in place are handed to
+/// [`update_in_place`]. Otherwise the old file is moved into the backup store
+/// and the data copied with [`copy_contents`]; if the copy fails, the partial
+/// file is removed and the backup put back.
+fn copy_with_backup(
+    src_root: &Path,
+    dst_root: &Path,
+    dst_side: Side,
+    rel_path: &str,
+    store: &BackupStore,
+    options: &SyncOptions,
+) -> Result<(Option<PathBuf>, CopyStats)> {
+    let src = src_root.join(rel_path);
+    let dst = dst_root.join(rel_path);
+    // Fail before touching the destination if the source is unreadable.
+    std::fs::File::open(&src)?;
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Link to the group leader only if it was synced, i.e. matches the source.
+    let leader =
+        options
+            .link_leader(dst_side.other(), rel_path)
+            .filter(|leader| match same_content(&src, &dst_root.join(leader)) {
+                Ok(true) => true,
+                Ok(false) | Err(_) => {
+                    log::warn!(
+                        "Copying {} instead of linking it: {} does not match the source",
+                        rel_path,
+                        leader
+                    );
+                    false
+                }
+            });
+    if leader.is_none() && can_update_in_place(&src, &dst, options) {
+        return update_in_place(&src, dst_root, dst_side, rel_path, store, options);
+    }
+    let backup = store.backup(dst_root, dst_side, rel_path)?;
+    let copied = match leader {
+        Some(leader) => link_file(&dst_root.join(leader), &dst),
+        None => copy_contents(&src, &dst, options).and_then(|stats| {
+            preserve_metadata(&src, &dst, &options.preserve)?;
+            Ok(stats)
+        }),
+    };
+    match copied {
+        Ok(stats) => Ok((backup, stats)),
+        Err(e) => {
+            let _ = std::fs::remove_file(&dst);
+            if let Some(backup) = &backup {
+                restore_file(backup, &dst)?;
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Whether `dst` is worth updating block by block instead of replacing: a
+/// large, unshared regular file, with a source that is not sparse.
+fn can_update_in_place(src: &Path, dst: &Path, options: &SyncOptions) -> bool {
+    if options.whole_file || options.copy_method == CopyMethod::Reflink {
+        return false;
+    }
+    let (Ok(src_meta), Ok(dst_meta)) = (std::fs::metadata(src), std::fs::symlink_metadata(dst))
+    else {
+        return false;
+    };
+    #[cfg(unix)]
+    let unshared = std::os::unix::fs::MetadataExt::nlink(&dst_meta) == 1;
+    #[cfg(not(unix))]
+    let unshared = true;
+    dst_meta.is_file() && dst_meta.len() >= DELTA_MIN_SIZE && unshared && !is_sparse(&src_meta)
+}
+
+/// Update the large file `dst_root/rel_path` to match `src`.
+///
+/// A reflink is tried first where allowed, since cloning is free; the old
+/// file then goes to the backup store whole. Otherwise only the blocks that
+/// differ are rewritten, and only those are saved, in a block backup next to
+/// where the full backup would be. A failed update is undone from it.
+fn update_in_place(
+    src: &Path,
+    dst_root: &Path,
+    dst_side: Side,
+    rel_path: &str,
+    store: &BackupStore,
+    options: &SyncOptions,
+) -> Result<(Option<PathBuf>, CopyStats)> {
+    let dst = &dst_root.join(rel_path);
+    if options.copy_method == CopyMethod::Auto {
+        let tmp = sibling_tmp_path(dst);
+        let _ = std::fs::remove_file(&tmp);
+        match reflink_file(src, &tmp).and_then(|stats| {
+            preserve_metadata(src, &tmp, &options.preserve)?;
+            Ok(stats)
+        }) {
+            Ok(stats) => {
+                let backup = store.backup(dst_root, dst_side, rel_path)?;
+                if let Err(e) = std::fs::rename(&tmp, dst) {
+                    let _ = std::fs::remove_file(&tmp);
+                    if let Some(backup) = &backup {
+                        restore_file(backup, dst)?;
+                    }
+                    return Err(e.into());
+                }
+                return Ok((backup, stats));
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp);
+                log::debug!(
+                    "Reflink of {} failed, updating in place: {}",
+                    src.display(),
+                    e
+                );
+            }
+        }
+    }
+    let blocks = block_backup_path(&store.backup_path(dst_root, dst_side, rel_path));
+    if let Some(parent) = blocks.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let updated = delta_update(
+        src,
+        dst,
+        &blocks,
+        DELTA_BLOCK_SIZE,
+        options.throttle.as_deref(),
+    )
+    .and_then(|stats| {
+        preserve_metadata(src, dst, &options.preserve)?;
+        Ok(stats)
+    });
+    match updated {
+        Ok(stats) => Ok((Some(blocks), stats)),
+        Err(e) => {
+            if blocks.exists() {
+                restore_backup(&blocks, dst)?;
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Replace the duplicate of `dedupe` with a link to its original.
+///
+/// The link is made next to the duplicate and renamed over it, so the path
+/// always holds one of the two. Gives a reason to skip instead if the files
+/// are already one inode or their contents no longer match.
+fn dedupe_file(
+    dedupe: &Dedupe,
+    left: &Path,
+    right: &Path,
+) -> Result<std::result::Result<CopyStats, &'static str>> {
+    let root = |side: Side| match side {
+        Side::Left => left,
+        Side::Right => right,
+    };
+    let target = root(dedupe.side).join(&dedupe.path);
+    let original = root(dedupe.original_side).join(&dedupe.original);
+    let (target_meta, original_meta) = (std::fs::metadata(&target)?, std::fs::metadata(&original)?);
+    if inode_key(&target_meta).is_some() && inode_key(&target_meta) == inode_key(&original_meta) {
+        return Ok(Err("already linked"));
+    }
+    if !same_content(&target, &original)? {
+        return Ok(Err("no longer identical"));
+    }
+    let tmp = sibling_tmp_path(&target);
+    let _ = std::fs::remove_file(&tmp);
+    let linked = match dedupe.method {
+        DedupeMethod::Hardlink => link_file(&original, &tmp),
+        DedupeMethod::Reflink => reflink_file(&original, &tmp).and_then(|stats| {
+            preserve_metadata(&target, &tmp, &PreserveOptions::default())?;
+            Ok(stats)
+        }),
+    }
+    .and_then(|stats| {
+        std::fs::rename(&tmp, &target)?;
+        Ok(stats)
+    });
+    if linked.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    linked.map(Ok)
+}
+
+/// Carry out `relink`, backing up the file it replaces.
+///
+/// The replacement (a link to the leader, or a copy of the file) is made
+/// next to the file and renamed over it once the old file is in the backup
+/// store. A link is made only if the leader holds what the other tree has
+/// at the path. Gives a reason to skip instead if there is nothing to do.
+fn relink_file(
+    relink: &Relink,
+    left: &Path,
+    right: &Path,
+    store: &BackupStore,
+) -> Result<std::result::Result<PathBuf, &'static str>> {
+    let (root, source_root) = match relink.side {
+        Side::Left => (left, right),
+        Side::Right => (right, left),
+    };
+    let target = root.join(&relink.path);
+    let target_meta = std::fs::symlink_metadata(&target)?;
+    let tmp = sibling_tmp_path(&target);
+    let _ = std::fs::remove_file(&tmp);
+    let made = match &relink.leader {
+        Some(leader) => {
+            let leader = root.join(leader);
+            let leader_meta = std::fs::metadata(&leader)?;
+            if inode_key(&target_meta).is_some()
+                && inode_key(&target_meta) == inode_key(&leader_meta)
+            {
+                return Ok(Err("already linked"));
+            }
+            if !same_content(&leader, &source_root.join(&relink.path))? {
+                return Ok(Err("leader does not match the source"));
+            }
+            link_file(&leader, &tmp).map(drop)
+        }
+        None => {
+            if link_count(&target_meta) < 2 {
+                return Ok(Err("not linked"));
+            }
+            copy_file(&target, &tmp, None)
+                .and_then(|_| preserve_metadata(&target, &tmp, &PreserveOptions::default()))
+        }
+    };
+    let replaced = made.and_then(|_| {
+        let backup = store
+            .backup(root, relink.side, &relink.path)?
+            .ok_or_else(|| FolderDifferError::Other(format!("{} vanished", target.display())))?;
+        if let Err(e) = std::fs::rename(&tmp, &target) {
+            restore_file(&backup, &target)?;
+            return Err(e.into());
+        }
+        Ok(backup)
+    });
+    if replaced.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    replaced.map(Ok)
+}
+
+/// Where a file replacing `target` is made before being renamed over it.
+pub(crate) fn sibling_tmp_path(target: &Path) -> PathBuf {
+    let name = target
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    target.with_file_name(format!(".{}.folder-differ-tmp", name))
+}
+
+/// Give a deduplicated file its own copy of the data again.
+///
+/// Undoes [`SyncAction::Dedupe`]; the content is unchanged either way. The
+/// copy gets `metadata` if it was recorded, or else that of the file it was
+/// linked to.
+pub fn unshare_file(path: &Path, metadata: Option<&FileMetadata>) -> Result<()> {
+    let tmp = sibling_tmp_path(path);
+    let _ = std::fs::remove_file(&tmp);
+    if !path.exists() {
+        return Ok(());
+    }
+    let copied = copy_file(path, &tmp, None)
+        .and_then(|_| match metadata {
+            Some(metadata) => metadata.apply(&tmp),
+            None => preserve_metadata(path, &tmp, &PreserveOptions::default()),
+        })
+        .and_then(|_| Ok(std::fs::rename(&tmp, path)?));
+    if copied.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    copied
+}
+
+/// Compare the contents of two files block by block.
+fn same_content(a: &Path, b: &Path) -> Result<bool> {
+    let (mut a, mut b) = (std::fs::File::open(a)?, std::fs::File::open(b)?);
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let mut a_buf = vec![0u8; DELTA_BLOCK_SIZE];
+    let mut b_buf = vec![0u8; DELTA_BLOCK_SIZE];
+    loop {
+        let n = read_block(&mut a, &mut a_buf)?;
+        if n != read_block(&mut b, &mut b_buf)? || a_buf[..n] != b_buf[..n] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Copy the data of `src` to `dst` with the configured [`CopyMethod`].
+///
+/// Unless reflinks are disabled they are tried first, since cloning is
+/// instant. Otherwise sparse files are copied hole by hole and everything
+/// else is copied whole.
+fn copy_contents(src: &Path, dst: &Path, options: &SyncOptions) -> Result<CopyStats> {
+    if options.copy_method != CopyMethod::Copy {
+        match reflink_file(src, dst) {
+            Ok(stats) => return Ok(stats),
+            Err(e) if options.copy_method == CopyMethod::Reflink => return Err(e),
+            Err(e) => log::debug!("Reflink of {} failed, copying: {}", src.display(), e),
+        }
+    }
+    let throttle = options.throttle.as_deref();
+    if is_sparse(&std::fs::metadata(src)?) {
+        return sparse_copy(src, dst, throttle);
+    }
+    copy_file(src, dst, throttle)
+}
+
+/// Permissions, owner and times of a file, kept to be put back later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub accessed: SystemTime,
+    pub modified: SystemTime,
+}
+
+impl FileMetadata {
+    pub fn from_metadata(meta: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let (mode, uid, gid) = {
+            use std::os::unix::fs::MetadataExt;
+            (meta.mode() & 0o7777, meta.uid(), meta.gid())
+        };
+        #[cfg(not(unix))]
+        let (mode, uid, gid) = (
+            if meta.permissions().readonly() {
+                0o444
             } else {
-                format!("FAILED to delete {} from right", rel_path)
-            };
-            log_sync_action(log, action, &msg);
+                0o644
+            },
+            0,
+            0,
+        );
+        let time = |t: filetime::FileTime| {
+            SystemTime::UNIX_EPOCH
+                + std::time::Duration::new(t.unix_seconds().max(0) as u64, t.nanoseconds())
+        };
+        Self {
+            mode,
+            uid,
+            gid,
+            accessed: time(filetime::FileTime::from_last_access_time(meta)),
+            modified: time(filetime::FileTime::from_last_modification_time(meta)),
         }
-        SyncAction::Conflict(rel_path) => {
-            let msg = format!("Conflict on {}. Manual resolution required.", rel_path);
-            log_sync_action(log, action, &msg);
+    }
+
+    /// Give `path` these permissions, owner and times, in the order
+    /// [`preserve_metadata`] uses.
+    pub fn apply(&self, path: &Path) -> Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            match std::os::unix::fs::chown(path, Some(self.uid), Some(self.gid)) {
+                Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    log::debug!("Not permitted to restore owner of {}", path.display());
+                }
+                res => res?,
+            }
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(self.mode))?;
         }
-        SyncAction::NoOp(rel_path) => {
-            let msg = format!("No operation for {}.", rel_path);
-            log_sync_action(log, action, &msg);
+        #[cfg(not(unix))]
+        {
+            let mut permissions = std::fs::metadata(path)?.permissions();
+            permissions.set_readonly(self.mode & 0o222 == 0);
+            std::fs::set_permissions(path, permissions)?;
         }
+        filetime::set_file_times(
+            path,
+            filetime::FileTime::from_system_time(self.accessed),
+            filetime::FileTime::from_system_time(self.modified),
+        )?;
+        Ok(())
+    }
+
+    /// As one journal field, e.g. `644:1000:1000:1700000000.000000000:...`
+    /// (mode in octal, then owner, group, atime and mtime).
+    pub fn to_field(&self) -> String {
+        format!(
+            "{:o}:{}:{}:{}:{}",
+            self.mode,
+            self.uid,
+            self.gid,
+            format_time(self.accessed),
+            format_time(self.modified)
+        )
+    }
+
+    /// Parse a field written by [`Self::to_field`].
+    pub fn parse(field: &str) -> Option<Self> {
+        let mut parts = field.split(':');
+        let metadata = Self {
+            mode: u32::from_str_radix(parts.next()?, 8).ok()?,
+            uid: parts.next()?.parse().ok()?,
+            gid: parts.next()?.parse().ok()?,
+            accessed: parse_time(parts.next()?)?,
+            modified: parse_time(parts.next()?)?,
+        };
+        parts.next().is_none().then_some(metadata)
+    }
+}
+
+/// Apply the selected metadata of `src` to `dst`.
+///
+/// Ownership goes first since `chown` may clear set-id bits, and times go
+/// last since changing the other attributes does not touch mtime.
+pub fn preserve_metadata(src: &Path, dst: &Path, preserve: &PreserveOptions) -> Result<()> {
+    let meta = std::fs::metadata(src)?;
+    #[cfg(unix)]
+    if preserve.owner {
+        use std::os::unix::fs::MetadataExt;
+        match std::os::unix::fs::chown(dst, Some(meta.uid()), Some(meta.gid())) {
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                log::debug!("Not permitted to preserve owner of {}", dst.display());
+            }
+            res => res?,
+        }
+    }
+    if preserve.mode {
+        std::fs::set_permissions(dst, meta.permissions())?;
+    }
+    if preserve.times {
+        filetime::set_file_times(
+            dst,
+            filetime::FileTime::from_last_access_time(&meta),
+            filetime::FileTime::from_last_modification_time(&meta),
+        )?;
     }
     Ok(())
 }
 
+/// Perform `actions` in parallel, without journaling.
+///
+/// See [`executor::execute_actions`] for the ordering guarantees.
+pub fn execute_sync_actions(
+    actions: &[SyncAction],
+    left: &Path,
+    right: &Path,
+    store: &BackupStore,
+    options: &SyncOptions,
+    log: &mut SyncLog,
+) -> SyncReport {
+    executor::execute_actions(actions, left, right, options, log, |action, log| {
+        Ok(perform_sync_action(
+            action, left, right, store, options, log,
+        ))
+    })
+    .expect("perform_sync_action never returns an error")
+}
+
 /// Roll back all sync actions in the log.
+///
+/// Backups are located through the paths recorded in the log entries.
 pub fn rollback(log: &SyncLog, left: &Path, right: &Path) -> Result<()> {
     for entry in log.entries.iter().rev() {
+        if entry.outcome != SyncOutcome::Success {
+            continue;
+        }
+        let backup = entry.backup.as_deref();
         match &entry.action {
             SyncAction::CopyLeftToRight(rel_path) => {
-                let dst = right.join(rel_path);
-                let backup = dst.with_extension("bak");
-                if backup.exists() {
-                    restore_file(&backup, &dst)?;
-                    std::fs::remove_file(&backup)?;
-                } else {
-                    let _ = std::fs::remove_file(&dst);
-                }
+                undo_copy(&right.join(rel_path), backup)?;
                 println!("Rolled back CopyLeftToRight: {}", rel_path);
             }
             SyncAction::CopyRightToLeft(rel_path) => {
-                let dst = left.join(rel_path);
-                let backup = dst.with_extension("bak");
-                if backup.exists() {
-                    restore_file(&backup, &dst)?;
-                    std::fs::remove_file(&backup)?;
-                } else {
-                    let _ = std::fs::remove_file(&dst);
-                }
+                undo_copy(&left.join(rel_path), backup)?;
                 println!("Rolled back CopyRightToLeft: {}", rel_path);
             }
             SyncAction::DeleteLeft(rel_path) => {
-                let orig = left.join(rel_path);
-                let backup = orig.with_extension("bak");
-                if backup.exists() {
-                    restore_file(&backup, &orig)?;
-                    std::fs::remove_file(&backup)?;
+                if let Some(backup) = backup {
+                    restore_file(backup, &left.join(rel_path))?;
                 }
                 println!("Rolled back DeleteLeft: {}", rel_path);
             }
             SyncAction::DeleteRight(rel_path) => {
-                let orig = right.join(rel_path);
-                let backup = orig.with_extension("bak");
-                if backup.exists() {
-                    restore_file(&backup, &orig)?;
-                    std::fs::remove_file(&backup)?;
+                if let Some(backup) = backup {
+                    restore_file(backup, &right.join(rel_path))?;
                 }
                 println!("Rolled back DeleteRight: {}", rel_path);
             }
             SyncAction::Conflict(rel_path) | SyncAction::NoOp(rel_path) => {
                 println!("No rollback for action on {}", rel_path);
             }
+            SyncAction::Dedupe(dedupe) => {
+                let root = match dedupe.side {
+                    Side::Left => left,
+                    Side::Right => right,
+                };
+                unshare_file(&root.join(&dedupe.path), dedupe.metadata.as_ref())?;
+                println!("Rolled back {}: {}", entry.action.kind(), dedupe.path);
+            }
+            SyncAction::Relink(relink) => {
+                let root = match relink.side {
+                    Side::Left => left,
+                    Side::Right => right,
+                };
+                if let Some(backup) = backup {
+                    restore_file(backup, &root.join(&relink.path))?;
+                }
+                println!("Rolled back {}: {}", entry.action.kind(), relink.path);
+            }
         }
     }
     Ok(())
 }
+
+/// Undo a copy: restore the overwritten file, or remove the new one.
+fn undo_copy(dst: &Path, backup: Option<&Path>) -> Result<()> {
+    match backup {
+        Some(backup) => restore_backup(backup, dst),
+        None => {
+            let _ = std::fs::remove_file(dst);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_backups_do_not_collide() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("foo.txt"), b"txt").unwrap();
+        fs::write(dir.path().join("foo.json"), b"json").unwrap();
+        let store = BackupStore::in_tree("run1");
+
+        let txt = store.backup(dir.path(), Side::Right, "foo.txt").unwrap();
+        let json = store.backup(dir.path(), Side::Right, "foo.json").unwrap();
+        assert_ne!(txt, json);
+        assert_eq!(fs::read(txt.unwrap()).unwrap(), b"txt");
+        assert_eq!(fs::read(json.unwrap()).unwrap(), b"json");
+        assert!(!dir.path().join("foo.bak").exists());
+    }
+
+    #[test]
+    fn test_external_backup_dir() {
+        let tree = tempdir().unwrap();
+        let backups = tempdir().unwrap();
+        fs::write(tree.path().join("a.txt"), b"a").unwrap();
+        let store = BackupStore::external(backups.path(), "run1");
+
+        let backup = store.backup(tree.path(), Side::Left, "a.txt").unwrap();
+        assert_eq!(
+            backup,
+            Some(backups.path().join("run1").join("left").join("a.txt"))
+        );
+        assert!(!tree.path().join(STATE_DIR_NAME).exists());
+    }
+
+    #[test]
+    fn test_sync_and_rollback() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        fs::write(left.path().join("a.txt"), b"new").unwrap();
+        fs::write(right.path().join("a.txt"), b"old").unwrap();
+        fs::write(right.path().join("b.txt"), b"gone").unwrap();
+        let store = BackupStore::in_tree("run1");
+        let mut log = SyncLog::default();
+
+        let actions = [
+            SyncAction::CopyLeftToRight("a.txt".to_string()),
+            SyncAction::DeleteRight("b.txt".to_string()),
+        ];
+        let report = execute_sync_actions(
+            &actions,
+            left.path(),
+            right.path(),
+            &store,
+            &SyncOptions::default(),
+            &mut log,
+        );
+        assert!(report.is_success());
+        assert_eq!(report.succeeded, 2);
+        assert_eq!(fs::read(right.path().join("a.txt")).unwrap(), b"new");
+        assert!(!right.path().join("b.txt").exists());
+
+        rollback(&log, left.path(), right.path()).unwrap();
+        assert_eq!(fs::read(right.path().join("a.txt")).unwrap(), b"old");
+        assert_eq!(fs::read(right.path().join("b.txt")).unwrap(), b"gone");
+    }
+
+    #[test]
+    fn test_delta_sync_reports_transferred_bytes() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        let mut content = vec![7u8; DELTA_MIN_SIZE as usize];
+        fs::write(right.path().join("big.bin"), &content).unwrap();
+        let old_content = content.clone();
+        content[DELTA_BLOCK_SIZE + 1] = 0;
+        fs::write(left.path().join("big.bin"), &content).unwrap();
+        let store = BackupStore::in_tree("run1");
+        let mut log = SyncLog::default();
+
+        let outcome = perform_sync_action(
+            &SyncAction::CopyLeftToRight("big.bin".to_string()),
+            left.path(),
+            right.path(),
+            &store,
+            &SyncOptions {
+                copy_method: CopyMethod::Copy,
+                ..SyncOptions::default()
+            },
+            &mut log,
+        );
+        assert_eq!(outcome, SyncOutcome::Success);
+        assert_eq!(fs::read(right.path().join("big.bin")).unwrap(), content);
+        let expected = format!(
+            "delta, {} of {} bytes transferred",
+            DELTA_BLOCK_SIZE,
+            content.len()
+        );
+        assert!(log.entries[0].details.contains(&expected));
+        // Only the changed block was backed up, and rollback puts it back.
+        let blocks = log.entries[0].backup.clone().unwrap();
+        assert!(fs::metadata(&blocks).unwrap().len() < 2 * DELTA_BLOCK_SIZE as u64);
+        rollback(&log, left.path(), right.path()).unwrap();
+        assert_eq!(fs::read(right.path().join("big.bin")).unwrap(), old_content);
+        assert!(!blocks.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sync_recreates_hardlinks() {
+        use std::os::unix::fs::MetadataExt;
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        fs::write(left.path().join("a"), b"linked").unwrap();
+        fs::hard_link(left.path().join("a"), left.path().join("b")).unwrap();
+        let mut left_files = rustc_hash::FxHashMap::default();
+        crate::get_dir_files_with_ignore(left.path(), &mut left_files, &[]).unwrap();
+        let options = SyncOptions {
+            hardlinks: Some(Arc::new(Hardlinks {
+                left: crate::links::LinkGroups::from_files(&left_files),
+                ..Hardlinks::default()
+            })),
+            ..SyncOptions::default()
+        };
+        let mut log = SyncLog::default();
+
+        let report = execute_sync_actions(
+            &[
+                SyncAction::CopyLeftToRight("b".to_string()),
+                SyncAction::CopyLeftToRight("a".to_string()),
+            ],
+            left.path(),
+            right.path(),
+            &BackupStore::in_tree("run1"),
+            &options,
+            &mut log,
+        );
+        assert!(report.is_success());
+        let a = fs::metadata(right.path().join("a")).unwrap();
+        let b = fs::metadata(right.path().join("b")).unwrap();
+        assert_eq!(a.ino(), b.ino());
+        assert!(log.entries[0].details.contains("hardlink"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sync_relinks_mismatched_groups() {
+        use std::os::unix::fs::MetadataExt;
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        for name in ["a", "c"] {
+            fs::write(left.path().join(name), b"same").unwrap();
+        }
+        fs::hard_link(left.path().join("a"), left.path().join("b")).unwrap();
+        for name in ["a", "b"] {
+            fs::write(right.path().join(name), b"same").unwrap();
+        }
+        fs::hard_link(right.path().join("a"), right.path().join("c")).unwrap();
+        let scan = |dir: &Path| {
+            let mut files = rustc_hash::FxHashMap::default();
+            crate::get_dir_files_with_ignore(dir, &mut files, &[]).unwrap();
+            files
+        };
+        let (left_files, right_files) = (scan(left.path()), scan(right.path()));
+        let diffs = crate::diff::hardlink_diffs(&left_files, &right_files, &Default::default());
+        let ino = |name: &str| fs::metadata(right.path().join(name)).unwrap().ino();
+
+        // A plain sync leaves the right tree's links as they are.
+        let actions = plan_sync_actions(&diffs, "", &SyncOptions::default());
+        assert!(actions.iter().all(|a| matches!(a, SyncAction::NoOp(_))));
+        let mut log = SyncLog::default();
+        execute_sync_actions(
+            &actions,
+            left.path(),
+            right.path(),
+            &BackupStore::in_tree("run0"),
+            &SyncOptions::default(),
+            &mut log,
+        );
+        assert_eq!(ino("a"), ino("c"));
+        assert_ne!(ino("a"), ino("b"));
+
+        let options = SyncOptions {
+            hardlinks: Some(Arc::new(Hardlinks {
+                left: crate::links::LinkGroups::from_files(&left_files),
+                right: crate::links::LinkGroups::from_files(&right_files),
+            })),
+            ..SyncOptions::default()
+        };
+        let actions = plan_sync_actions(&diffs, "", &options);
+        let relink = |path: &str, leader: Option<&str>| {
+            SyncAction::Relink(Relink {
+                side: Side::Right,
+                path: path.to_string(),
+                leader: leader.map(str::to_string),
+            })
+        };
+        assert_eq!(actions, [relink("b", Some("a")), relink("c", None)]);
+
+        let mut log = SyncLog::default();
+        let report = execute_sync_actions(
+            &actions,
+            left.path(),
+            right.path(),
+            &BackupStore::in_tree("run1"),
+            &options,
+            &mut log,
+        );
+        assert!(report.is_success());
+        assert_eq!(ino("a"), ino("b"));
+        assert_ne!(ino("a"), ino("c"));
+        assert!(log.entries.iter().all(|e| e.backup.is_some()));
+
+        // The backups kept their inodes, so rollback restores the old links.
+        rollback(&log, left.path(), right.path()).unwrap();
+        assert_eq!(ino("a"), ino("c"));
+        assert_ne!(ino("a"), ino("b"));
+    }
+
+    #[test]
+    fn test_failure_policies() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        fs::write(left.path().join("b.txt"), b"b").unwrap();
+        fs::write(right.path().join("a.txt"), b"keep").unwrap();
+        let store = BackupStore::in_tree("run1");
+        let actions = [
+            SyncAction::CopyLeftToRight("a.txt".to_string()),
+            SyncAction::DeleteRight("missing.txt".to_string()),
+            SyncAction::CopyLeftToRight("b.txt".to_string()),
+        ];
+
+        let mut log = SyncLog::default();
+        let report = execute_sync_actions(
+            &actions,
+            left.path(),
+            right.path(),
+            &store,
+            &SyncOptions::default(),
+            &mut log,
+        );
+        assert_eq!(report.failed.len(), 1);
+        assert!(matches!(
+            report.failed[0].1,
+            SyncOutcome::Failed {
+                kind: std::io::ErrorKind::NotFound,
+                ..
+            }
+        ));
+        assert_eq!(report.not_attempted, 2);
+        // The failed copy must not have disturbed the destination.
+        assert_eq!(fs::read(right.path().join("a.txt")).unwrap(), b"keep");
+
+        let mut log = SyncLog::default();
+        let report = execute_sync_actions(
+            &actions,
+            left.path(),
+            right.path(),
+            &store,
+            &SyncOptions {
+                failure_policy: FailurePolicy::KeepGoing,
+                ..SyncOptions::default()
+            },
+            &mut log,
+        );
+        assert_eq!(report.summary(), "1 succeeded, 1 skipped, 1 failed");
+        assert!(right.path().join("b.txt").exists());
+    }
+
+    #[test]
+    fn test_preserved_times_compare_equal() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        let src = left.path().join("a.bin");
+        fs::write(&src, vec![7u8; 4096]).unwrap();
+        let old = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(&src, old).unwrap();
+        let store = BackupStore::in_tree("run1");
+        let mut log = SyncLog::default();
+
+        let outcome = perform_sync_action(
+            &SyncAction::CopyLeftToRight("a.bin".to_string()),
+            left.path(),
+            right.path(),
+            &store,
+            &SyncOptions::default(),
+            &mut log,
+        );
+        assert_eq!(outcome, SyncOutcome::Success);
+        let copied = fs::metadata(right.path().join("a.bin")).unwrap();
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&copied),
+            old
+        );
+        assert!(
+            crate::diff::compare_dirs(left.path(), right.path())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_parse_preserve_options() {
+        assert_eq!(
+            PreserveOptions::parse("times").unwrap(),
+            PreserveOptions {
+                times: true,
+                mode: false,
+                owner: false
+            }
+        );
+        assert_eq!(
+            PreserveOptions::parse("all").unwrap(),
+            PreserveOptions::all()
+        );
+        assert!(PreserveOptions::parse("xattrs").is_err());
+    }
+
+    #[test]
+    fn test_merge_applies_one_sided_changes() {
+        let base = tempdir().unwrap();
+        let ours = tempdir().unwrap();
+        let theirs = tempdir().unwrap();
+        for root in [base.path(), ours.path(), theirs.path()] {
+            fs::write(root.join("gone.txt"), b"v1").unwrap();
+            fs::write(root.join("conflict.txt"), b"v1").unwrap();
+        }
+        fs::remove_file(ours.path().join("gone.txt")).unwrap();
+        fs::write(theirs.path().join("vendor.txt"), b"new").unwrap();
+        fs::write(ours.path().join("conflict.txt"), b"ours").unwrap();
+        fs::write(theirs.path().join("conflict.txt"), b"theirs").unwrap();
+
+        let diffs = crate::three_way::compare3(base.path(), ours.path(), theirs.path()).unwrap();
+        let actions = plan_merge_actions(&diffs);
+        assert_eq!(
+            actions,
+            [
+                SyncAction::Conflict("conflict.txt".to_string()),
+                SyncAction::DeleteRight("gone.txt".to_string()),
+                SyncAction::CopyRightToLeft("vendor.txt".to_string()),
+            ]
+        );
+        let mut log = SyncLog::default();
+        let report = execute_sync_actions(
+            &actions,
+            ours.path(),
+            theirs.path(),
+            &BackupStore::in_tree("run1"),
+            &SyncOptions::default(),
+            &mut log,
+        );
+        assert!(report.is_success());
+        assert_eq!(fs::read(ours.path().join("vendor.txt")).unwrap(), b"new");
+        assert!(!theirs.path().join("gone.txt").exists());
+        assert_eq!(fs::read(ours.path().join("conflict.txt")).unwrap(), b"ours");
+    }
+}
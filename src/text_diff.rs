@@ -0,0 +1,156 @@
+//! Line-level diffs of changed text files for folder-differ
+//!
+//! A file counts as text when it is valid UTF-8 without NUL bytes and no
+//! larger than a size threshold. Changed text files can be rendered as a
+//! unified diff (`--- a/path`, `+++ b/path`), which `patch -p1` applies in
+//! the left tree to turn it into the right one.
+
+use crate::Result;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Files larger than this are not diffed by default.
+pub const DEFAULT_MAX_TEXT_SIZE: u64 = 1024 * 1024;
+
+/// When and how to render unified diffs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextDiffOptions {
+    /// Largest file, in bytes, that is treated as text.
+    pub max_size: u64,
+    /// Unchanged lines shown around each change.
+    pub context: usize,
+}
+
+impl Default for TextDiffOptions {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_TEXT_SIZE,
+            context: 3,
+        }
+    }
+}
+
+/// The content of `path` if it is text no larger than `max_size`.
+pub fn read_text(path: &Path, max_size: u64) -> Result<Option<String>> {
+    let file = File::open(path)?;
+    if file.metadata()?.len() > max_size {
+        return Ok(None);
+    }
+    let mut content = Vec::new();
+    // The file may have grown since it was stat'ed.
+    file.take(max_size + 1).read_to_end(&mut content)?;
+    if content.len() as u64 > max_size || content.contains(&0) {
+        return Ok(None);
+    }
+    Ok(String::from_utf8(content).ok())
+}
+
+/// True if [`read_text`] would accept `path` as text, whatever its size.
+///
+/// The file is read in blocks and the check stops at the first byte that
+/// rules text out, so a binary file is usually rejected after its first
+/// block.
+pub fn is_text(path: &Path) -> Result<bool> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    // Bytes of a character split across two blocks, moved to the front.
+    let mut carry = 0;
+    loop {
+        let n = file.read(&mut buf[carry..])?;
+        if n == 0 {
+            return Ok(carry == 0);
+        }
+        let len = carry + n;
+        if buf[carry..len].contains(&0) {
+            return Ok(false);
+        }
+        carry = match std::str::from_utf8(&buf[..len]) {
+            Ok(_) => 0,
+            Err(e) if e.error_len().is_some() => return Ok(false),
+            Err(e) => {
+                buf.copy_within(e.valid_up_to()..len, 0);
+                len - e.valid_up_to()
+            }
+        };
+    }
+}
+
+/// Unified diff of `rel_path` from the `left` tree to the `right` tree.
+///
+/// `None` if either side is not text; an empty string if the contents are
+/// equal.
+pub fn unified_diff(
+    rel_path: &str,
+    left: &Path,
+    right: &Path,
+    options: &TextDiffOptions,
+) -> Result<Option<String>> {
+    let Some(old) = read_text(&left.join(rel_path), options.max_size)? else {
+        return Ok(None);
+    };
+    let Some(new) = read_text(&right.join(rel_path), options.max_size)? else {
+        return Ok(None);
+    };
+    Ok(Some(
+        similar::TextDiff::from_lines(&old, &new)
+            .unified_diff()
+            .context_radius(options.context)
+            .header(&format!("a/{}", rel_path), &format!("b/{}", rel_path))
+            .to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_unified_diff_of_text_files_only() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        std::fs::write(left.path().join("config.yaml"), "port: 80\nhost: a\n").unwrap();
+        std::fs::write(right.path().join("config.yaml"), "port: 8080\nhost: a\n").unwrap();
+        std::fs::write(left.path().join("image.bin"), b"\x00\x01").unwrap();
+        std::fs::write(right.path().join("image.bin"), b"\x00\x02").unwrap();
+
+        let options = TextDiffOptions::default();
+        let patch = unified_diff("config.yaml", left.path(), right.path(), &options)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            patch,
+            "--- a/config.yaml\n+++ b/config.yaml\n@@ -1,2 +1,2 @@\n-port: 80\n+port: 8080\n host: a\n"
+        );
+        assert_eq!(
+            unified_diff("image.bin", left.path(), right.path(), &options).unwrap(),
+            None
+        );
+        let tiny = TextDiffOptions {
+            max_size: 4,
+            ..options
+        };
+        assert_eq!(
+            unified_diff("config.yaml", left.path(), right.path(), &tiny).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_text_ignores_size() {
+        let dir = tempdir().unwrap();
+        let text = dir.path().join("big.txt");
+        // Two-byte characters straddle every block boundary.
+        std::fs::write(&text, "é".repeat(100_001)).unwrap();
+        assert!(is_text(&text).unwrap());
+        assert_eq!(read_text(&text, 1024).unwrap(), None);
+
+        let binary = dir.path().join("image.bin");
+        std::fs::write(&binary, b"abc\x00def").unwrap();
+        assert!(!is_text(&binary).unwrap());
+        let truncated = dir.path().join("cut.txt");
+        std::fs::write(&truncated, &"é".as_bytes()[..1]).unwrap();
+        assert!(!is_text(&truncated).unwrap());
+    }
+}
@@ -0,0 +1,155 @@
+//! Three-way comparison for folder-differ
+//!
+//! Two trees that both descend from a common base (e.g. a local copy and a
+//! new vendor drop) are each compared against the base, and against each
+//! other where both changed, to tell which side changed every path. The
+//! result feeds [`crate::sync::plan_merge_actions`].
+
+use crate::Result;
+use crate::diff::{CompareContext, DiffType, compare_entry};
+use crate::get_dir_files_with_ignore;
+use crate::hash::HashAlgorithm;
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::fs::Metadata;
+use std::path::Path;
+
+/// How a path changed in `ours` and `theirs` relative to the base.
+///
+/// A path counts as changed if it was added, deleted, or its content differs;
+/// differences in allocation alone are ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreeWayStatus {
+    Unchanged,
+    ChangedInOurs,
+    ChangedInTheirs,
+    /// Both sides made the same change.
+    ChangedIdentically,
+    /// Both sides changed the path, differently.
+    Conflict,
+}
+
+/// The classification of one path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreeWayDiff {
+    pub path: String,
+    pub status: ThreeWayStatus,
+    pub in_base: bool,
+    pub in_ours: bool,
+    pub in_theirs: bool,
+}
+
+/// Classify every path of `base`, `ours` and `theirs`, sorted by path.
+pub fn compare3(base: &Path, ours: &Path, theirs: &Path) -> Result<Vec<ThreeWayDiff>> {
+    compare3_with(base, ours, theirs, HashAlgorithm::default())
+}
+
+/// [`compare3`], hashing contents with `algorithm`.
+pub fn compare3_with(
+    base: &Path,
+    ours: &Path,
+    theirs: &Path,
+    algorithm: HashAlgorithm,
+) -> Result<Vec<ThreeWayDiff>> {
+    let mut base_files = FxHashMap::default();
+    let mut our_files = FxHashMap::default();
+    let mut their_files = FxHashMap::default();
+    let (base_res, (our_res, their_res)) = rayon::join(
+        || get_dir_files_with_ignore(base, &mut base_files, &[]),
+        || {
+            rayon::join(
+                || get_dir_files_with_ignore(ours, &mut our_files, &[]),
+                || get_dir_files_with_ignore(theirs, &mut their_files, &[]),
+            )
+        },
+    );
+    base_res?;
+    our_res?;
+    their_res?;
+
+    // One context for all three pairs, so that no tree's hash cache is
+    // consulted for another; hardlinked files are still hashed once.
+    let context = CompareContext::new(algorithm);
+    let all_paths: FxHashSet<&String> = base_files
+        .keys()
+        .chain(our_files.keys())
+        .chain(their_files.keys())
+        .collect();
+    let mut diffs = all_paths
+        .par_iter()
+        .map(|path| {
+            let same = |a: (&Path, Option<&Metadata>), b: (&Path, Option<&Metadata>)| {
+                Ok::<_, crate::FolderDifferError>(match (a.1, b.1) {
+                    (None, None) => true,
+                    (Some(a_meta), Some(b_meta)) => !matches!(
+                        compare_entry(path, a.0, b.0, a_meta, b_meta, &context)?,
+                        Some(diff) if matches!(diff.diff_type, DiffType::Different { .. })
+                    ),
+                    _ => false,
+                })
+            };
+            let in_base = (base, base_files.get(*path));
+            let in_ours = (ours, our_files.get(*path));
+            let in_theirs = (theirs, their_files.get(*path));
+            let status = match (!same(in_base, in_ours)?, !same(in_base, in_theirs)?) {
+                (false, false) => ThreeWayStatus::Unchanged,
+                (true, false) => ThreeWayStatus::ChangedInOurs,
+                (false, true) => ThreeWayStatus::ChangedInTheirs,
+                (true, true) if same(in_ours, in_theirs)? => ThreeWayStatus::ChangedIdentically,
+                (true, true) => ThreeWayStatus::Conflict,
+            };
+            Ok(ThreeWayDiff {
+                path: (*path).clone(),
+                status,
+                in_base: in_base.1.is_some(),
+                in_ours: in_ours.1.is_some(),
+                in_theirs: in_theirs.1.is_some(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    diffs.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_compare3_classification() {
+        let dirs = [tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()];
+        let [base, ours, theirs] = [dirs[0].path(), dirs[1].path(), dirs[2].path()];
+        for root in [base, ours, theirs] {
+            std::fs::write(root.join("same"), b"v1").unwrap();
+            std::fs::write(root.join("ours"), b"v1").unwrap();
+            std::fs::write(root.join("theirs"), b"v1").unwrap();
+            std::fs::write(root.join("both"), b"v1").unwrap();
+            std::fs::write(root.join("conflict"), b"v1").unwrap();
+        }
+        std::fs::write(ours.join("ours"), b"ours v2").unwrap();
+        std::fs::remove_file(theirs.join("theirs")).unwrap();
+        std::fs::write(ours.join("both"), b"v2 both").unwrap();
+        std::fs::write(theirs.join("both"), b"v2 both").unwrap();
+        std::fs::write(ours.join("conflict"), b"ours!").unwrap();
+        std::fs::write(theirs.join("conflict"), b"theirs!").unwrap();
+        std::fs::write(theirs.join("added"), b"new").unwrap();
+
+        let statuses: Vec<(String, ThreeWayStatus)> = compare3(base, ours, theirs)
+            .unwrap()
+            .into_iter()
+            .map(|d| (d.path, d.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("added".to_string(), ThreeWayStatus::ChangedInTheirs),
+                ("both".to_string(), ThreeWayStatus::ChangedIdentically),
+                ("conflict".to_string(), ThreeWayStatus::Conflict),
+                ("ours".to_string(), ThreeWayStatus::ChangedInOurs),
+                ("same".to_string(), ThreeWayStatus::Unchanged),
+                ("theirs".to_string(), ThreeWayStatus::ChangedInTheirs),
+            ]
+        );
+    }
+}
@@ -0,0 +1,326 @@
+//! Watch mode for folder-differ
+//!
+//! After an initial full comparison, every directory of both trees is watched
+//! with inotify. Events are gathered into batches, only the paths they name
+//! are compared again, and the changes to the set of diffs are reported.
+//! Like the main comparison, hidden entries (including the `.folder-differ`
+//! state directory) are skipped.
+
+use crate::diff::{CompareContext, Diff, DiffType, compare_entry, compare_path};
+use crate::sync::Side;
+use crate::{Result, join_rel};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::Metadata;
+use std::io::ErrorKind;
+use std::mem::discriminant;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A change to the set of diffs between the two trees.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffChange {
+    /// A path that matched now differs.
+    Added(Diff),
+    /// A path still differs, but in another way (e.g. it now exists on both sides).
+    Changed(Diff),
+    /// A path that differed now matches (or is gone from both trees).
+    Resolved(String),
+}
+
+const WATCH_MASK: WatchMask = WatchMask::CREATE
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MODIFY)
+    .union(WatchMask::CLOSE_WRITE)
+    .union(WatchMask::ATTRIB)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO);
+
+const EVENT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Paths named by one batch of events.
+#[derive(Debug, Default)]
+struct Pending {
+    files: BTreeSet<String>,
+    dirs: BTreeSet<String>,
+    overflow: bool,
+}
+
+/// Keeps the diffs between two trees up to date as they change.
+pub struct TreeWatcher {
+    left: PathBuf,
+    right: PathBuf,
+    context: CompareContext,
+    inotify: Inotify,
+    /// Directory (relative to its tree's root) of each watch.
+    watches: FxHashMap<WatchDescriptor, String>,
+    diffs: BTreeMap<String, Diff>,
+}
+
+impl TreeWatcher {
+    /// Watch both trees and run the initial full comparison.
+    pub fn new(left: &Path, right: &Path, context: CompareContext) -> Result<Self> {
+        let mut watcher = Self {
+            left: left.to_path_buf(),
+            right: right.to_path_buf(),
+            context,
+            inotify: Inotify::init()?,
+            watches: FxHashMap::default(),
+            diffs: BTreeMap::new(),
+        };
+        // Watches go in before the scan so that no change slips in between.
+        watcher.rescan()?;
+        Ok(watcher)
+    }
+
+    /// The current diffs, sorted by path.
+    pub fn diffs(&self) -> impl Iterator<Item = &Diff> {
+        self.diffs.values()
+    }
+
+    /// The comparison state, e.g. to save its hash caches.
+    pub fn context(&self) -> &CompareContext {
+        &self.context
+    }
+
+    fn root(&self, side: Side) -> &Path {
+        match side {
+            Side::Left => &self.left,
+            Side::Right => &self.right,
+        }
+    }
+
+    /// Collect the files under `rel` on `side`, watching every directory.
+    fn walk(&mut self, side: Side, rel: &str, files: &mut FxHashMap<String, Metadata>) {
+        let dir = self.root(side).join(rel);
+        match self.inotify.watches().add(&dir, WATCH_MASK) {
+            Ok(wd) => {
+                self.watches.insert(wd, rel.to_string());
+            }
+            Err(e) => {
+                // Gone already, or not a directory; the events say what happened.
+                log::debug!("Cannot watch {}: {}", dir.display(), e);
+                return;
+            }
+        }
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') {
+                continue;
+            }
+            let child = join_rel(rel, &name);
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => self.walk(side, &child, files),
+                Ok(_) => {
+                    if let Ok(meta) = std::fs::metadata(entry.path())
+                        && meta.is_file()
+                    {
+                        files.insert(child, meta);
+                    }
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Compare both trees in full, as at startup, and report what changed.
+    pub fn rescan(&mut self) -> Result<Vec<DiffChange>> {
+        let mut left_files = FxHashMap::default();
+        let mut right_files = FxHashMap::default();
+        self.walk(Side::Left, "", &mut left_files);
+        self.walk(Side::Right, "", &mut right_files);
+        let all_paths: FxHashSet<&String> = left_files.keys().chain(right_files.keys()).collect();
+        let (left, right, context) = (&self.left, &self.right, &self.context);
+        let mut diffs: BTreeMap<String, Diff> = all_paths
+            .par_iter()
+            .map(|path| {
+                let diff_type = match (left_files.get(*path), right_files.get(*path)) {
+                    (Some(left_meta), Some(right_meta)) => {
+                        return compare_entry(path, left, right, left_meta, right_meta, context);
+                    }
+                    (Some(_), None) => DiffType::OnlyInLeft,
+                    (None, Some(_)) => DiffType::OnlyInRight,
+                    (None, None) => return Ok(None),
+                };
+                Ok(Some(Diff {
+                    path: (*path).clone(),
+                    diff_type,
+                }))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .map(|diff| (diff.path.clone(), diff))
+            .collect();
+
+        let paths: BTreeSet<String> = self.diffs.keys().chain(diffs.keys()).cloned().collect();
+        Ok(paths
+            .into_iter()
+            .filter_map(|path| {
+                let diff = diffs.remove(&path);
+                self.update(path, diff)
+            })
+            .collect())
+    }
+
+    /// Record the new state of `path` and return the change, if any.
+    fn update(&mut self, path: String, diff: Option<Diff>) -> Option<DiffChange> {
+        match (self.diffs.remove(&path), diff) {
+            (None, None) => None,
+            (Some(_), None) => Some(DiffChange::Resolved(path)),
+            (None, Some(diff)) => {
+                self.diffs.insert(path, diff.clone());
+                Some(DiffChange::Added(diff))
+            }
+            (Some(old), Some(diff)) => {
+                let changed = discriminant(&old.diff_type) != discriminant(&diff.diff_type);
+                self.diffs.insert(path, diff.clone());
+                changed.then_some(DiffChange::Changed(diff))
+            }
+        }
+    }
+
+    /// Compare `paths` again and report how the diffs changed.
+    pub fn refresh<I>(&mut self, paths: I) -> Vec<DiffChange>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut changes = Vec::new();
+        for path in paths {
+            match compare_path(&path, &self.left, &self.right, &self.context) {
+                Ok(diff) => changes.extend(self.update(path, diff)),
+                // Usually a file replaced mid-comparison; its next event re-checks it.
+                Err(e) => log::warn!("Could not compare {}: {}", path, e),
+            }
+        }
+        changes
+    }
+
+    fn collect_events(
+        &mut self,
+        buffer: &mut [u8],
+        blocking: bool,
+        pending: &mut Pending,
+    ) -> Result<bool> {
+        let events = if blocking {
+            self.inotify.read_events_blocking(buffer)
+        } else {
+            self.inotify.read_events(buffer)
+        };
+        let events = match events {
+            Ok(events) => events,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        for event in events {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                pending.overflow = true;
+                continue;
+            }
+            if event.mask.contains(EventMask::IGNORED) {
+                self.watches.remove(&event.wd);
+                continue;
+            }
+            let (Some(dir), Some(name)) = (self.watches.get(&event.wd), event.name) else {
+                continue;
+            };
+            let name = name.to_string_lossy();
+            if name.starts_with('.') {
+                continue;
+            }
+            let path = join_rel(dir, &name);
+            if event.mask.contains(EventMask::ISDIR) {
+                pending.dirs.insert(path);
+            } else {
+                pending.files.insert(path);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Block until the trees change, then report how the diffs changed.
+    ///
+    /// Events arriving within `settle` of each other are handled as one
+    /// batch, so a file being written is compared once it is quiet. The
+    /// result can be empty when the events changed nothing.
+    pub fn wait(&mut self, settle: Duration) -> Result<Vec<DiffChange>> {
+        let mut buffer = vec![0u8; EVENT_BUFFER_SIZE];
+        let mut pending = Pending::default();
+        self.collect_events(&mut buffer, true, &mut pending)?;
+        loop {
+            std::thread::sleep(settle);
+            if !self.collect_events(&mut buffer, false, &mut pending)? {
+                break;
+            }
+        }
+        if pending.overflow {
+            log::warn!("Too many changes at once; comparing both trees again");
+            return self.rescan();
+        }
+
+        // A directory that appeared or vanished: watch whatever is there now
+        // and re-check everything under it on both sides.
+        let mut paths = pending.files;
+        for dir in pending.dirs {
+            let mut files = FxHashMap::default();
+            self.walk(Side::Left, &dir, &mut files);
+            self.walk(Side::Right, &dir, &mut files);
+            paths.extend(files.into_keys());
+            let prefix = format!("{}/", dir);
+            paths.extend(
+                self.diffs
+                    .range(prefix.clone()..)
+                    .take_while(|(path, _)| path.starts_with(&prefix))
+                    .map(|(path, _)| path.clone()),
+            );
+        }
+        Ok(self.refresh(paths))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::HashAlgorithm;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_watch_reports_incremental_changes() {
+        let left = tempdir().unwrap();
+        let right = tempdir().unwrap();
+        std::fs::write(left.path().join("a.txt"), b"same").unwrap();
+        std::fs::write(right.path().join("a.txt"), b"same").unwrap();
+        std::fs::write(left.path().join("b.txt"), b"only left").unwrap();
+
+        let mut watcher = TreeWatcher::new(
+            left.path(),
+            right.path(),
+            CompareContext::new(HashAlgorithm::Blake3),
+        )
+        .unwrap();
+        assert_eq!(
+            watcher.diffs().map(|d| d.path.as_str()).collect::<Vec<_>>(),
+            ["b.txt"]
+        );
+
+        std::fs::write(right.path().join("b.txt"), b"only left").unwrap();
+        std::fs::create_dir(left.path().join("sub")).unwrap();
+        std::fs::write(left.path().join("sub/c.txt"), b"new").unwrap();
+        let settle = Duration::from_millis(50);
+        let mut changes = watcher.wait(settle).unwrap();
+        // The directory may be seen before its file; wait for the rest.
+        while !changes.iter().any(|c| matches!(c, DiffChange::Added(_))) {
+            changes.extend(watcher.wait(settle).unwrap());
+        }
+        assert!(changes.contains(&DiffChange::Resolved("b.txt".to_string())));
+        assert!(changes.contains(&DiffChange::Added(Diff {
+            path: "sub/c.txt".to_string(),
+            diff_type: DiffType::OnlyInLeft,
+        })));
+    }
+}
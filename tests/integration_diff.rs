@@ -1,4 +1,4 @@
-use folder_differ::{diff::compare_dirs, get_dir_files_with_ignore};
+use folder_differ::diff::compare_dirs;
 use std::fs::File;
 use std::io::Write;
 use tempfile::tempdir;